@@ -0,0 +1,12 @@
+// blocks translated ahead of time by `rvemu-rs translate`, built in with
+// RVEMU_AOT=/abs/path/out.rs cargo build --features aot
+#[cfg(feature = "aot")]
+include!(env!("RVEMU_AOT"));
+
+#[cfg(not(feature = "aot"))]
+pub const AOT_HASH: u64 = 0;
+
+#[cfg(not(feature = "aot"))]
+pub fn aot_exec(_state: &mut crate::rvemu::State) -> bool {
+    return false;
+}
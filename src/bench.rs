@@ -0,0 +1,65 @@
+use std::{fs, hint::black_box, time::Instant};
+
+use crate::{
+    decode::{insn_decode, insn_decode_checked, insn_decode_tree},
+    rvemu::Insn,
+    translate::image_load,
+};
+
+const BENCH_ROUNDS: usize = 200;
+
+fn decode_rate(words: &[u32], decode: fn(&mut Insn, u32)) -> f64 {
+    let start = Instant::now();
+    for _ in 0..BENCH_ROUNDS {
+        for data in words.iter() {
+            let mut insn = Insn::new();
+            decode(&mut insn, black_box(*data));
+            black_box(insn);
+        }
+    }
+    return (words.len() * BENCH_ROUNDS) as f64 / start.elapsed().as_secs_f64();
+}
+
+// decode throughput of the decode tree against the tables, over the
+// instructions of the executable segments of prog
+pub fn bench_decode(prog: &str) -> Result<(), String> {
+    let buf = fs::read(prog).map_err(|e| format!("{}: {}", prog, e))?;
+    let image = image_load(&buf)?;
+
+    let mut words = Vec::new();
+    for seg in image.segments.iter() {
+        let mut off = 0;
+        while off + 4 <= seg.data.len() {
+            let data = u32::from_le_bytes(seg.data[off..off + 4].try_into().unwrap());
+            off += if data & 0x3 == 0x3 { 4 } else { 2 };
+            if insn_decode_checked(data).is_some() {
+                words.push(data);
+            }
+        }
+    }
+    if words.is_empty() {
+        return Err(format!("{}: no instructions to decode", prog));
+    }
+
+    let start = Instant::now();
+    for data in words.iter() {
+        let mut insn = Insn::new();
+        insn_decode(&mut insn, *data);
+        if Some(insn) != insn_decode_checked(*data) {
+            return Err(format!("decode mismatch on {:#x}", data));
+        }
+    }
+    let build = start.elapsed();
+
+    let tree = decode_rate(&words, insn_decode_tree);
+    let tables = decode_rate(&words, insn_decode);
+    eprintln!("decoding {} insns x {} rounds", words.len(), BENCH_ROUNDS);
+    eprintln!("  tree:   {:>8.1} Minsn/s", tree / 1e6);
+    eprintln!(
+        "  tables: {:>8.1} Minsn/s  ({:.2}x)",
+        tables / 1e6,
+        tables / tree
+    );
+    eprintln!("  table setup and check: {:?}", build);
+    return Ok(());
+}
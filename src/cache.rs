@@ -0,0 +1,170 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    os::raw::c_void,
+    ptr,
+};
+
+use libc::{mmap, munmap, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE};
+
+use crate::{
+    elfdef::{PROT_EXEC, PROT_READ, PROT_WRITE},
+    fatal,
+    interp::{fetch_block, Block},
+    round_up,
+    rvemu::{Mmu, MmuFault},
+    vma::{vma_exec_end, vma_has_write},
+};
+
+pub const CACHE_SIZE: usize = 64 * 1024 * 1024;
+pub const BLOCK_ALIGN: usize = 16;
+
+pub struct Cache {
+    pub jitcode: *mut u8,
+    pub offset: usize,
+    pub table: HashMap<u64, usize>,
+    pub blocks: HashMap<u64, Block>,
+    pub queue: VecDeque<u64>,
+    // jitcode offset of each native load/store to its guest pc
+    pub pcs: HashMap<usize, u64>,
+    // the blocks decoded from writable memory are all in here, see
+    // State::code_start
+    pub code_start: u64,
+    pub code_end: u64,
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache {
+            jitcode: ptr::null_mut(),
+            offset: 0,
+            table: HashMap::new(),
+            blocks: HashMap::new(),
+            queue: VecDeque::new(),
+            pcs: HashMap::new(),
+            code_start: u64::MAX,
+            code_end: 0,
+        }
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        return Cache::new();
+    }
+}
+
+// one past the last insn of block
+fn block_end(block: &Block) -> u64 {
+    let last = block.insns.last().unwrap();
+    return last.pc + if last.insn.rvc { 2 } else { 4 };
+}
+
+// the block at pc, decoded on first use. fetching from memory that is not
+// executable faults at the first address that is not.
+pub fn cache_block<'a>(
+    cache: &'a mut Cache,
+    mmu: &Mmu,
+    pc: u64,
+) -> Result<&'a mut Block, MmuFault> {
+    let vacant = match cache.blocks.entry(pc) {
+        Entry::Occupied(entry) => return Ok(entry.into_mut()),
+        Entry::Vacant(entry) => entry,
+    };
+    let end = vma_exec_end(mmu, pc).ok_or(MmuFault::Fetch(pc))?;
+    let block = fetch_block(mmu.host_base, pc, end);
+    if block.insns.is_empty() {
+        return Err(MmuFault::Fetch(end));
+    }
+    let block_end = block_end(&block);
+    if vma_has_write(mmu, pc, block_end) {
+        cache.code_start = cache.code_start.min(pc);
+        cache.code_end = cache.code_end.max(block_end);
+    }
+    return Ok(vacant.insert(block));
+}
+
+pub fn cache_lookup(cache: &Cache, pc: u64) -> Option<*const u8> {
+    let offset = cache.table.get(&pc)?;
+    return Some(unsafe { cache.jitcode.add(*offset) } as *const u8);
+}
+
+pub fn cache_flush(cache: &mut Cache) {
+    cache.table.clear();
+    cache.pcs.clear();
+    cache.offset = 0;
+    for block in cache.blocks.values_mut() {
+        block.queued = false;
+    }
+}
+
+// drop the blocks decoded from [start, end) and, if there were any, all
+// compiled code since traces may run through them
+pub fn cache_invalidate(cache: &mut Cache, start: u64, end: u64) {
+    let count = cache.blocks.len();
+    cache
+        .blocks
+        .retain(|_, block| block_end(block) <= start || block.insns[0].pc >= end);
+    if cache.blocks.len() != count {
+        cache_flush(cache);
+    }
+    if cache.blocks.is_empty() {
+        cache.code_start = u64::MAX;
+        cache.code_end = 0;
+    }
+}
+
+// guest pc of the load/store at host address addr, if it is in jitcode
+pub fn cache_host_pc(cache: &Cache, addr: u64) -> Option<u64> {
+    let start = cache.jitcode as u64;
+    if cache.jitcode.is_null() || addr < start || addr >= start + cache.offset as u64 {
+        return None;
+    }
+    return cache.pcs.get(&((addr - start) as usize)).copied();
+}
+
+pub fn cache_add(cache: &mut Cache, pc: u64, code: &[u8], pcs: &[(usize, u64)]) -> *const u8 {
+    if cache.jitcode.is_null() {
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                CACHE_SIZE,
+                PROT_READ | PROT_WRITE | PROT_EXEC,
+                MAP_ANONYMOUS | MAP_PRIVATE,
+                -1,
+                0,
+            )
+        };
+        if ptr == MAP_FAILED {
+            fatal!("mmap jitcode failed!");
+            std::process::exit(1);
+        }
+        cache.jitcode = ptr as *mut u8;
+    }
+
+    if cache.offset + code.len() > CACHE_SIZE {
+        cache_flush(cache);
+    }
+
+    let offset = cache.offset;
+    unsafe {
+        cache
+            .jitcode
+            .add(offset)
+            .copy_from(code.as_ptr(), code.len())
+    };
+    cache.offset = round_up!(offset + code.len(), BLOCK_ALIGN) as usize;
+    cache.table.insert(pc, offset);
+    cache
+        .pcs
+        .extend(pcs.iter().map(|&(at, pc)| (offset + at, pc)));
+
+    return unsafe { cache.jitcode.add(offset) } as *const u8;
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        if !self.jitcode.is_null() {
+            unsafe { munmap(self.jitcode as *mut c_void, CACHE_SIZE) };
+        }
+    }
+}
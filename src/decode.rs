@@ -1,7 +1,9 @@
+use std::sync::OnceLock;
+
 use crate::{
     fatal,
     reg::GpRegTypeT,
-    rvemu::{Insn, InsnType},
+    rvemu::{Fusion, Insn, InsnType},
 };
 
 #[macro_export]
@@ -507,53 +509,56 @@ pub fn insn_ciwtype_read(data: u16) -> Insn {
     return insn;
 }
 
-pub fn insn_decode(insn: &mut Insn, data: u32) {
-    let quadrant = quadrant!(data);
+// the compressed insns, quadrants 0 to 2. None for the reserved encodings
+// and the ones not implemented.
+fn insn_decode_rvc(data: u32) -> Option<Insn> {
+    let mut insn;
 
-    match quadrant {
+    match quadrant!(data) {
         0x0 => {
             let copcode = cop_code!(data);
             match copcode {
                 0x0 => {
-                    *insn = insn_ciwtype_read(data as u16);
+                    insn = insn_ciwtype_read(data as u16);
                     insn.rs1 = GpRegTypeT::Sp as i8;
                     insn.i_type = InsnType::InsnAddi;
-                    assert!(insn.imm != 0);
-                    return;
+                    if insn.imm == 0 {
+                        return None;
+                    }
+                    return Some(insn);
                 }
                 0x1 => {
-                    *insn = insn_cltype_read2(data as u16);
+                    insn = insn_cltype_read2(data as u16);
                     insn.i_type = InsnType::InsnFld;
-                    return;
+                    return Some(insn);
                 }
                 0x2 => {
-                    *insn = insn_cltype_read(data as u16);
+                    insn = insn_cltype_read(data as u16);
                     insn.i_type = InsnType::InsnLw;
-                    return;
+                    return Some(insn);
                 }
                 0x3 => {
-                    *insn = insn_cltype_read2(data as u16);
+                    insn = insn_cltype_read2(data as u16);
                     insn.i_type = InsnType::InsnLd;
-                    return;
+                    return Some(insn);
                 }
                 0x5 => {
-                    *insn = insn_cstype_read(data as u16);
+                    insn = insn_cstype_read(data as u16);
                     insn.i_type = InsnType::InsnFsd;
-                    return;
+                    return Some(insn);
                 }
                 0x6 => {
-                    *insn = insn_cstype_read2(data as u16);
+                    insn = insn_cstype_read2(data as u16);
                     insn.i_type = InsnType::InsnSw;
-                    return;
+                    return Some(insn);
                 }
                 0x7 => {
-                    *insn = insn_cstype_read(data as u16);
+                    insn = insn_cstype_read(data as u16);
                     insn.i_type = InsnType::InsnSd;
-                    return;
+                    return Some(insn);
                 }
                 _ => {
-                    println!("data: {}", data);
-                    fatal!("unimplemented");
+                    return None;
                 }
             }
         }
@@ -561,54 +566,60 @@ pub fn insn_decode(insn: &mut Insn, data: u32) {
             let copcode = cop_code!(data);
             match copcode {
                 0x0 => {
-                    *insn = insn_citype_read(data as u16);
+                    insn = insn_citype_read(data as u16);
                     insn.rs1 = insn.rd;
                     insn.i_type = InsnType::InsnAddi;
-                    return;
+                    return Some(insn);
                 }
                 0x1 => {
-                    *insn = insn_citype_read(data as u16);
-                    assert!(insn.rd != 0);
+                    insn = insn_citype_read(data as u16);
+                    if insn.rd == 0 {
+                        return None;
+                    }
                     insn.rs1 = insn.rd;
                     insn.i_type = InsnType::InsnAddiw;
-                    return;
+                    return Some(insn);
                 }
                 0x2 => {
-                    *insn = insn_citype_read(data as u16);
+                    insn = insn_citype_read(data as u16);
                     insn.rs1 = GpRegTypeT::Zero as i8;
                     insn.i_type = InsnType::InsnAddi;
-                    return;
+                    return Some(insn);
                 }
                 0x3 => {
                     let rd = rc1!(data);
                     if rd == 2 {
-                        *insn = insn_citype_read3(data as u16);
-                        assert!(insn.imm != 0);
+                        insn = insn_citype_read3(data as u16);
+                        if insn.imm == 0 {
+                            return None;
+                        }
                         insn.rs1 = insn.rd;
                         insn.i_type = InsnType::InsnAddi;
-                        return;
+                        return Some(insn);
                     } else {
-                        *insn = insn_citype_read5(data as u16);
-                        assert!(insn.imm != 0);
+                        insn = insn_citype_read5(data as u16);
+                        if insn.imm == 0 {
+                            return None;
+                        }
                         insn.i_type = InsnType::InsnLui;
-                        return;
+                        return Some(insn);
                     }
                 }
                 0x4 => {
                     let cfunct2high = cfunc_t2_high!(data);
                     match cfunct2high {
                         0x0 | 0x1 | 0x2 => {
-                            *insn = insn_cbtype_read2(data as u16);
+                            insn = insn_cbtype_read2(data as u16);
                             insn.rs1 = insn.rd;
                             if cfunct2high == 0x0 {
                                 insn.i_type = InsnType::InsnSrli;
-                                return;
+                                return Some(insn);
                             } else if cfunct2high == 0x1 {
                                 insn.i_type = InsnType::InsnSrai;
-                                return;
+                                return Some(insn);
                             } else {
                                 insn.i_type = InsnType::InsnAndi;
-                                return;
+                                return Some(insn);
                             }
                         }
                         0x3 => {
@@ -617,52 +628,52 @@ pub fn insn_decode(insn: &mut Insn, data: u32) {
                                 0x0 => {
                                     let cfunct2low = cfunc_t2_low!(data);
 
-                                    *insn = insn_catype_read(data as u16);
+                                    insn = insn_catype_read(data as u16);
                                     insn.rs1 = insn.rd;
 
                                     match cfunct2low {
                                         0x0 => {
                                             insn.i_type = InsnType::InsnSub;
-                                            return;
+                                            return Some(insn);
                                         }
                                         0x1 => {
                                             insn.i_type = InsnType::InsnXor;
-                                            return;
+                                            return Some(insn);
                                         }
                                         0x2 => {
                                             insn.i_type = InsnType::InsnOr;
-                                            return;
+                                            return Some(insn);
                                         }
                                         0x3 => {
                                             insn.i_type = InsnType::InsnAnd;
-                                            return;
+                                            return Some(insn);
                                         }
                                         _ => {
-                                            unreachable!()
+                                            return None
                                         }
                                     }
                                 }
                                 0x1 => {
                                     let cfunct2low = cfunc_t2_low!(data);
 
-                                    *insn = insn_catype_read(data as u16);
+                                    insn = insn_catype_read(data as u16);
                                     insn.rs1 = insn.rd;
                                     match cfunct2low {
                                         0x0 => {
                                             insn.i_type = InsnType::InsnSubw;
-                                            return;
+                                            return Some(insn);
                                         }
                                         0x1 => {
                                             insn.i_type = InsnType::InsnAddw;
-                                            return;
+                                            return Some(insn);
                                         }
                                         _ => {
-                                            unreachable!()
+                                            return None
                                         }
                                     }
                                 }
                                 _ => {
-                                    unreachable!()
+                                    return None
                                 }
                             }
                         }
@@ -670,25 +681,25 @@ pub fn insn_decode(insn: &mut Insn, data: u32) {
                     }
                 }
                 0x5 => {
-                    *insn = insn_cjtype_read(data as u16);
+                    insn = insn_cjtype_read(data as u16);
                     insn.rd = GpRegTypeT::Zero as i8;
                     insn.i_type = InsnType::InsnJal;
                     insn.cont = true;
-                    return;
+                    return Some(insn);
                 }
                 0x6 | 0x7 => {
-                    *insn = insn_cbtype_read(data as u16);
+                    insn = insn_cbtype_read(data as u16);
                     insn.rs2 = GpRegTypeT::Zero as i8;
                     if copcode == 0x6 {
                         insn.i_type = InsnType::InsnBeq;
-                        return;
+                        return Some(insn);
                     } else {
                         insn.i_type = InsnType::InsnBne;
-                        return;
+                        return Some(insn);
                     }
                 }
                 _ => {
-                    fatal!("unrecognized copcode");
+                    return None;
                 }
             }
         }
@@ -696,94 +707,113 @@ pub fn insn_decode(insn: &mut Insn, data: u32) {
             let copcode = cop_code!(data);
             match copcode {
                 0x0 => {
-                    *insn = insn_citype_read(data as u16);
+                    insn = insn_citype_read(data as u16);
                     insn.rs1 = insn.rd;
                     insn.i_type = InsnType::InsnSlli;
-                    return;
+                    return Some(insn);
                 }
                 0x1 => {
-                    *insn = insn_citype_read2(data as u16);
+                    insn = insn_citype_read2(data as u16);
                     insn.rs1 = GpRegTypeT::Sp as i8;
                     insn.i_type = InsnType::InsnFld;
-                    return;
+                    return Some(insn);
                 }
                 0x2 => {
-                    *insn = insn_citype_read4(data as u16);
-                    assert!(insn.rd != 0);
+                    insn = insn_citype_read4(data as u16);
+                    if insn.rd == 0 {
+                        return None;
+                    }
                     insn.rs1 = GpRegTypeT::Sp as i8;
                     insn.i_type = InsnType::InsnLw;
-                    return;
+                    return Some(insn);
                 }
                 0x3 => {
-                    *insn = insn_citype_read2(data as u16);
-                    assert!(insn.rd != 0);
+                    insn = insn_citype_read2(data as u16);
+                    if insn.rd == 0 {
+                        return None;
+                    }
                     insn.rs1 = GpRegTypeT::Sp as i8;
                     insn.i_type = InsnType::InsnLd;
-                    return;
+                    return Some(insn);
                 }
                 0x4 => {
                     let cfunct1 = cfunc_t1!(data);
                     match cfunct1 {
                         0x0 => {
-                            *insn = insn_crtype_read(data as u16);
+                            insn = insn_crtype_read(data as u16);
 
                             if insn.rs2 == 0 {
-                                assert!(insn.rs1 != 0);
+                                if insn.rs1 == 0 {
+                                    return None;
+                                }
                                 insn.rd = GpRegTypeT::Zero as i8;
                                 insn.i_type = InsnType::InsnJalr;
                                 insn.cont = true;
-                                return;
+                                return Some(insn);
                             } else {
                                 insn.rd = insn.rs1;
                                 insn.rs1 = GpRegTypeT::Zero as i8;
                                 insn.i_type = InsnType::InsnAdd;
-                                return;
+                                return Some(insn);
                             }
                         }
                         0x1 => {
-                            *insn = insn_crtype_read(data as u16);
+                            insn = insn_crtype_read(data as u16);
                             if insn.rs1 == 0 && insn.rs2 == 0 {
-                                fatal!("unimplmented");
-                                return;
+                                return None;
                             } else if insn.rs2 == 0 {
                                 insn.rd = GpRegTypeT::RA as i8;
                                 insn.i_type = InsnType::InsnJalr;
                                 insn.cont = true;
-                                return;
+                                return Some(insn);
                             } else {
                                 insn.rd = insn.rs1;
                                 insn.i_type = InsnType::InsnAdd;
-                                return;
+                                return Some(insn);
                             }
                         }
                         _ => {
-                            unreachable!()
+                            return None
                         }
                     }
                 }
                 0x5 => {
-                    *insn = insn_csstype_read(data as u16);
+                    insn = insn_csstype_read(data as u16);
                     insn.rs1 = GpRegTypeT::Sp as i8;
                     insn.i_type = InsnType::InsnFsd;
-                    return;
+                    return Some(insn);
                 }
                 0x6 => {
-                    *insn = insn_csstype_read2(data as u16);
+                    insn = insn_csstype_read2(data as u16);
                     insn.rs1 = GpRegTypeT::Sp as i8;
                     insn.i_type = InsnType::InsnSw;
-                    return;
+                    return Some(insn);
                 }
                 0x7 => {
-                    *insn = insn_csstype_read(data as u16);
+                    insn = insn_csstype_read(data as u16);
                     insn.rs1 = GpRegTypeT::Sp as i8;
                     insn.i_type = InsnType::InsnSd;
-                    return;
+                    return Some(insn);
                 }
                 _ => {
-                    fatal!("unrecognized copcode")
+                    return None
                 }
             }
         }
+        _ => {}
+    }
+    return None;
+}
+
+pub fn insn_decode_tree(insn: &mut Insn, data: u32) {
+    let quadrant = quadrant!(data);
+
+    match quadrant {
+        0x0..=0x2 => match insn_decode_rvc(data) {
+            Some(rvc) => *insn = rvc,
+            None if insn_unimplemented(data) => fatal!(format!("unimplemented: {:#x}", data)),
+            None => unreachable!(),
+        },
         0x3 => {
             let opcode = op_code!(data);
             match opcode {
@@ -1168,7 +1198,9 @@ pub fn insn_decode(insn: &mut Insn, data: u32) {
                                 unreachable!()
                             }
                         },
-                        _ => {}
+                        _ => {
+                            unreachable!()
+                        }
                     }
                 }
                 0x10 => {
@@ -1550,7 +1582,9 @@ pub fn insn_decode(insn: &mut Insn, data: u32) {
                             insn.i_type = InsnType::InsnFmvDX;
                             return;
                         }
-                        _ => {}
+                        _ => {
+                            unreachable!()
+                        }
                     }
                 }
                 0x18 => {
@@ -1647,3 +1681,398 @@ pub fn insn_decode(insn: &mut Insn, data: u32) {
         _ => unreachable!(),
     }
 }
+
+fn insn_fence_read(_data: u32) -> Insn {
+    return Insn::new();
+}
+
+type OpcodeReader = fn(u32) -> Insn;
+
+// first level of the 32-bit table: the format reader of each major opcode
+static OPCODE_READERS: [Option<OpcodeReader>; 32] = [
+    Some(insn_itype_read),   // 0x00 load
+    Some(insn_itype_read),   // 0x01 load-fp
+    None,
+    Some(insn_fence_read),   // 0x03 misc-mem
+    Some(insn_itype_read),   // 0x04 op-imm
+    Some(insn_utype_read),   // 0x05 auipc
+    Some(insn_itype_read),   // 0x06 op-imm-32
+    None,
+    Some(insn_stype_read),   // 0x08 store
+    Some(insn_stype_read),   // 0x09 store-fp
+    None,
+    None,
+    Some(insn_rtype_read),   // 0x0c op
+    Some(insn_utype_read),   // 0x0d lui
+    Some(insn_rtype_read),   // 0x0e op-32
+    None,
+    Some(insn_fprtype_read), // 0x10 madd
+    Some(insn_fprtype_read), // 0x11 msub
+    Some(insn_fprtype_read), // 0x12 nmsub
+    Some(insn_fprtype_read), // 0x13 nmadd
+    Some(insn_rtype_read),   // 0x14 op-fp
+    None,
+    None,
+    None,
+    Some(insn_btype_read),   // 0x18 branch
+    Some(insn_itype_read),   // 0x19 jalr
+    None,
+    Some(insn_jtype_read),   // 0x1b jal
+    Some(insn_csrtype_read), // 0x1c system
+    None,
+    None,
+    None,
+];
+
+// second level: funct3 and funct7, plus rs2 for op-fp where it selects
+// the conversion. the tables are built from those fields, the same ones
+// insn_decode_tree matches on.
+type OpcodeTable = Vec<Option<(InsnType, bool)>>;
+
+static OPCODE_TABLES: [OnceLock<OpcodeTable>; 32] = [const { OnceLock::new() }; 32];
+static RVC_TABLE: OnceLock<Vec<Option<Insn>>> = OnceLock::new();
+
+fn opcode_key(data: u32) -> usize {
+    let key = (func_t3!(data) | (func_t7!(data) << 3)) as usize;
+    if op_code!(data) == 0x14 {
+        return key | (rs2!(data) as usize) << 10;
+    }
+    return key;
+}
+
+// encodings insn_decode_tree reports with fatal! instead of panicking
+fn insn_unimplemented(data: u32) -> bool {
+    return match quadrant!(data) {
+        0x0 => cop_code!(data) == 0x4,
+        0x2 => data & 0xffff == 0x9002,
+        0x3 => op_code!(data) == 0x6 && ![0x0, 0x1, 0x5].contains(&func_t3!(data)),
+        _ => false,
+    };
+}
+
+// the type of a 32-bit insn from its fields, None where insn_decode_tree
+// rejects it. ecall is a single encoding, matched before the tables.
+fn opcode_type(opcode: u32, funct3: u32, funct7: u32, rs2: u32) -> Option<InsnType> {
+    use InsnType::*;
+
+    let fp = |single: InsnType, double: InsnType| match funct7 & 0x3 {
+        0x0 => Some(single),
+        0x1 => Some(double),
+        _ => None,
+    };
+    let typ = match (opcode, funct3, funct7) {
+        (0x00, 0x0, _) => InsnLb,
+        (0x00, 0x1, _) => InsnLh,
+        (0x00, 0x2, _) => InsnLw,
+        (0x00, 0x3, _) => InsnLd,
+        (0x00, 0x4, _) => InsnLbu,
+        (0x00, 0x5, _) => InsnLhu,
+        (0x00, 0x6, _) => InsnLwu,
+        (0x01, 0x2, _) => InsnFlw,
+        (0x01, 0x3, _) => InsnFld,
+        (0x03, 0x0, _) => InsnFence,
+        (0x03, 0x1, _) => InsnFenceI,
+        // shamt is six bits, funct7 holds its top one
+        (0x04, 0x0, _) => InsnAddi,
+        (0x04, 0x1, 0x00 | 0x01) => InsnSlli,
+        (0x04, 0x2, _) => InsnSlti,
+        (0x04, 0x3, _) => InsnSltiu,
+        (0x04, 0x4, _) => InsnXori,
+        (0x04, 0x5, 0x00 | 0x01) => InsnSrli,
+        (0x04, 0x5, 0x20 | 0x21) => InsnSrai,
+        (0x04, 0x6, _) => InsnOri,
+        (0x04, 0x7, _) => InsnAndi,
+        (0x05, _, _) => InsnAuipc,
+        (0x06, 0x0, _) => InsnAddiw,
+        (0x06, 0x1, 0x00) => InsnSlliw,
+        (0x06, 0x5, 0x00) => InsnSrliw,
+        (0x06, 0x5, 0x20) => InsnSraiw,
+        (0x08, 0x0, _) => InsnSb,
+        (0x08, 0x1, _) => InsnSh,
+        (0x08, 0x2, _) => InsnSw,
+        (0x08, 0x3, _) => InsnSd,
+        (0x09, 0x2, _) => InsnFsw,
+        (0x09, 0x3, _) => InsnFsd,
+        (0x0c, 0x0, 0x00) => InsnAdd,
+        (0x0c, 0x1, 0x00) => InsnSll,
+        (0x0c, 0x2, 0x00) => InsnSlt,
+        (0x0c, 0x3, 0x00) => InsnSltu,
+        (0x0c, 0x4, 0x00) => InsnXor,
+        (0x0c, 0x5, 0x00) => InsnSrl,
+        (0x0c, 0x6, 0x00) => InsnOr,
+        (0x0c, 0x7, 0x00) => InsnAnd,
+        (0x0c, 0x0, 0x01) => InsnMul,
+        (0x0c, 0x1, 0x01) => InsnMulh,
+        (0x0c, 0x2, 0x01) => InsnMulhsu,
+        (0x0c, 0x3, 0x01) => InsnMulhu,
+        (0x0c, 0x4, 0x01) => InsnDiv,
+        (0x0c, 0x5, 0x01) => InsnDivu,
+        (0x0c, 0x6, 0x01) => InsnRem,
+        (0x0c, 0x7, 0x01) => InsnRemu,
+        (0x0c, 0x0, 0x20) => InsnSub,
+        (0x0c, 0x5, 0x20) => InsnSra,
+        (0x0d, _, _) => InsnLui,
+        (0x0e, 0x0, 0x00) => InsnAddw,
+        (0x0e, 0x1, 0x00) => InsnSllw,
+        (0x0e, 0x5, 0x00) => InsnSrlw,
+        (0x0e, 0x0, 0x01) => InsnMulw,
+        (0x0e, 0x4, 0x01) => InsnDivw,
+        (0x0e, 0x5, 0x01) => InsnDivuw,
+        (0x0e, 0x6, 0x01) => InsnRemw,
+        (0x0e, 0x7, 0x01) => InsnRemuw,
+        (0x0e, 0x0, 0x20) => InsnSubw,
+        (0x0e, 0x5, 0x20) => InsnSraw,
+        // funct3 is the rounding mode, funct7 the format and rs3
+        (0x10, _, _) => fp(InsnFmaddS, InsnFmaddD)?,
+        (0x11, _, _) => fp(InsnFmsubS, InsnFmsubD)?,
+        (0x12, _, _) => fp(InsnFnmsubS, InsnFnmsubD)?,
+        (0x13, _, _) => fp(InsnFnmaddS, InsnFnmaddD)?,
+        (0x14, _, _) => return opfp_type(funct3, funct7, rs2),
+        (0x18, 0x0, _) => InsnBeq,
+        (0x18, 0x1, _) => InsnBne,
+        (0x18, 0x4, _) => InsnBlt,
+        (0x18, 0x5, _) => InsnBge,
+        (0x18, 0x6, _) => InsnBltu,
+        (0x18, 0x7, _) => InsnBgeu,
+        (0x19, _, _) => InsnJalr,
+        (0x1b, _, _) => InsnJal,
+        (0x1c, 0x1, _) => InsnCsrrw,
+        (0x1c, 0x2, _) => InsnCsrrs,
+        (0x1c, 0x3, _) => InsnCsrrc,
+        (0x1c, 0x5, _) => InsnCsrrwi,
+        (0x1c, 0x6, _) => InsnCsrrsi,
+        (0x1c, 0x7, _) => InsnCsrrci,
+        _ => return None,
+    };
+    return Some(typ);
+}
+
+// op-fp: funct7 is the operation and format, funct3 the rounding mode or
+// a sub-operation, rs2 the conversion
+fn opfp_type(funct3: u32, funct7: u32, rs2: u32) -> Option<InsnType> {
+    use InsnType::*;
+
+    let typ = match (funct7, funct3, rs2) {
+        (0x00, _, _) => InsnFaddS,
+        (0x01, _, _) => InsnFaddD,
+        (0x04, _, _) => InsnFsubS,
+        (0x05, _, _) => InsnFsubD,
+        (0x08, _, _) => InsnFmulS,
+        (0x09, _, _) => InsnFmulD,
+        (0x0c, _, _) => InsnFdivS,
+        (0x0d, _, _) => InsnFdivD,
+        (0x10, 0x0, _) => InsnFsgnjS,
+        (0x10, 0x1, _) => InsnFsgnjnS,
+        (0x10, 0x2, _) => InsnFsgnjxS,
+        (0x11, 0x0, _) => InsnFsgnjD,
+        (0x11, 0x1, _) => InsnFsgnjnD,
+        (0x11, 0x2, _) => InsnFsgnjxD,
+        (0x14, 0x0, _) => InsnFminS,
+        (0x14, 0x1, _) => InsnFmaxS,
+        (0x15, 0x0, _) => InsnFminD,
+        (0x15, 0x1, _) => InsnFmaxD,
+        (0x20, _, 0x1) => InsnFcvtSD,
+        (0x21, _, 0x1) => InsnFcvtDS,
+        (0x2c, _, 0x1) => InsnFsqrtS,
+        (0x2d, _, 0x1) => InsnFsqrtD,
+        (0x50, 0x0, _) => InsnFleS,
+        (0x50, 0x1, _) => InsnFltS,
+        (0x50, 0x2, _) => InsnFeqS,
+        (0x51, 0x0, _) => InsnFleD,
+        (0x51, 0x1, _) => InsnFltD,
+        (0x51, 0x2, _) => InsnFeqD,
+        (0x60, _, 0x0) => InsnFcvtWS,
+        (0x60, _, 0x1) => InsnFcvtWuS,
+        (0x60, _, 0x2) => InsnFcvtLS,
+        (0x60, _, 0x3) => InsnFcvtLuS,
+        (0x61, _, 0x0) => InsnFcvtWD,
+        (0x61, _, 0x1) => InsnFcvtWuD,
+        (0x61, _, 0x2) => InsnFcvtLD,
+        (0x61, _, 0x3) => InsnFcvtLuD,
+        (0x68, _, 0x0) => InsnFcvtSW,
+        (0x68, _, 0x1) => InsnFcvtSWu,
+        (0x68, _, 0x2) => InsnFcvtSL,
+        (0x68, _, 0x3) => InsnFcvtSLu,
+        (0x69, _, 0x0) => InsnFcvtDW,
+        (0x69, _, 0x1) => InsnFcvtDWu,
+        (0x69, _, 0x2) => InsnFcvtDL,
+        (0x69, _, 0x3) => InsnFcvtDLu,
+        (0x70, _, 0x0) => InsnFmvXW,
+        (0x70, _, 0x1) => InsnFclassS,
+        (0x71, _, 0x0) => InsnFmvXD,
+        (0x71, _, 0x1) => InsnFclassD,
+        (0x78, 0x0, 0x0) => InsnFmvWX,
+        (0x79, 0x0, 0x0) => InsnFmvDX,
+        _ => return None,
+    };
+    return Some(typ);
+}
+
+fn rvc_table() -> &'static Vec<Option<Insn>> {
+    return RVC_TABLE.get_or_init(|| (0..1 << 16).map(insn_decode_rvc).collect());
+}
+
+fn opcode_table(opcode: u32) -> &'static OpcodeTable {
+    return OPCODE_TABLES[opcode as usize].get_or_init(|| {
+        let len = if opcode == 0x14 { 1 << 15 } else { 1 << 10 };
+        return (0..len as u32)
+            .map(|key| {
+                let typ = opcode_type(opcode, key & 0x7, key >> 3 & 0x7f, key >> 10)?;
+                let cont = matches!(typ, InsnType::InsnJal | InsnType::InsnJalr);
+                return Some((typ, cont));
+            })
+            .collect();
+    });
+}
+
+// decode without reporting, None for encodings the decoder rejects
+pub fn insn_decode_checked(data: u32) -> Option<Insn> {
+    if quadrant!(data) != 0x3 {
+        return rvc_table()[(data & 0xffff) as usize];
+    }
+    if data == 0x73 {
+        let mut insn = Insn::new();
+        insn.i_type = InsnType::InsnEcall;
+        insn.cont = true;
+        return Some(insn);
+    }
+    let read = OPCODE_READERS[op_code!(data) as usize]?;
+    let (i_type, cont) = opcode_table(op_code!(data))[opcode_key(data)]?;
+    let mut insn = read(data);
+    insn.i_type = i_type;
+    insn.cont = cont;
+    return Some(insn);
+}
+
+// encodings missing from the tables go through the decode tree, which
+// reports them the way it always has
+pub fn insn_decode(insn: &mut Insn, data: u32) {
+    match insn_decode_checked(data) {
+        Some(decoded) => *insn = decoded,
+        None => insn_decode_tree(insn, data),
+    }
+}
+
+// the second insn must consume the result of the first, so the pair has
+// the same architectural effect as running both in order
+pub fn insn_fuse(first: &Insn, second: &Insn) -> Fusion {
+    if first.rd == 0 {
+        return Fusion::None;
+    }
+
+    return match (first.i_type, second.i_type) {
+        (InsnType::InsnLui, InsnType::InsnAddi)
+            if second.rd == first.rd && second.rs1 == first.rd => Fusion::LuiAddi,
+        (InsnType::InsnAuipc, InsnType::InsnJalr) if second.rs1 == first.rd => Fusion::AuipcJalr,
+        (InsnType::InsnAuipc, InsnType::InsnLd) if second.rs1 == first.rd => Fusion::AuipcLd,
+        (InsnType::InsnSlli, InsnType::InsnSrli)
+            if second.rd == first.rd
+                && second.rs1 == first.rd
+                && first.imm == 32
+                && second.imm == 32 => Fusion::SlliSrli,
+        (InsnType::InsnMulh, InsnType::InsnMul)
+            if second.rs1 == first.rs1
+                && second.rs2 == first.rs2
+                && first.rd != first.rs1
+                && first.rd != first.rs2
+                && second.rd != 0 => Fusion::MulhMul,
+        _ => Fusion::None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        cell::Cell,
+        panic::{self, AssertUnwindSafe},
+    };
+
+    thread_local! {
+        static IN_TREE: Cell<bool> = const { Cell::new(false) };
+    }
+
+    // the tree panics on encodings it rejects
+    fn decode_tree(data: u32) -> Option<Insn> {
+        let mut insn = Insn::new();
+        IN_TREE.with(|t| t.set(true));
+        let decoded = panic::catch_unwind(AssertUnwindSafe(|| insn_decode_tree(&mut insn, data)));
+        IN_TREE.with(|t| t.set(false));
+        return decoded.ok().map(|_| insn);
+    }
+
+    fn check(data: u32) {
+        match insn_decode_checked(data) {
+            Some(insn) => assert_eq!(Some(insn), decode_tree(data), "{:#x}", data),
+            None => assert!(
+                insn_unimplemented(data) || decode_tree(data).is_none(),
+                "{:#x}",
+                data
+            ),
+        }
+    }
+
+    #[test]
+    fn decode_table_matches_tree() {
+        // keep the tree's panics quiet, everything else still reports
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !IN_TREE.with(|t| t.get()) {
+                hook(info);
+            }
+        }));
+
+        for data in 0..1 << 16 {
+            check(data);
+        }
+        for opcode in 0..32 {
+            let len = if opcode == 0x14 { 1 << 15 } else { 1 << 10 };
+            for key in 0..len {
+                let rs2 = if opcode == 0x14 { key >> 10 } else { 0x7 };
+                let data = 0x3
+                    | opcode << 2
+                    | 0x5 << 7
+                    | (key & 0x7) << 12
+                    | 0x6 << 15
+                    | rs2 << 20
+                    | (key >> 3 & 0x7f) << 25;
+                check(data);
+            }
+        }
+        check(0x73);
+    }
+
+    fn fuse(first: u32, second: u32) -> Fusion {
+        let first = insn_decode_checked(first).unwrap();
+        let second = insn_decode_checked(second).unwrap();
+        return insn_fuse(&first, &second);
+    }
+
+    #[test]
+    fn fuse_pairs() {
+        // lui a0, 0x12; addi a0, a0, 0x345
+        assert_eq!(fuse(0x00012537, 0x34550513), Fusion::LuiAddi);
+        // lui a0, 0x12; addi a1, a0, 1: a0 is still live
+        assert_eq!(fuse(0x00012537, 0x00150593), Fusion::None);
+        // lui zero, 0x12; addi zero, zero, 1
+        assert_eq!(fuse(0x00012037, 0x00100013), Fusion::None);
+        // auipc t0, 0; jalr ra, 16(t0)
+        assert_eq!(fuse(0x00000297, 0x010280e7), Fusion::AuipcJalr);
+        // auipc t0, 0; ld a0, 8(t0)
+        assert_eq!(fuse(0x00000297, 0x0082b503), Fusion::AuipcLd);
+        // auipc t0, 0; ld a0, 8(t1)
+        assert_eq!(fuse(0x00000297, 0x00833503), Fusion::None);
+        // slli a0, a0, 32; srli a0, a0, 32
+        assert_eq!(fuse(0x02051513, 0x02055513), Fusion::SlliSrli);
+        // slli a0, a0, 32; srli a0, a0, 31
+        assert_eq!(fuse(0x02051513, 0x01f55513), Fusion::None);
+        // mulh a2, a0, a1; mul a3, a0, a1
+        assert_eq!(fuse(0x02b51633, 0x02b506b3), Fusion::MulhMul);
+        // mulh a0, a0, a1; mul a3, a0, a1: mulh clobbers an operand
+        assert_eq!(fuse(0x02b51533, 0x02b506b3), Fusion::None);
+        // mulh a2, a0, a1; mul a3, a1, a0
+        assert_eq!(fuse(0x02b51633, 0x02a586b3), Fusion::None);
+        // mulh a2, a0, a1; mul zero, a0, a1
+        assert_eq!(fuse(0x02b51633, 0x02b50033), Fusion::None);
+    }
+}
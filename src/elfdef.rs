@@ -1,15 +1,66 @@
+use std::{mem::size_of, ptr};
+
 pub const EI_NIDENT: usize = 16;
 pub const ELFMAG: &[u8; 4] = b"\x7fELF";
 
 pub const EM_RISCV: u16 = 243;
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
 
 pub const EI_CLASS: usize = 4;
 pub const ELFCLASSNONE: u64 = 0;
 pub const ELFCLASS32: u64 = 1;
 pub const ELFCLASS64: u8 = 2;
 pub const ELFCLASSNUM: u64 = 3;
+pub const EI_DATA: usize = 5;
+pub const ELFDATA2LSB: u8 = 1;
+pub const EI_OSABI: usize = 7;
+pub const ELFOSABI_LINUX: u8 = 3;
 
 pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_INTERP: u32 = 3;
+pub const PT_NOTE: u32 = 4;
+pub const PT_PHDR: u32 = 6;
+pub const PT_TLS: u32 = 7;
+pub const PT_GNU_STACK: u32 = 0x6474e551;
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_FLAGS: u64 = 8;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_UID: u64 = 11;
+pub const AT_EUID: u64 = 12;
+pub const AT_GID: u64 = 13;
+pub const AT_EGID: u64 = 14;
+pub const AT_HWCAP: u64 = 16;
+pub const AT_CLKTCK: u64 = 17;
+pub const AT_SECURE: u64 = 23;
+pub const AT_RANDOM: u64 = 25;
+pub const AT_EXECFN: u64 = 31;
+pub const AT_SYSINFO_EHDR: u64 = 33;
+pub const AT_MINSIGSTKSZ: u64 = 51;
+pub const EV_CURRENT: u8 = 1;
+pub const EF_RISCV_RVC: u32 = 0x1;
+pub const EF_RISCV_FLOAT_ABI_DOUBLE: u32 = 0x4;
+pub const SHT_SYMTAB: u32 = 2;
+pub const NT_GNU_ABI_TAG: u32 = 1;
+pub const STB_GLOBAL: u8 = 1;
+pub const STT_FUNC: u8 = 2;
+pub const DT_NULL: i64 = 0;
+pub const DT_HASH: i64 = 4;
+pub const DT_STRTAB: i64 = 5;
+pub const DT_SYMTAB: i64 = 6;
+pub const DT_STRSZ: i64 = 10;
+pub const DT_SYMENT: i64 = 11;
+pub const DT_SONAME: i64 = 14;
+pub const DT_VERSYM: i64 = 0x6ffffff0;
+pub const DT_VERDEF: i64 = 0x6ffffffc;
+pub const DT_VERDEFNUM: i64 = 0x6ffffffd;
+pub const VER_FLG_BASE: u16 = 0x1;
 
 pub const PF_X: i32 = 0x1;
 pub const PF_W: i32 = 0x2;
@@ -65,19 +116,20 @@ macro_rules! array_size {
 
 #[macro_export]
 macro_rules! to_host {
-    ($addr:expr) => {
-        $addr + 0x018800000000u64
+    ($base:expr, $addr:expr) => {
+        ($base).wrapping_add($addr)
     };
 }
 
 #[macro_export]
 macro_rules! to_guest {
-    ($addr:expr) => {
-        $addr - 0x018800000000u64
+    ($base:expr, $addr:expr) => {
+        $addr - ($base)
     };
 }
 
 #[repr(C)]
+#[derive(Debug, Clone, Copy)]
 pub struct Ehdr {
     pub e_ident: [u8; EI_NIDENT],
     pub e_type: u16,
@@ -122,3 +174,86 @@ impl Phdr {
         }
     }
 }
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Shdr {
+    pub sh_name: u32,
+    pub sh_type: u32,
+    pub sh_flags: u64,
+    pub sh_addr: u64,
+    pub sh_offset: u64,
+    pub sh_size: u64,
+    pub sh_link: u32,
+    pub sh_info: u32,
+    pub sh_addralign: u64,
+    pub sh_entsize: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Nhdr {
+    pub n_namesz: u32,
+    pub n_descsz: u32,
+    pub n_type: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Sym {
+    pub st_name: u32,
+    pub st_info: u8,
+    pub st_other: u8,
+    pub st_shndx: u16,
+    pub st_value: u64,
+    pub st_size: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Dyn {
+    pub d_tag: i64,
+    pub d_val: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Verdef {
+    pub vd_version: u16,
+    pub vd_flags: u16,
+    pub vd_ndx: u16,
+    pub vd_cnt: u16,
+    pub vd_hash: u32,
+    pub vd_aux: u32,
+    pub vd_next: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Verdaux {
+    pub vda_name: u32,
+    pub vda_next: u32,
+}
+
+// the sysv hash of a symbol or version name
+pub fn elf_hash(name: &str) -> u32 {
+    let mut h: u32 = 0;
+    for b in name.bytes() {
+        h = (h << 4).wrapping_add(b as u32);
+        let g = h & 0xf0000000;
+        if g != 0 {
+            h ^= g >> 24;
+        }
+        h &= !g;
+    }
+    return h;
+}
+
+// a T at off in buf, None if it runs past the end
+pub fn elf_read<T: Copy>(buf: &[u8], off: u64) -> Option<T> {
+    let end = off.checked_add(size_of::<T>() as u64)?;
+    if end > buf.len() as u64 {
+        return None;
+    }
+    return Some(unsafe { ptr::read_unaligned(buf[off as usize..].as_ptr() as *const T) });
+}
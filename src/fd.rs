@@ -0,0 +1,274 @@
+use std::{cell::RefCell, collections::BTreeMap, io, os::raw::c_void, rc::Rc};
+
+use libc::{EBADF, EINVAL, EMFILE, ESPIPE};
+
+// what a guest fd refers to. dup'd fds share one File, with its offset.
+pub enum File {
+    // a host fd, closed with the last guest fd for it unless the emulator
+    // did not open it, like its own stdio. path is the guest path it was
+    // opened by, for *at calls under a sandbox.
+    Host {
+        fd: i32,
+        owned: bool,
+        path: Option<String>,
+    },
+    // bytes the guest reads, pos is how far it got
+    Input {
+        data: Vec<u8>,
+        pos: usize,
+    },
+    // everything the guest writes, for whoever runs the machine to look at
+    Output {
+        data: Vec<u8>,
+    },
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        if let File::Host {
+            fd, owned: true, ..
+        } = *self
+        {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+pub struct Fd {
+    pub file: Rc<RefCell<File>>,
+    // FD_CLOEXEC, which only the guest sees: every host fd the emulator
+    // opens is close-on-exec anyway
+    pub cloexec: bool,
+}
+
+pub struct FdTable {
+    pub fds: BTreeMap<i32, Fd>,
+    // RLIMIT_NOFILE, fds go from 0 up to below it
+    pub limit: i32,
+}
+
+pub const FD_LIMIT: i32 = 1024;
+
+impl FdTable {
+    // the guest starts with the emulator's stdin, stdout and stderr
+    pub fn new() -> FdTable {
+        let mut table = FdTable {
+            fds: BTreeMap::new(),
+            limit: FD_LIMIT,
+        };
+        for fd in 0..3 {
+            let file = File::Host {
+                fd,
+                owned: false,
+                path: None,
+            };
+            fd_install(&mut table, fd, file);
+        }
+        return table;
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> FdTable {
+        return FdTable::new();
+    }
+}
+
+fn host_errno() -> i32 {
+    return io::Error::last_os_error().raw_os_error().unwrap();
+}
+
+// put file at fd, closing what was there
+pub fn fd_install(table: &mut FdTable, fd: i32, file: File) {
+    let fd_entry = Fd {
+        file: Rc::new(RefCell::new(file)),
+        cloexec: false,
+    };
+    table.fds.insert(fd, fd_entry);
+}
+
+// the lowest free fd from min up
+fn fd_lowest(table: &FdTable, min: i32) -> Result<i32, i32> {
+    let mut fd = min;
+    for (&used, _) in table.fds.range(min..) {
+        if used != fd {
+            break;
+        }
+        fd += 1;
+    }
+    if fd >= table.limit {
+        return Err(EMFILE);
+    }
+    return Ok(fd);
+}
+
+// a new guest fd for file, the lowest free one like linux
+pub fn fd_new(table: &mut FdTable, file: File, cloexec: bool) -> Result<i32, i32> {
+    let fd = fd_lowest(table, 0)?;
+    let file = Rc::new(RefCell::new(file));
+    table.fds.insert(fd, Fd { file, cloexec });
+    return Ok(fd);
+}
+
+pub fn fd_get(table: &FdTable, fd: u64) -> Result<Rc<RefCell<File>>, i32> {
+    return match table
+        .fds
+        .get(&(fd as i32))
+        .filter(|_| fd <= i32::MAX as u64)
+    {
+        Some(entry) => Ok(entry.file.clone()),
+        None => Err(EBADF),
+    };
+}
+
+// the host fd behind a guest fd, for the calls that go straight to the
+// host with it. an in-memory file has none.
+pub fn fd_host(table: &FdTable, fd: u64, none: i32) -> Result<i32, i32> {
+    return match *fd_get(table, fd)?.borrow() {
+        File::Host { fd, .. } => Ok(fd),
+        _ => Err(none),
+    };
+}
+
+pub fn fd_close(table: &mut FdTable, fd: u64) -> Result<(), i32> {
+    if fd > i32::MAX as u64 {
+        return Err(EBADF);
+    }
+    return table.fds.remove(&(fd as i32)).map(|_| ()).ok_or(EBADF);
+}
+
+// dup and F_DUPFD: the lowest free fd from min up
+pub fn fd_dup(table: &mut FdTable, old: u64, min: u64, cloexec: bool) -> Result<i32, i32> {
+    let file = fd_get(table, old)?;
+    if min >= table.limit as u64 {
+        return Err(EINVAL);
+    }
+    let fd = fd_lowest(table, min as i32)?;
+    table.fds.insert(fd, Fd { file, cloexec });
+    return Ok(fd);
+}
+
+// dup3: new becomes old, closing what new was
+pub fn fd_dup3(table: &mut FdTable, old: u64, new: u64, cloexec: bool) -> Result<i32, i32> {
+    let file = fd_get(table, old)?;
+    if old == new {
+        return Err(EINVAL);
+    }
+    if new >= table.limit as u64 {
+        return Err(EBADF);
+    }
+    table.fds.insert(new as i32, Fd { file, cloexec });
+    return Ok(new as i32);
+}
+
+pub fn fd_read(file: &mut File, buf: &mut [u8], offset: Option<u64>) -> Result<usize, i32> {
+    let ret = match (file, offset) {
+        (File::Host { fd, .. }, None) => unsafe {
+            libc::read(*fd, buf.as_mut_ptr() as *mut c_void, buf.len())
+        },
+        (File::Host { fd, .. }, Some(offset)) => unsafe {
+            libc::pread(
+                *fd,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len(),
+                offset as i64,
+            )
+        },
+        (File::Input { data, pos }, None) => {
+            let len = buf.len().min(data.len() - *pos);
+            buf[..len].copy_from_slice(&data[*pos..*pos + len]);
+            *pos += len;
+            return Ok(len);
+        }
+        (File::Input { .. }, Some(_)) => return Err(ESPIPE),
+        (File::Output { .. }, _) => return Err(EBADF),
+    };
+    if ret < 0 {
+        return Err(host_errno());
+    }
+    return Ok(ret as usize);
+}
+
+pub fn fd_write(file: &mut File, buf: &[u8], offset: Option<u64>) -> Result<usize, i32> {
+    let ret = match (file, offset) {
+        (File::Host { fd, .. }, None) => unsafe {
+            libc::write(*fd, buf.as_ptr() as *const c_void, buf.len())
+        },
+        (File::Host { fd, .. }, Some(offset)) => unsafe {
+            libc::pwrite(*fd, buf.as_ptr() as *const c_void, buf.len(), offset as i64)
+        },
+        (File::Output { data }, None) => {
+            data.extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+        (File::Output { .. }, Some(_)) => return Err(ESPIPE),
+        (File::Input { .. }, _) => return Err(EBADF),
+    };
+    if ret < 0 {
+        return Err(host_errno());
+    }
+    return Ok(ret as usize);
+}
+
+// what an Output file has collected so far
+pub fn fd_output(table: &FdTable, fd: u64) -> Option<Vec<u8>> {
+    return match &*fd_get(table, fd).ok()?.borrow() {
+        File::Output { data } => Some(data.clone()),
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fd_lowest_free() {
+        let mut table = FdTable::new();
+        let input = File::Input {
+            data: Vec::new(),
+            pos: 0,
+        };
+        assert_eq!(fd_new(&mut table, input, false), Ok(3));
+        assert_eq!(fd_close(&mut table, 1), Ok(()));
+        assert_eq!(fd_close(&mut table, 1), Err(EBADF));
+        assert_eq!(fd_dup(&mut table, 3, 0, true), Ok(1));
+        assert!(table.fds[&1].cloexec);
+        assert_eq!(fd_dup(&mut table, 3, 2, false), Ok(4));
+        assert_eq!(fd_dup(&mut table, 3, FD_LIMIT as u64, false), Err(EINVAL));
+        assert_eq!(fd_dup3(&mut table, 3, 3, false), Err(EINVAL));
+        assert_eq!(fd_dup3(&mut table, 3, 9, false), Ok(9));
+        assert_eq!(fd_dup(&mut table, 7, 0, false), Err(EBADF));
+    }
+
+    #[test]
+    fn fd_buffers() {
+        let mut table = FdTable::new();
+        let input = File::Input {
+            data: b"hello".to_vec(),
+            pos: 0,
+        };
+        fd_install(&mut table, 0, input);
+        fd_install(&mut table, 1, File::Output { data: Vec::new() });
+        // a dup shares the read position
+        assert_eq!(fd_dup(&mut table, 0, 0, false), Ok(3));
+        let mut buf = [0u8; 3];
+        let file = fd_get(&table, 0).unwrap();
+        assert_eq!(fd_read(&mut file.borrow_mut(), &mut buf, None), Ok(3));
+        let file = fd_get(&table, 3).unwrap();
+        assert_eq!(fd_read(&mut file.borrow_mut(), &mut buf, None), Ok(2));
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(fd_read(&mut file.borrow_mut(), &mut buf, None), Ok(0));
+        assert_eq!(
+            fd_read(&mut file.borrow_mut(), &mut buf, Some(0)),
+            Err(ESPIPE)
+        );
+
+        let file = fd_get(&table, 1).unwrap();
+        assert_eq!(fd_write(&mut file.borrow_mut(), b"out", None), Ok(3));
+        assert_eq!(fd_read(&mut file.borrow_mut(), &mut buf, None), Err(EBADF));
+        assert_eq!(fd_output(&table, 1), Some(b"out".to_vec()));
+        assert_eq!(fd_host(&table, 1, ESPIPE), Err(ESPIPE));
+        assert_eq!(fd_host(&table, 2, ESPIPE), Ok(2));
+    }
+}
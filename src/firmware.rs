@@ -0,0 +1,337 @@
+use std::path::Path;
+
+use crate::{
+    elfdef::{ELFMAG, PROT_EXEC, PROT_READ, PROT_WRITE},
+    mmu::{mmu_copy_in, GUEST_SPACE},
+    round_down, round_up,
+    rvemu::Mmu,
+    vma::{vma_find, vma_map, LINUX_MAP_ANONYMOUS, LINUX_MAP_FIXED_NOREPLACE, LINUX_MAP_PRIVATE},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Elf,
+    // the bytes as they go into memory, at the load address
+    Bin,
+    Ihex,
+    Srec,
+}
+
+// what a hex or srec file holds: runs of bytes by address and the start
+// address record, if any
+pub struct Firmware {
+    pub chunks: Vec<(u64, Vec<u8>)>,
+    pub entry: Option<u64>,
+}
+
+pub fn firmware_format(name: &str) -> Option<Format> {
+    return match name {
+        "elf" => Some(Format::Elf),
+        "bin" | "raw" => Some(Format::Bin),
+        "ihex" | "hex" => Some(Format::Ihex),
+        "srec" => Some(Format::Srec),
+        _ => None,
+    };
+}
+
+// the format of an image by its magic, extension or first record. a raw
+// binary has none of those, it needs a .bin or .img name or --format=bin.
+pub fn firmware_detect(buf: &[u8], name: &str) -> Result<Format, String> {
+    if buf.starts_with(ELFMAG) {
+        return Ok(Format::Elf);
+    }
+    let ext = Path::new(name).extension().and_then(|ext| ext.to_str());
+    match ext.map(|ext| ext.to_ascii_lowercase()).as_deref() {
+        Some("hex" | "ihex" | "ihx") => return Ok(Format::Ihex),
+        Some("srec" | "s19" | "s28" | "s37" | "mot") => return Ok(Format::Srec),
+        Some("bin" | "img") => return Ok(Format::Bin),
+        _ => {}
+    }
+    let text = buf.iter().skip_while(|b| b.is_ascii_whitespace());
+    let head: Vec<u8> = text.take(2).cloned().collect();
+    return match head.as_slice() {
+        [b':', c] if c.is_ascii_hexdigit() => Ok(Format::Ihex),
+        [b'S', c] if c.is_ascii_digit() => Ok(Format::Srec),
+        _ => Err("not an elf file (--format=bin for a raw binary)".to_string()),
+    };
+}
+
+// the bytes of one hex encoded record, its checksum still at the end
+fn firmware_record(line: &str, n: usize) -> Result<Vec<u8>, String> {
+    if !line.len().is_multiple_of(2) || !line.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("line {}: bad record", n));
+    }
+    return Ok((0..line.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&line[i..i + 2], 16).unwrap())
+        .collect());
+}
+
+fn firmware_add(fw: &mut Firmware, addr: u64, data: &[u8]) {
+    if let Some((start, bytes)) = fw.chunks.last_mut() {
+        if *start + bytes.len() as u64 == addr {
+            bytes.extend_from_slice(data);
+            return;
+        }
+    }
+    fw.chunks.push((addr, data.to_vec()));
+}
+
+// intel hex: ":" count, 16-bit address, type, data and a checksum that
+// makes the bytes sum to zero. types 02 and 04 set the upper address bits,
+// 03 and 05 the start address.
+pub fn firmware_parse_ihex(buf: &[u8]) -> Result<Firmware, String> {
+    let mut fw = Firmware {
+        chunks: Vec::new(),
+        entry: None,
+    };
+    let mut base = 0u64;
+    for (i, line) in String::from_utf8_lossy(buf).lines().enumerate() {
+        let n = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let rec = match line.strip_prefix(':') {
+            Some(rec) => firmware_record(rec, n)?,
+            None => return Err(format!("line {}: not an intel hex record", n)),
+        };
+        if rec.len() < 5 || rec.len() != rec[0] as usize + 5 {
+            return Err(format!("line {}: bad record length", n));
+        }
+        if rec.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(format!("line {}: bad checksum", n));
+        }
+        let addr = u16::from_be_bytes([rec[1], rec[2]]) as u64;
+        let data = &rec[4..rec.len() - 1];
+        let word = || data.iter().fold(0u64, |acc, b| acc << 8 | *b as u64);
+        match (rec[3], data.len()) {
+            (0x00, _) => firmware_add(&mut fw, base + addr, data),
+            (0x01, _) => return Ok(fw),
+            (0x02, 2) => base = word() << 4,
+            (0x03, 4) => fw.entry = Some((word() >> 16 << 4) + (word() & 0xffff)),
+            (0x04, 2) => base = word() << 16,
+            (0x05, 4) => fw.entry = Some(word()),
+            _ => return Err(format!("line {}: bad record type {:#x}", n, rec[3])),
+        }
+    }
+    return Err("missing end of file record".to_string());
+}
+
+// motorola s-record: "S", type, count, a 2, 3 or 4 byte address, data
+// and the ones' complement of the byte sum. S1-S3 hold data, S7-S9 the
+// start address, S0 and S5/S6 are a header and a count.
+pub fn firmware_parse_srec(buf: &[u8]) -> Result<Firmware, String> {
+    let mut fw = Firmware {
+        chunks: Vec::new(),
+        entry: None,
+    };
+    for (i, line) in String::from_utf8_lossy(buf).lines().enumerate() {
+        let n = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (kind, rec) = match line.strip_prefix('S') {
+            Some(rest) if !rest.is_empty() => (rest.as_bytes()[0], firmware_record(&rest[1..], n)?),
+            _ => return Err(format!("line {}: not an s-record", n)),
+        };
+        let addr_len = match kind {
+            b'0' | b'1' | b'5' | b'9' => 2,
+            b'2' | b'6' | b'8' => 3,
+            b'3' | b'7' => 4,
+            _ => return Err(format!("line {}: bad record type S{}", n, kind as char)),
+        };
+        if rec.is_empty() || rec.len() != rec[0] as usize + 1 || rec.len() < addr_len + 2 {
+            return Err(format!("line {}: bad record length", n));
+        }
+        if rec.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xff {
+            return Err(format!("line {}: bad checksum", n));
+        }
+        let addr = rec[1..1 + addr_len]
+            .iter()
+            .fold(0u64, |acc, b| acc << 8 | *b as u64);
+        let data = &rec[1 + addr_len..rec.len() - 1];
+        match kind {
+            b'1' | b'2' | b'3' => firmware_add(&mut fw, addr, data),
+            b'7' | b'8' | b'9' => fw.entry = Some(addr),
+            _ => {}
+        }
+    }
+    return Ok(fw);
+}
+
+// the runs of bytes in an image that is not elf. a raw binary goes at
+// load_addr, which hex and srec addresses are moved by.
+pub fn firmware_parse(
+    buf: &[u8],
+    format: Format,
+    load_addr: Option<u64>,
+) -> Result<Firmware, String> {
+    let mut fw = match format {
+        Format::Bin => {
+            let addr = load_addr.ok_or("a raw binary needs a load address".to_string())?;
+            return Ok(Firmware {
+                chunks: vec![(addr, buf.to_vec())],
+                entry: Some(addr),
+            });
+        }
+        Format::Ihex => firmware_parse_ihex(buf)?,
+        Format::Srec => firmware_parse_srec(buf)?,
+        Format::Elf => unreachable!(),
+    };
+    let offset = load_addr.unwrap_or(0);
+    for (addr, _) in fw.chunks.iter_mut() {
+        *addr = addr
+            .checked_add(offset)
+            .ok_or(format!("image at {:#x} does not fit", addr))?;
+    }
+    fw.entry = fw.entry.map(|entry| entry.wrapping_add(offset));
+    return Ok(fw);
+}
+
+// copy the image in, mapping the pages it needs read/write/exec since
+// there is nothing to say otherwise. pages another image already mapped
+// are shared with it. returns the end of the highest page.
+pub fn firmware_load(mmu: &mut Mmu, fw: &Firmware, name: &str) -> Result<u64, String> {
+    let pz = page_size::get() as u64;
+    let mut top = 0;
+    for (addr, data) in fw.chunks.iter().filter(|(_, data)| !data.is_empty()) {
+        let end = addr
+            .checked_add(data.len() as u64)
+            .filter(|&end| end <= GUEST_SPACE)
+            .ok_or(format!("image at {:#x} does not fit", addr))?;
+        let end = round_up!(end, pz);
+
+        let mut at = round_down!(*addr, pz);
+        while at < end {
+            if let Some(vma) = vma_find(mmu, at) {
+                at = vma.end;
+                continue;
+            }
+            let next = mmu
+                .vmas
+                .range(at..end)
+                .next()
+                .map_or(end, |(&start, _)| start);
+            let prot = PROT_READ | PROT_WRITE | PROT_EXEC;
+            let flags = LINUX_MAP_PRIVATE | LINUX_MAP_ANONYMOUS | LINUX_MAP_FIXED_NOREPLACE;
+            vma_map(mmu, at, next - at, prot, flags, -1, 0)
+                .map_err(|_| format!("cannot map image at {:#x}", at))?;
+            let vma = mmu.vmas.get_mut(&at).unwrap();
+            vma.anonymous = false;
+            vma.name = name.to_string();
+            at = next;
+        }
+
+        mmu_copy_in(mmu, *addr, data)
+            .map_err(|_| format!("image at {:#x} overlaps read-only memory", addr))?;
+        top = top.max(end);
+    }
+    return Ok(top);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn firmware_ihex_records() {
+        let hex = b":020000040800F2
+:04001000DEADBEEFB4
+:020014000102E7
+:020000021000EC
+:01000000AA55
+:0400000508000010DF
+:00000001FF
+:01000000AA55
+";
+        let fw = firmware_parse_ihex(hex).unwrap();
+        // a run continues across records, the segment base starts another,
+        // nothing after the end of file record counts
+        assert_eq!(
+            fw.chunks,
+            [
+                (0x08000010, vec![0xde, 0xad, 0xbe, 0xef, 0x01, 0x02]),
+                (0x10000, vec![0xaa]),
+            ]
+        );
+        assert_eq!(fw.entry, Some(0x08000010));
+
+        // cs:ip, 0x1234:0x0008
+        let fw = firmware_parse_ihex(b":0400000312340008AB\n:00000001FF\n").unwrap();
+        assert_eq!(fw.entry, Some(0x12348));
+
+        let bad = b":04001000DEADBEEFB5\n:00000001FF\n";
+        assert_eq!(
+            firmware_parse_ihex(bad).err().unwrap(),
+            "line 1: bad checksum"
+        );
+        let short = b":05001000DEADBEEFB3\n:00000001FF\n";
+        assert_eq!(
+            firmware_parse_ihex(short).err().unwrap(),
+            "line 1: bad record length"
+        );
+        let eof = b":04001000DEADBEEFB4\n";
+        assert_eq!(
+            firmware_parse_ihex(eof).err().unwrap(),
+            "missing end of file record"
+        );
+    }
+
+    #[test]
+    fn firmware_srec_records() {
+        let srec = b"S0060000686472BB
+S10510000102E7
+S205123456035B
+S3078000000004056F
+S306800000020671
+S5030002FA
+S705800000007A
+";
+        let fw = firmware_parse_srec(srec).unwrap();
+        assert_eq!(
+            fw.chunks,
+            [
+                (0x1000, vec![0x01, 0x02]),
+                (0x123456, vec![0x03]),
+                (0x80000000, vec![0x04, 0x05, 0x06]),
+            ]
+        );
+        assert_eq!(fw.entry, Some(0x80000000));
+
+        // moved by the load address
+        let fw = firmware_parse(srec, Format::Srec, Some(0x10000)).unwrap();
+        assert_eq!(fw.chunks[0].0, 0x11000);
+        assert_eq!(fw.entry, Some(0x80010000));
+
+        let bad = b"S10510000102E8\n";
+        assert_eq!(
+            firmware_parse_srec(bad).err().unwrap(),
+            "line 1: bad checksum"
+        );
+        let kind = b"S40510000102E7\n";
+        assert_eq!(
+            firmware_parse_srec(kind).err().unwrap(),
+            "line 1: bad record type S4"
+        );
+    }
+
+    #[test]
+    fn firmware_detect_format() {
+        assert_eq!(firmware_detect(b"\x7fELF\x02", "prog.bin"), Ok(Format::Elf));
+        assert_eq!(
+            firmware_detect(b"\x13\x00\x00\x00", "boot.BIN"),
+            Ok(Format::Bin)
+        );
+        assert_eq!(
+            firmware_detect(b"\x13\x00\x00\x00", "disk.img"),
+            Ok(Format::Bin)
+        );
+        assert_eq!(firmware_detect(b"", "fw.s19"), Ok(Format::Srec));
+        assert_eq!(firmware_detect(b"\n:00000001FF", "fw"), Ok(Format::Ihex));
+        assert_eq!(firmware_detect(b"S10510000102E7", "fw"), Ok(Format::Srec));
+        assert!(firmware_detect(b"\x13\x00\x00\x00", "fw").is_err());
+        assert!(firmware_detect(b"#!/bin/sh", "run.sh").is_err());
+    }
+}
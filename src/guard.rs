@@ -0,0 +1,241 @@
+use std::{
+    any::Any,
+    os::raw::c_void,
+    panic::{self, AssertUnwindSafe},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// guard on both sides of the guest address space, so a base register plus
+// offset that wraps below zero or runs past the end still lands in memory
+// we own
+pub const GUARD_SIZE: u64 = 1 << 32;
+
+const MAX_REGIONS: usize = 64;
+
+// host ranges of every reservation, guards included. the signal handler
+// reads this, so it is a fixed array of atomics rather than a locked Vec
+static REGIONS: [(AtomicU64, AtomicU64); MAX_REGIONS] =
+    [const { (AtomicU64::new(0), AtomicU64::new(0)) }; MAX_REGIONS];
+
+// a host fault on guest memory, caught while running guest code
+#[derive(Debug, Clone, Copy)]
+pub struct HostFault {
+    pub addr: u64,
+    pub rip: u64,
+    pub write: bool,
+}
+
+pub fn guard_register(start: u64, end: u64) {
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    host::install();
+
+    for (lo, hi) in REGIONS.iter() {
+        if lo
+            .compare_exchange(0, start, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            hi.store(end, Ordering::SeqCst);
+            return;
+        }
+    }
+    panic!("too many guest address spaces");
+}
+
+pub fn guard_unregister(start: u64) {
+    for (lo, hi) in REGIONS.iter() {
+        if lo.load(Ordering::SeqCst) == start {
+            hi.store(0, Ordering::SeqCst);
+            lo.store(0, Ordering::SeqCst);
+            return;
+        }
+    }
+}
+
+fn guard_owns(addr: u64) -> bool {
+    return REGIONS.iter().any(|(lo, hi)| {
+        let lo = lo.load(Ordering::Relaxed);
+        lo != 0 && addr >= lo && addr < hi.load(Ordering::Relaxed)
+    });
+}
+
+struct GuardCall<'a> {
+    f: &'a mut dyn FnMut(),
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+// the closure runs under an extern "C" frame, so panics are carried across
+// it by hand
+extern "C" fn guard_shim(arg: *mut c_void) {
+    let call = unsafe { &mut *(arg as *mut GuardCall) };
+    if let Err(e) = panic::catch_unwind(AssertUnwindSafe(|| (call.f)())) {
+        call.panic = Some(e);
+    }
+}
+
+// run f, turning a SIGSEGV/SIGBUS on guest memory into an error. f is left
+// half way through on a fault, so it must not own anything that needs drop
+pub fn guard_call(f: &mut dyn FnMut()) -> Result<(), HostFault> {
+    let mut call = GuardCall { f, panic: None };
+    let arg = &mut call as *mut GuardCall as *mut c_void;
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    let fault = host::call(guard_shim, arg);
+    #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+    let fault: Option<HostFault> = {
+        guard_shim(arg);
+        None
+    };
+
+    if let Some(e) = call.panic {
+        panic::resume_unwind(e);
+    }
+    return match fault {
+        Some(fault) => Err(fault),
+        None => Ok(()),
+    };
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod host {
+    use std::{
+        arch::global_asm,
+        cell::{Cell, UnsafeCell},
+        mem,
+        os::raw::{c_int, c_void},
+        ptr,
+        sync::{Once, OnceLock},
+    };
+
+    use libc::{
+        sigaction, sigemptyset, siginfo_t, ucontext_t, REG_ERR, REG_RIP, REG_RSP, SA_SIGINFO,
+        SIGBUS, SIGSEGV, SIG_DFL, SIG_IGN,
+    };
+
+    use super::{guard_owns, HostFault};
+
+    #[repr(C)]
+    struct GuardFrame {
+        sp: u64,
+        resume: u64,
+    }
+
+    // guard_enter(f, arg, frame) saves the callee-saved registers, records
+    // where to resume in frame and calls f(arg). it returns 0, or 1 when the
+    // signal handler moved the faulting thread to the resume point.
+    global_asm!(
+        ".globl rvemu_guard_enter",
+        "rvemu_guard_enter:",
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "sub rsp, 8",
+        "mov [rdx], rsp",
+        "lea rax, [rip + 3f]",
+        "mov [rdx + 8], rax",
+        "mov rax, rdi",
+        "mov rdi, rsi",
+        "call rax",
+        "xor eax, eax",
+        "2:",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        "3:",
+        "mov eax, 1",
+        "jmp 2b",
+    );
+
+    extern "C" {
+        fn rvemu_guard_enter(
+            f: extern "C" fn(*mut c_void),
+            arg: *mut c_void,
+            frame: *mut GuardFrame,
+        ) -> u32;
+    }
+
+    thread_local! {
+        static FRAME: UnsafeCell<GuardFrame> = const { UnsafeCell::new(GuardFrame { sp: 0, resume: 0 }) };
+        static ACTIVE: Cell<bool> = const { Cell::new(false) };
+        static FAULT: Cell<Option<HostFault>> = const { Cell::new(None) };
+    }
+
+    static INSTALL: Once = Once::new();
+
+    // what SIGSEGV and SIGBUS did before us, std's stack overflow check say
+    static PREVIOUS: OnceLock<[sigaction; 2]> = OnceLock::new();
+
+    // not a guest access: hand it to whoever had the signal before
+    fn chain(sig: c_int, info: *mut siginfo_t, ctx: *mut c_void) {
+        let old = match PREVIOUS.get() {
+            Some(previous) => previous[(sig == SIGBUS) as usize],
+            None => unsafe { mem::zeroed() },
+        };
+        match old.sa_sigaction {
+            // put it back and fault again
+            SIG_DFL | SIG_IGN => unsafe {
+                sigaction(sig, &old, ptr::null_mut());
+            },
+            f if old.sa_flags & SA_SIGINFO != 0 => {
+                let f: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) =
+                    unsafe { mem::transmute(f) };
+                f(sig, info, ctx);
+            }
+            f => {
+                let f: extern "C" fn(c_int) = unsafe { mem::transmute(f) };
+                f(sig);
+            }
+        }
+    }
+
+    extern "C" fn handler(sig: c_int, info: *mut siginfo_t, ctx: *mut c_void) {
+        let addr = unsafe { (*info).si_addr() } as u64;
+        if !ACTIVE.get() || !guard_owns(addr) {
+            chain(sig, info, ctx);
+            return;
+        }
+
+        let gregs = unsafe { &mut (*(ctx as *mut ucontext_t)).uc_mcontext.gregs };
+        FAULT.set(Some(HostFault {
+            addr,
+            rip: gregs[REG_RIP as usize] as u64,
+            write: gregs[REG_ERR as usize] & 0x2 != 0,
+        }));
+        let frame = FRAME.with(|frame| unsafe { &*frame.get() });
+        gregs[REG_RSP as usize] = frame.sp as i64;
+        gregs[REG_RIP as usize] = frame.resume as i64;
+    }
+
+    pub fn install() {
+        INSTALL.call_once(|| {
+            let mut act: sigaction = unsafe { mem::zeroed() };
+            act.sa_sigaction =
+                handler as extern "C" fn(c_int, *mut siginfo_t, *mut c_void) as usize;
+            act.sa_flags = SA_SIGINFO;
+            unsafe { sigemptyset(&mut act.sa_mask) };
+            let mut previous: [sigaction; 2] = unsafe { mem::zeroed() };
+            for (sig, old) in [SIGSEGV, SIGBUS].into_iter().zip(previous.iter_mut()) {
+                unsafe { sigaction(sig, &act, old) };
+            }
+            let _ = PREVIOUS.set(previous);
+        });
+    }
+
+    pub fn call(f: extern "C" fn(*mut c_void), arg: *mut c_void) -> Option<HostFault> {
+        assert!(!ACTIVE.get());
+        ACTIVE.set(true);
+        let faulted = FRAME.with(|frame| unsafe { rvemu_guard_enter(f, arg, frame.get()) });
+        ACTIVE.set(false);
+        if faulted == 0 {
+            return None;
+        }
+        return FAULT.take();
+    }
+}
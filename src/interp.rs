@@ -1,19 +1,83 @@
 use std::mem;
 
 use crate::{
-    decode::insn_decode,
+    decode::{insn_decode, insn_fuse},
     interp_utils::{f32_classify, f64_classify, fsgnj32, fsgnj64, mulh, mulhsu, mulhu},
+    mmu::GUEST_SPACE,
     reg::GpRegTypeT,
-    rvemu::{get_ptr, ExitReason, Insn, State},
+    rvemu::{get_ptr, ExitReason, Fusion, Insn, InsnType, Misaligned, MmuFault, State},
+    stats::Stats,
     to_host,
+    watch::{watch_exec, watch_load, watch_peek, watch_store},
 };
 use rvemu_rs::{
-    p_func1, p_func10, p_func11, p_func12, p_func13, p_func14, p_func2, p_func3, p_func4, p_func5,
-    p_func6, p_func7, p_func8, p_func9,
+    p_func1, p_func10, p_func11, p_func12, p_func13, p_func14, p_func15, p_func2, p_func3, p_func4,
+    p_func5, p_func6, p_func7, p_func8, p_func9,
 };
 
 pub fn func_empty(_state: &mut State, _insn: &mut Insn) {}
 
+// end the block at the insn after the one at state.pc, with [start, end)
+// to be dropped from the cache before the guest goes on
+fn code_stale(state: &mut State, start: u64, end: u64, len: u64) {
+    state.stale = Some((start, end));
+    state.reenter_pc = state.pc + len;
+    state.exit_reason = ExitReason::DirectBranch;
+}
+
+// after a store of size bytes at addr: if it wrote over code the cache
+// holds, the rest of the block may be stale. true when it ended the block.
+pub fn mem_code(state: &mut State, addr: u64, size: u64, len: u64) -> bool {
+    if addr >= state.code_end || addr + size <= state.code_start {
+        return false;
+    }
+    code_stale(state, addr, addr + size, len);
+    return true;
+}
+
+// an address past the guest space could land past the guard pages too, so
+// it faults here rather than on the host. true when it did.
+pub fn mem_bounds(state: &mut State, addr: u64, store: bool) -> bool {
+    if addr < GUEST_SPACE {
+        return false;
+    }
+    state.fault = Some(if store {
+        MmuFault::Store(addr)
+    } else {
+        MmuFault::Load(addr)
+    });
+    state.exit_reason = ExitReason::Fault;
+    return true;
+}
+
+// apply the misaligned policy to a size byte access at addr. true when the
+// access must not happen because it trapped.
+pub fn mem_misaligned(state: &mut State, addr: u64, size: u64, store: bool) -> bool {
+    match state.misaligned {
+        Misaligned::Emulate => return false,
+        Misaligned::Report => {
+            state.misaligned_count += 1;
+            eprintln!(
+                "rvemu-rs: misaligned {}-byte {} at {:#x}, pc {:#x}",
+                size,
+                if store { "store" } else { "load" },
+                addr,
+                state.pc
+            );
+            return false;
+        }
+        Misaligned::Trap => {
+            state.fault = Some(if store {
+                MmuFault::StoreMisaligned(addr)
+            } else {
+                MmuFault::LoadMisaligned(addr)
+            });
+            state.exit_reason = ExitReason::Fault;
+            return true;
+        }
+    }
+}
+
 pub fn func_lb(state: &mut State, insn: &mut Insn) {
     p_func1!(i8);
 }
@@ -42,6 +106,13 @@ pub fn func_lwu(state: &mut State, insn: &mut Insn) {
     p_func1!(u32);
 }
 
+// the guest changed its code, whatever the cache has may be stale
+pub fn func_fence_i(state: &mut State, insn: &mut Insn) {
+    let len = if insn.rvc { 2 } else { 4 };
+    code_stale(state, 0, u64::MAX, len);
+    insn.cont = true;
+}
+
 pub fn func_addi(state: &mut State, insn: &mut Insn) {
     p_func2!(rs1 as i64 + imm);
 }
@@ -330,13 +401,19 @@ pub fn func_csrrci(state: &mut State, insn: &mut Insn) {
 }
 
 pub fn func_flw(state: &mut State, insn: &mut Insn) {
-    let addr = state.gp_regs[insn.rs1 as usize] + insn.imm as u64;
-    state.fp_regs[insn.rd as usize].v = to_host!(addr) | (-1i64 << 32) as u64;
+    p_func15!(u32);
+    let reg = &mut state.fp_regs[insn.rd as usize];
+    reg.w = n;
+    reg.f = f32::from_bits(n);
+    // a single in a double register is NaN-boxed
+    reg.v = n as u64 | (-1i64 << 32) as u64;
 }
 
 pub fn func_fld(state: &mut State, insn: &mut Insn) {
-    let addr = state.gp_regs[insn.rs1 as usize] + insn.imm as u64;
-    state.fp_regs[insn.rd as usize].v = to_host!(addr);
+    p_func15!(u64);
+    let reg = &mut state.fp_regs[insn.rd as usize];
+    reg.v = n;
+    reg.d = f64::from_bits(n);
 }
 
 pub fn func_fsw(state: &mut State, insn: &mut Insn) {
@@ -588,7 +665,7 @@ pub static FUNCS: [fn(&mut State, &mut Insn); 133] = [
     func_lhu,
     func_lwu,
     func_empty,
-    func_empty,
+    func_fence_i,
     func_addi,
     func_slli,
     func_slti,
@@ -715,19 +792,181 @@ pub static FUNCS: [fn(&mut State, &mut Insn); 133] = [
     func_fmv_d_x,
 ];
 
-pub fn exec_block_interp(state: &mut State) {
-    let mut insn: Insn = Insn::new();
-    loop {
-        let ptr = get_ptr(to_host!(state.pc)) as *const u32;
-        insn_decode(&mut insn, *unsafe { ptr.as_ref().unwrap() });
+pub const MAX_BLOCK_INSNS: usize = 128;
 
-        FUNCS.get(insn.i_type as usize).unwrap()(state, &mut insn);
-        state.gp_regs[GpRegTypeT::Zero as usize] = 0;
+#[derive(Clone, Copy)]
+pub struct BlockInsn {
+    pub pc: u64,
+    pub data: u32,
+    pub insn: Insn,
+    // set on the first insn of a fused pair
+    pub fusion: Fusion,
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+pub struct Block {
+    pub insns: Vec<BlockInsn>,
+    pub hot: u64,
+    pub queued: bool,
+}
+
+impl Block {
+    pub fn new() -> Block {
+        Block {
+            insns: Vec::new(),
+            hot: 0,
+            queued: false,
+        }
+    }
+}
+
+impl Default for Block {
+    fn default() -> Block {
+        return Block::new();
+    }
+}
 
+// decode the block at pc, stopping before an insn that would run past end,
+// the end of executable memory. the block is empty if the first one does.
+pub fn fetch_block(host_base: u64, pc: u64, end: u64) -> Block {
+    let mut block = Block::new();
+    let mut pc = pc;
+    while block.insns.len() < MAX_BLOCK_INSNS && pc + 2 <= end {
+        let ptr = get_ptr(to_host!(host_base, pc));
+        let data = if pc + 4 <= end {
+            unsafe { (ptr as *const u32).read_unaligned() }
+        } else {
+            unsafe { (ptr as *const u16).read_unaligned() as u32 }
+        };
+        if data & 0x3 == 0x3 && pc + 4 > end {
+            break;
+        }
+        let mut insn = Insn::new();
+        insn_decode(&mut insn, data);
+        block.insns.push(BlockInsn {
+            pc,
+            data,
+            insn,
+            fusion: Fusion::None,
+            taken: 0,
+            not_taken: 0,
+        });
         if insn.cont {
             break;
         }
+        pc += if insn.rvc { 2 } else { 4 };
+    }
 
-        state.pc += if insn.rvc { 2 } else { 4 };
+    let mut i = 0;
+    while i + 1 < block.insns.len() {
+        let fusion = insn_fuse(&block.insns[i].insn, &block.insns[i + 1].insn);
+        block.insns[i].fusion = fusion;
+        i += if fusion == Fusion::None { 1 } else { 2 };
     }
+    return block;
+}
+
+pub fn insn_is_branch(typ: InsnType) -> bool {
+    matches!(
+        typ,
+        InsnType::InsnBeq
+            | InsnType::InsnBne
+            | InsnType::InsnBlt
+            | InsnType::InsnBge
+            | InsnType::InsnBltu
+            | InsnType::InsnBgeu
+    )
+}
+
+fn exec_fused(state: &mut State, first: &BlockInsn, second: &BlockInsn) {
+    let (a, b) = (&first.insn, &second.insn);
+    match first.fusion {
+        Fusion::LuiAddi => {
+            state.gp_regs[b.rd as usize] = (a.imm as i64).wrapping_add(b.imm as i64) as u64;
+        }
+        Fusion::AuipcJalr => {
+            let base = first.pc.wrapping_add(a.imm as u64);
+            state.gp_regs[a.rd as usize] = base;
+            state.gp_regs[b.rd as usize] = second.pc + if b.rvc { 2 } else { 4 };
+            state.pc = second.pc;
+            state.reenter_pc = base.wrapping_add(b.imm as u64) & !1u64;
+            state.exit_reason = ExitReason::IndirectBranch;
+        }
+        Fusion::AuipcLd => {
+            let base = first.pc.wrapping_add(a.imm as u64);
+            state.gp_regs[a.rd as usize] = base;
+            // the ld is the insn that faults
+            state.pc = second.pc;
+            let addr = base.wrapping_add(b.imm as u64);
+            if mem_bounds(state, addr, false) {
+                return;
+            }
+            if addr & 0x7 != 0 && mem_misaligned(state, addr, 8, false) {
+                return;
+            }
+            let ptr = get_ptr(to_host!(state.host_base, addr)) as *const i64;
+            state.gp_regs[b.rd as usize] = unsafe { ptr.read_unaligned() } as u64;
+        }
+        Fusion::SlliSrli => {
+            state.gp_regs[b.rd as usize] = state.gp_regs[a.rs1 as usize] & 0xffff_ffff;
+        }
+        Fusion::MulhMul => {
+            let rs1 = state.gp_regs[a.rs1 as usize];
+            let rs2 = state.gp_regs[a.rs2 as usize];
+            state.gp_regs[a.rd as usize] = mulh(rs1 as i64, rs2 as i64) as u64;
+            state.gp_regs[b.rd as usize] = rs1.wrapping_mul(rs2);
+        }
+        _ => unreachable!(),
+    }
+    state.gp_regs[GpRegTypeT::Zero as usize] = 0;
+}
+
+pub fn exec_block_interp(state: &mut State, block: &mut Block, stats: &mut Stats) {
+    let mut i = 0;
+    while i < block.insns.len() {
+        let fusion = block.insns[i].fusion;
+        // fused pairs skip the watchpoint checks
+        if fusion != Fusion::None && !state.watching {
+            exec_fused(state, &block.insns[i], &block.insns[i + 1]);
+            stats.fused[fusion as usize] += 1;
+            if block.insns[i + 1].insn.cont || state.exit_reason == ExitReason::Fault {
+                return;
+            }
+            i += 2;
+            continue;
+        }
+
+        let entry = &mut block.insns[i];
+        let mut insn = entry.insn;
+        state.pc = entry.pc;
+        let len = if insn.rvc { 2 } else { 4 };
+        if state.watching && watch_exec(state, entry.pc, entry.data, len) {
+            return;
+        }
+
+        FUNCS.get(insn.i_type as usize).unwrap()(state, &mut insn);
+        state.gp_regs[GpRegTypeT::Zero as usize] = 0;
+
+        // a taken branch is the only way p_func5 handlers end the block
+        if insn_is_branch(insn.i_type) {
+            if insn.cont {
+                entry.taken += 1;
+            } else {
+                entry.not_taken += 1;
+            }
+        }
+
+        if insn.cont {
+            return;
+        }
+        i += 1;
+    }
+
+    // the block was cut at MAX_BLOCK_INSNS or the end of executable memory,
+    // carry on with the next one
+    let last = block.insns.last().unwrap();
+    state.pc = last.pc + if last.insn.rvc { 2 } else { 4 };
+    state.reenter_pc = state.pc;
+    state.exit_reason = ExitReason::DirectBranch;
 }
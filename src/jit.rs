@@ -0,0 +1,686 @@
+use std::mem::{self, offset_of};
+
+use crate::{
+    decode::insn_decode,
+    interp::{BlockInsn, FUNCS},
+    mmu::GUEST_SPACE,
+    reg::GpRegTypeT,
+    rvemu::{ExitReason, Insn, InsnType, Misaligned, State},
+    trace::{Trace, TraceEnd, TraceInsn},
+};
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RSP: u8 = 4;
+const RBP: u8 = 5;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R12: u8 = 12;
+const R13: u8 = 13;
+const R14: u8 = 14;
+const R15: u8 = 15;
+
+// callee-saved host registers guest registers may live in within a block.
+// rbx always holds the State pointer.
+const HOST_REGS: [u8; 5] = [RBP, R12, R13, R14, R15];
+
+const ALU_ADD: u8 = 0x01;
+const ALU_OR: u8 = 0x09;
+const ALU_AND: u8 = 0x21;
+const ALU_SUB: u8 = 0x29;
+const ALU_XOR: u8 = 0x31;
+const ALU_CMP: u8 = 0x39;
+
+const EXT_ADD: u8 = 0;
+const EXT_OR: u8 = 1;
+const EXT_AND: u8 = 4;
+const EXT_SUB: u8 = 5;
+const EXT_XOR: u8 = 6;
+const EXT_CMP: u8 = 7;
+
+const SHIFT_SHL: u8 = 4;
+const SHIFT_SHR: u8 = 5;
+const SHIFT_SAR: u8 = 7;
+
+const CC_B: u8 = 0x2;
+const CC_AE: u8 = 0x3;
+const CC_E: u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_BE: u8 = 0x6;
+const CC_L: u8 = 0xc;
+
+pub type ExecBlockFunc = unsafe extern "C" fn(*mut State);
+
+pub struct Emitter {
+    pub code: Vec<u8>,
+    pub map: [Option<u8>; GpRegTypeT::NumGpRegS as usize],
+    pub host_base: u64,
+    // code offset of every native guest load/store and the guest pc it
+    // belongs to, so a host fault there can be traced back
+    pub pcs: Vec<(usize, u64)>,
+}
+
+impl Emitter {
+    pub fn new(host_base: u64) -> Emitter {
+        Emitter {
+            code: Vec::new(),
+            map: [None; GpRegTypeT::NumGpRegS as usize],
+            host_base,
+            pcs: Vec::new(),
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_u32(&mut self, data: u32) {
+        self.code.extend_from_slice(&data.to_le_bytes());
+    }
+
+    fn emit_u64(&mut self, data: u64) {
+        self.code.extend_from_slice(&data.to_le_bytes());
+    }
+
+    fn rex(&mut self, w: bool, reg: u8, rm: u8) {
+        self.emit(&[0x40 | ((w as u8) << 3) | ((reg >> 3) << 2) | (rm >> 3)]);
+    }
+
+    fn modrm(&mut self, md: u8, reg: u8, rm: u8) {
+        self.emit(&[(md << 6) | ((reg & 7) << 3) | (rm & 7)]);
+    }
+
+    fn push(&mut self, reg: u8) {
+        if reg >= 8 {
+            self.emit(&[0x41]);
+        }
+        self.emit(&[0x50 + (reg & 7)]);
+    }
+
+    fn pop(&mut self, reg: u8) {
+        if reg >= 8 {
+            self.emit(&[0x41]);
+        }
+        self.emit(&[0x58 + (reg & 7)]);
+    }
+
+    fn mov_rr(&mut self, dst: u8, src: u8) {
+        self.rex(true, src, dst);
+        self.emit(&[0x89]);
+        self.modrm(3, src, dst);
+    }
+
+    fn mov_ri(&mut self, dst: u8, imm: u64) {
+        if imm as i64 == (imm as i32) as i64 {
+            self.rex(true, 0, dst);
+            self.emit(&[0xc7]);
+            self.modrm(3, 0, dst);
+            self.emit_u32(imm as u32);
+        } else {
+            self.rex(true, 0, dst);
+            self.emit(&[0xb8 + (dst & 7)]);
+            self.emit_u64(imm);
+        }
+    }
+
+    fn mov_ri32(&mut self, dst: u8, imm: u32) {
+        if dst >= 8 {
+            self.emit(&[0x41]);
+        }
+        self.emit(&[0xb8 + (dst & 7)]);
+        self.emit_u32(imm);
+    }
+
+    fn alu_rr(&mut self, op: u8, dst: u8, src: u8) {
+        self.rex(true, src, dst);
+        self.emit(&[op]);
+        self.modrm(3, src, dst);
+    }
+
+    fn alu_ri(&mut self, ext: u8, dst: u8, imm: i32) {
+        self.rex(true, 0, dst);
+        self.emit(&[0x81]);
+        self.modrm(3, ext, dst);
+        self.emit_u32(imm as u32);
+    }
+
+    fn shift_ri(&mut self, ext: u8, dst: u8, imm: u8) {
+        self.rex(true, 0, dst);
+        self.emit(&[0xc1]);
+        self.modrm(3, ext, dst);
+        self.emit(&[imm]);
+    }
+
+    fn shift_rcl(&mut self, ext: u8, dst: u8, wide: bool) {
+        self.rex(wide, 0, dst);
+        self.emit(&[0xd3]);
+        self.modrm(3, ext, dst);
+    }
+
+    fn imul_rr(&mut self, dst: u8, src: u8) {
+        self.rex(true, dst, src);
+        self.emit(&[0x0f, 0xaf]);
+        self.modrm(3, dst, src);
+    }
+
+    fn movsxd_rr(&mut self, dst: u8, src: u8) {
+        self.rex(true, dst, src);
+        self.emit(&[0x63]);
+        self.modrm(3, dst, src);
+    }
+
+    fn setcc_rax(&mut self, cc: u8) {
+        self.emit(&[0x0f, 0x90 | cc, 0xc0]);
+        self.emit(&[0x0f, 0xb6, 0xc0]);
+    }
+
+    fn load_state(&mut self, dst: u8, off: usize) {
+        self.rex(true, dst, RBX);
+        self.emit(&[0x8b]);
+        self.modrm(2, dst, RBX);
+        self.emit_u32(off as u32);
+    }
+
+    fn store_state(&mut self, off: usize, src: u8) {
+        self.rex(true, src, RBX);
+        self.emit(&[0x89]);
+        self.modrm(2, src, RBX);
+        self.emit_u32(off as u32);
+    }
+
+    fn store_state_imm(&mut self, off: usize, imm: u64) {
+        self.mov_ri(RAX, imm);
+        self.store_state(off, RAX);
+    }
+
+    fn store_state_u8(&mut self, off: usize, imm: u8) {
+        self.emit(&[0xc6]);
+        self.modrm(2, 0, RBX);
+        self.emit_u32(off as u32);
+        self.emit(&[imm]);
+    }
+
+    fn cmp_state_u8(&mut self, off: usize, imm: u8) {
+        self.emit(&[0x80]);
+        self.modrm(2, EXT_CMP, RBX);
+        self.emit_u32(off as u32);
+        self.emit(&[imm]);
+    }
+
+    fn jcc(&mut self, cc: u8) -> usize {
+        self.emit(&[0x0f, 0x80 | cc]);
+        self.emit_u32(0);
+        return self.code.len() - 4;
+    }
+
+    fn jmp_back(&mut self, target: usize) {
+        let rel = target as i64 - (self.code.len() + 5) as i64;
+        self.emit(&[0xe9]);
+        self.emit_u32(rel as i32 as u32);
+    }
+
+    fn patch(&mut self, pos: usize) {
+        let rel = (self.code.len() - (pos + 4)) as u32;
+        self.code[pos..pos + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    fn call_abs(&mut self, addr: u64) {
+        self.mov_ri(RAX, addr);
+        self.emit(&[0xff, 0xd0]);
+    }
+
+    fn load_mem(&mut self, typ: InsnType) {
+        // rax = *(rax)
+        match typ {
+            InsnType::InsnLb => self.emit(&[0x48, 0x0f, 0xbe, 0x00]),
+            InsnType::InsnLh => self.emit(&[0x48, 0x0f, 0xbf, 0x00]),
+            InsnType::InsnLw => self.emit(&[0x48, 0x63, 0x00]),
+            InsnType::InsnLd => self.emit(&[0x48, 0x8b, 0x00]),
+            InsnType::InsnLbu => self.emit(&[0x0f, 0xb6, 0x00]),
+            InsnType::InsnLhu => self.emit(&[0x0f, 0xb7, 0x00]),
+            InsnType::InsnLwu => self.emit(&[0x8b, 0x00]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn store_mem(&mut self, typ: InsnType) {
+        // *(rax) = rcx
+        match typ {
+            InsnType::InsnSb => self.emit(&[0x88, 0x08]),
+            InsnType::InsnSh => self.emit(&[0x66, 0x89, 0x08]),
+            InsnType::InsnSw => self.emit(&[0x89, 0x08]),
+            InsnType::InsnSd => self.emit(&[0x48, 0x89, 0x08]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn load_guest(&mut self, dst: u8, reg: i8) {
+        if reg == GpRegTypeT::Zero as i8 {
+            self.alu_rr(ALU_XOR, dst, dst);
+        } else if let Some(host) = self.map[reg as usize] {
+            self.mov_rr(dst, host);
+        } else {
+            self.load_state(dst, gp_reg_offset(reg));
+        }
+    }
+
+    fn store_guest(&mut self, reg: i8, src: u8) {
+        if reg == GpRegTypeT::Zero as i8 {
+            return;
+        }
+        if let Some(host) = self.map[reg as usize] {
+            self.mov_rr(host, src);
+        } else {
+            self.store_state(gp_reg_offset(reg), src);
+        }
+    }
+
+    fn load_mapped(&mut self) {
+        for reg in 0..self.map.len() {
+            if let Some(host) = self.map[reg] {
+                self.load_state(host, gp_reg_offset(reg as i8));
+            }
+        }
+    }
+
+    fn store_mapped(&mut self) {
+        for reg in 0..self.map.len() {
+            if let Some(host) = self.map[reg] {
+                self.store_state(gp_reg_offset(reg as i8), host);
+            }
+        }
+    }
+
+    fn prologue(&mut self) {
+        self.push(RBX);
+        self.push(RBP);
+        self.push(R12);
+        self.push(R13);
+        self.push(R14);
+        self.push(R15);
+        // keep rsp 16-byte aligned for calls into the runtime
+        self.alu_ri(EXT_SUB, RSP, 8);
+        self.mov_rr(RBX, RDI);
+        self.load_mapped();
+    }
+
+    fn epilogue(&mut self) {
+        self.alu_ri(EXT_ADD, RSP, 8);
+        self.pop(R15);
+        self.pop(R14);
+        self.pop(R13);
+        self.pop(R12);
+        self.pop(RBP);
+        self.pop(RBX);
+        self.emit(&[0xc3]);
+    }
+
+    // leave the block with the same state the interpreter would leave behind.
+    // reenter_pc is either an immediate or already sitting in rdx.
+    fn exit(&mut self, reason: ExitReason, pc: u64, reenter_pc: Option<u64>) {
+        self.store_mapped();
+        match reenter_pc {
+            Some(target) => self.store_state_imm(offset_of!(State, reenter_pc), target),
+            None => self.store_state(offset_of!(State, reenter_pc), RDX),
+        }
+        self.store_state_imm(offset_of!(State, pc), pc);
+        self.store_state_u8(offset_of!(State, exit_reason), reason as u8);
+        self.epilogue();
+    }
+
+    // the host address in RAX. one past the guest space would miss the
+    // guard pages, so it goes to the interpreter handler, which faults.
+    // so does a store of size bytes over code the cache holds, which ends
+    // the trace. the handler does the whole access, so those paths never
+    // fall through to the native one after this.
+    fn effective_addr(&mut self, insn: &Insn, pc: u64, data: u32, store: Option<u64>) {
+        let next_pc = pc + if insn.rvc { 2 } else { 4 };
+        self.load_guest(RAX, insn.rs1);
+        self.alu_ri(EXT_ADD, RAX, insn.imm);
+        self.mov_ri(RCX, GUEST_SPACE);
+        self.alu_rr(ALU_CMP, RAX, RCX);
+        let inside = self.jcc(CC_B);
+        self.fallback_exit(pc, data, next_pc);
+        self.patch(inside);
+        if let Some(size) = store {
+            self.load_state(RCX, offset_of!(State, code_end));
+            self.alu_rr(ALU_CMP, RAX, RCX);
+            let above = self.jcc(CC_AE);
+            self.mov_rr(RCX, RAX);
+            self.alu_ri(EXT_ADD, RCX, size as i32);
+            self.load_state(RDX, offset_of!(State, code_start));
+            self.alu_rr(ALU_CMP, RCX, RDX);
+            let below = self.jcc(CC_BE);
+            self.fallback_exit(pc, data, next_pc);
+            self.patch(above);
+            self.patch(below);
+        }
+        self.mov_ri(RCX, self.host_base);
+        self.alu_rr(ALU_ADD, RAX, RCX);
+    }
+
+    // run a single instruction through its interpreter handler
+    fn fallback(&mut self, pc: u64, data: u32) {
+        self.store_mapped();
+        self.store_state_imm(offset_of!(State, pc), pc);
+        self.mov_rr(RDI, RBX);
+        self.mov_ri32(RSI, data);
+        self.call_abs(jit_exec_insn as extern "C" fn(&mut State, u32) as usize as u64);
+        self.cmp_state_u8(offset_of!(State, exit_reason), ExitReason::None as u8);
+        let cont = self.jcc(CC_E);
+        self.epilogue();
+        self.patch(cont);
+        self.load_mapped();
+    }
+
+    // run a single instruction through its interpreter handler and leave
+    // the block after it. the handler already wrote the guest registers, so
+    // they are not stored from the host ones again.
+    fn fallback_exit(&mut self, pc: u64, data: u32, next_pc: u64) {
+        self.store_mapped();
+        self.store_state_imm(offset_of!(State, pc), pc);
+        self.mov_rr(RDI, RBX);
+        self.mov_ri32(RSI, data);
+        self.call_abs(jit_exec_insn as extern "C" fn(&mut State, u32) as usize as u64);
+        self.cmp_state_u8(offset_of!(State, exit_reason), ExitReason::None as u8);
+        let stopped = self.jcc(CC_NE);
+        self.store_state_imm(offset_of!(State, reenter_pc), next_pc);
+        self.store_state_u8(
+            offset_of!(State, exit_reason),
+            ExitReason::DirectBranch as u8,
+        );
+        self.patch(stopped);
+        self.epilogue();
+    }
+
+    // run a branch the trace follows through its interpreter handler: a
+    // taken branch stays in the trace, otherwise leave at the next insn
+    fn fallback_follow(&mut self, pc: u64, data: u32, next_pc: u64) {
+        self.store_mapped();
+        self.store_state_imm(offset_of!(State, pc), pc);
+        self.mov_rr(RDI, RBX);
+        self.mov_ri32(RSI, data);
+        self.call_abs(jit_exec_insn as extern "C" fn(&mut State, u32) as usize as u64);
+        self.cmp_state_u8(offset_of!(State, exit_reason), ExitReason::None as u8);
+        let taken = self.jcc(CC_NE);
+        self.exit(ExitReason::DirectBranch, next_pc, Some(next_pc));
+        self.patch(taken);
+        self.store_state_u8(offset_of!(State, exit_reason), ExitReason::None as u8);
+        self.load_mapped();
+    }
+}
+
+fn gp_reg_offset(reg: i8) -> usize {
+    return offset_of!(State, gp_regs) + (reg as usize) * mem::size_of::<u64>();
+}
+
+extern "C" fn jit_exec_insn(state: &mut State, data: u32) {
+    let mut insn = Insn::new();
+    insn_decode(&mut insn, data);
+    FUNCS.get(insn.i_type as usize).unwrap()(state, &mut insn);
+    state.gp_regs[GpRegTypeT::Zero as usize] = 0;
+}
+
+fn is_native(typ: InsnType) -> bool {
+    matches!(
+        typ,
+        InsnType::InsnLb
+            | InsnType::InsnLh
+            | InsnType::InsnLw
+            | InsnType::InsnLd
+            | InsnType::InsnLbu
+            | InsnType::InsnLhu
+            | InsnType::InsnLwu
+            | InsnType::InsnFence
+            | InsnType::InsnAddi
+            | InsnType::InsnSlli
+            | InsnType::InsnSlti
+            | InsnType::InsnXori
+            | InsnType::InsnSrli
+            | InsnType::InsnOri
+            | InsnType::InsnAndi
+            | InsnType::InsnAuipc
+            | InsnType::InsnSb
+            | InsnType::InsnSh
+            | InsnType::InsnSw
+            | InsnType::InsnSd
+            | InsnType::InsnAdd
+            | InsnType::InsnSll
+            | InsnType::InsnSlt
+            | InsnType::InsnSltu
+            | InsnType::InsnXor
+            | InsnType::InsnSrl
+            | InsnType::InsnOr
+            | InsnType::InsnAnd
+            | InsnType::InsnMul
+            | InsnType::InsnSub
+            | InsnType::InsnSra
+            | InsnType::InsnLui
+            | InsnType::InsnSraw
+            | InsnType::InsnBeq
+            | InsnType::InsnBne
+            | InsnType::InsnBltu
+            | InsnType::InsnBgeu
+            | InsnType::InsnJalr
+            | InsnType::InsnJal
+            | InsnType::InsnEcall
+    )
+}
+
+// give the most used guest registers of the trace a host register
+fn alloc_regs(e: &mut Emitter, trace: &Trace) {
+    let mut uses = [0usize; GpRegTypeT::NumGpRegS as usize];
+    for TraceInsn { entry, .. } in trace.insns.iter() {
+        let insn = &entry.insn;
+        if !is_native(insn.i_type) {
+            continue;
+        }
+        for reg in [insn.rd, insn.rs1, insn.rs2] {
+            uses[reg as usize] += 1;
+        }
+    }
+    uses[GpRegTypeT::Zero as usize] = 0;
+
+    let mut regs: Vec<usize> = (0..uses.len()).filter(|r| uses[*r] > 1).collect();
+    regs.sort_by(|a, b| uses[*b].cmp(&uses[*a]));
+    for (reg, host) in regs.iter().zip(HOST_REGS.iter()) {
+        e.map[*reg] = Some(*host);
+    }
+}
+
+fn branch_cc(typ: InsnType) -> u8 {
+    return match typ {
+        InsnType::InsnBeq => CC_E,
+        InsnType::InsnBne => CC_NE,
+        InsnType::InsnBltu => CC_B,
+        _ => CC_AE,
+    };
+}
+
+// loads and stores only run natively when misaligned accesses need no
+// checking, the interpreter handlers apply the other policies
+pub fn jit_compile_trace(
+    trace: &Trace,
+    host_base: u64,
+    misaligned: Misaligned,
+) -> (Vec<u8>, Vec<(usize, u64)>) {
+    let native_mem = misaligned == Misaligned::Emulate;
+    let mut e = Emitter::new(host_base);
+    alloc_regs(&mut e, trace);
+    e.prologue();
+    let head = e.code.len();
+
+    for &TraceInsn { entry, follow } in trace.insns.iter() {
+        let BlockInsn { pc, data, insn, .. } = entry;
+        let next_pc = pc + if insn.rvc { 2 } else { 4 };
+
+        match insn.i_type {
+            InsnType::InsnFence => {}
+            InsnType::InsnLui => {
+                e.mov_ri(RAX, insn.imm as u64);
+                e.store_guest(insn.rd, RAX);
+            }
+            InsnType::InsnAuipc => {
+                e.mov_ri(RAX, pc.wrapping_add(insn.imm as u64));
+                e.store_guest(insn.rd, RAX);
+            }
+            InsnType::InsnAddi | InsnType::InsnXori | InsnType::InsnOri | InsnType::InsnAndi => {
+                let ext = match insn.i_type {
+                    InsnType::InsnAddi => EXT_ADD,
+                    InsnType::InsnXori => EXT_XOR,
+                    InsnType::InsnOri => EXT_OR,
+                    _ => EXT_AND,
+                };
+                e.load_guest(RAX, insn.rs1);
+                e.alu_ri(ext, RAX, insn.imm);
+                e.store_guest(insn.rd, RAX);
+            }
+            InsnType::InsnSlti => {
+                e.load_guest(RCX, insn.rs1);
+                e.alu_ri(EXT_CMP, RCX, insn.imm);
+                e.setcc_rax(CC_L);
+                e.store_guest(insn.rd, RAX);
+            }
+            InsnType::InsnSlli | InsnType::InsnSrli => {
+                let ext = if let InsnType::InsnSlli = insn.i_type {
+                    SHIFT_SHL
+                } else {
+                    SHIFT_SHR
+                };
+                e.load_guest(RAX, insn.rs1);
+                e.shift_ri(ext, RAX, (insn.imm & 0x3f) as u8);
+                e.store_guest(insn.rd, RAX);
+            }
+            InsnType::InsnAdd
+            | InsnType::InsnSub
+            | InsnType::InsnXor
+            | InsnType::InsnOr
+            | InsnType::InsnAnd => {
+                let op = match insn.i_type {
+                    InsnType::InsnAdd => ALU_ADD,
+                    InsnType::InsnSub => ALU_SUB,
+                    InsnType::InsnXor => ALU_XOR,
+                    InsnType::InsnOr => ALU_OR,
+                    _ => ALU_AND,
+                };
+                e.load_guest(RAX, insn.rs1);
+                e.load_guest(RCX, insn.rs2);
+                e.alu_rr(op, RAX, RCX);
+                e.store_guest(insn.rd, RAX);
+            }
+            InsnType::InsnSll | InsnType::InsnSrl | InsnType::InsnSra | InsnType::InsnSraw => {
+                let ext = match insn.i_type {
+                    InsnType::InsnSll => SHIFT_SHL,
+                    InsnType::InsnSrl => SHIFT_SHR,
+                    _ => SHIFT_SAR,
+                };
+                let wide = !matches!(insn.i_type, InsnType::InsnSraw);
+                e.load_guest(RAX, insn.rs1);
+                e.load_guest(RCX, insn.rs2);
+                e.shift_rcl(ext, RAX, wide);
+                if !wide {
+                    e.movsxd_rr(RAX, RAX);
+                }
+                e.store_guest(insn.rd, RAX);
+            }
+            InsnType::InsnSlt | InsnType::InsnSltu => {
+                let cc = if let InsnType::InsnSlt = insn.i_type {
+                    CC_L
+                } else {
+                    CC_B
+                };
+                e.load_guest(RCX, insn.rs1);
+                e.load_guest(RDX, insn.rs2);
+                e.alu_rr(ALU_CMP, RCX, RDX);
+                e.setcc_rax(cc);
+                e.store_guest(insn.rd, RAX);
+            }
+            InsnType::InsnMul => {
+                e.load_guest(RAX, insn.rs1);
+                e.load_guest(RCX, insn.rs2);
+                e.imul_rr(RAX, RCX);
+                e.store_guest(insn.rd, RAX);
+            }
+            InsnType::InsnLb
+            | InsnType::InsnLh
+            | InsnType::InsnLw
+            | InsnType::InsnLd
+            | InsnType::InsnLbu
+            | InsnType::InsnLhu
+            | InsnType::InsnLwu
+                if native_mem =>
+            {
+                e.effective_addr(&insn, pc, data, None);
+                e.pcs.push((e.code.len(), pc));
+                e.load_mem(insn.i_type);
+                e.store_guest(insn.rd, RAX);
+            }
+            InsnType::InsnSb | InsnType::InsnSh | InsnType::InsnSw | InsnType::InsnSd
+                if native_mem =>
+            {
+                let size = match insn.i_type {
+                    InsnType::InsnSb => 1,
+                    InsnType::InsnSh => 2,
+                    InsnType::InsnSw => 4,
+                    _ => 8,
+                };
+                e.effective_addr(&insn, pc, data, Some(size));
+                e.load_guest(RCX, insn.rs2);
+                e.pcs.push((e.code.len(), pc));
+                e.store_mem(insn.i_type);
+            }
+            InsnType::InsnBeq | InsnType::InsnBne | InsnType::InsnBltu | InsnType::InsnBgeu => {
+                // jump over the side exit when the trace stays on the hot path
+                let cc = branch_cc(insn.i_type);
+                let target = pc.wrapping_add(insn.imm as u64);
+                e.load_guest(RAX, insn.rs1);
+                e.load_guest(RCX, insn.rs2);
+                e.alu_rr(ALU_CMP, RAX, RCX);
+                if follow {
+                    let stay = e.jcc(cc);
+                    e.exit(ExitReason::DirectBranch, next_pc, Some(next_pc));
+                    e.patch(stay);
+                } else {
+                    let stay = e.jcc(cc ^ 1);
+                    e.exit(ExitReason::DirectBranch, target, Some(target));
+                    e.patch(stay);
+                }
+            }
+            InsnType::InsnJal => {
+                let target = pc.wrapping_add(insn.imm as u64);
+                e.mov_ri(RAX, next_pc);
+                e.store_guest(insn.rd, RAX);
+                if !follow {
+                    e.exit(ExitReason::IndirectBranch, target, Some(target));
+                }
+            }
+            InsnType::InsnJalr => {
+                e.load_guest(RDX, insn.rs1);
+                e.alu_ri(EXT_ADD, RDX, insn.imm);
+                e.alu_ri(EXT_AND, RDX, !1);
+                e.mov_ri(RAX, next_pc);
+                e.store_guest(insn.rd, RAX);
+                e.exit(ExitReason::IndirectBranch, pc, None);
+            }
+            InsnType::InsnEcall => {
+                e.exit(ExitReason::Ecall, pc, Some(pc + 4));
+            }
+            _ if follow => e.fallback_follow(pc, data, next_pc),
+            _ => e.fallback(pc, data),
+        }
+    }
+
+    match trace.end {
+        TraceEnd::Terminator => {}
+        TraceEnd::Exit(pc) => e.exit(ExitReason::DirectBranch, pc, Some(pc)),
+        TraceEnd::Loop => e.jmp_back(head),
+    }
+    return (e.code, e.pcs);
+}
+
+pub fn exec_block_jit(code: *const u8, state: &mut State) {
+    let f = unsafe { mem::transmute::<*const u8, ExecBlockFunc>(code) };
+    unsafe { f(state as *mut State) };
+}
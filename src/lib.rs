@@ -10,9 +10,22 @@ pub fn p_func1(typ: TokenStream) -> TokenStream {
     let ty: syn::Type = syn::parse(typ).unwrap();
     let tt = quote! {
         let addr: u64 = ((state.gp_regs[insn.rs1 as usize] as i64) + (insn.imm as i64)) as u64;
-        let ptr: *mut #ty = get_ptr(to_host!(addr)) as *mut #ty;
-        let n: #ty = unsafe{ (*(ptr.as_ref().unwrap()))};
+        let size = mem::size_of::<#ty>() as u64;
+        if mem_bounds(state, addr, false) {
+            insn.cont = true;
+            return;
+        }
+        if addr & (size - 1) != 0 && mem_misaligned(state, addr, size, false) {
+            insn.cont = true;
+            return;
+        }
+        let ptr: *mut #ty = get_ptr(to_host!(state.host_base, addr)) as *mut #ty;
+        let n: #ty = unsafe{ ptr.read_unaligned() };
         state.gp_regs[insn.rd as usize] = n as u64;
+        let len = if insn.rvc { 2 } else { 4 };
+        if state.watching && watch_load(state, addr, size, n as u64, len) {
+            insn.cont = true;
+        }
     };
 
     tt.into()
@@ -36,11 +49,29 @@ pub fn p_func3(typ: TokenStream) -> TokenStream {
     let tt = quote! {
         let rs1 = state.gp_regs[insn.rs1 as usize];
         let rs2 = state.gp_regs[insn.rs2 as usize];
-        let ptr = get_ptr(to_host!(((rs1 as i64) + (insn.imm as i64)) as u64));
+        let addr = ((rs1 as i64) + (insn.imm as i64)) as u64;
+        let size = mem::size_of::<#ty>() as u64;
+        if mem_bounds(state, addr, true) {
+            insn.cont = true;
+            return;
+        }
+        if addr & (size - 1) != 0 && mem_misaligned(state, addr, size, true) {
+            insn.cont = true;
+            return;
+        }
+        let ptr = get_ptr(to_host!(state.host_base, addr));
+        let old = if state.watching { watch_peek(state, addr, size) } else { 0 };
 
         let d_p = (state.gp_regs).as_ptr() as *const u8;
         let d_p = unsafe{ d_p.add((insn.rs2 as usize) * 8)};
         unsafe {ptr.copy_from(d_p, mem::size_of::<#ty>())};
+        let len = if insn.rvc { 2 } else { 4 };
+        if mem_code(state, addr, size, len) {
+            insn.cont = true;
+        }
+        if state.watching && watch_store(state, addr, size, old, rs2, len) {
+            insn.cont = true;
+        }
     };
 
     tt.into()
@@ -196,6 +227,33 @@ pub fn p_func14(expr: TokenStream) -> TokenStream {
     tt.into()
 }
 
+// p_func1 for the fp loads: the value is left in n for the caller to put
+// in the fp register
+#[proc_macro]
+pub fn p_func15(typ: TokenStream) -> TokenStream {
+    let ty: syn::Type = syn::parse(typ).unwrap();
+    let tt = quote! {
+        let addr: u64 = ((state.gp_regs[insn.rs1 as usize] as i64) + (insn.imm as i64)) as u64;
+        let size = mem::size_of::<#ty>() as u64;
+        if mem_bounds(state, addr, false) {
+            insn.cont = true;
+            return;
+        }
+        if addr & (size - 1) != 0 && mem_misaligned(state, addr, size, false) {
+            insn.cont = true;
+            return;
+        }
+        let ptr: *mut #ty = get_ptr(to_host!(state.host_base, addr)) as *mut #ty;
+        let n: #ty = unsafe{ ptr.read_unaligned() };
+        let len = if insn.rvc { 2 } else { 4 };
+        if state.watching && watch_load(state, addr, size, n as u64, len) {
+            insn.cont = true;
+        }
+    };
+
+    tt.into()
+}
+
 #[proc_macro]
 pub fn rewrite_flag(flag: TokenStream) -> TokenStream {
     let st: &str = &("NEWLIB_".to_string() + &flag.to_string());
@@ -211,12 +269,3 @@ pub fn rewrite_flag(flag: TokenStream) -> TokenStream {
 
     tt.into()
 }
-
-#[proc_macro]
-pub fn guest_mem_offset(_item: TokenStream) -> TokenStream {
-    let tt = quote! {
-        let guest_mem_offset: u64 = 0x000800000000u64;
-    };
-
-    tt.into()
-}
@@ -0,0 +1,551 @@
+use std::mem::size_of;
+
+use libc::{rlimit, rusage, stat, sysinfo, timespec, timeval, utsname};
+
+// the riscv64 linux kernel abi, as the guest's libc sees it: the struct
+// layouts of include/uapi/asm-generic for a 64-bit long. the host libc
+// lays some of them out differently, x86-64 struct stat for one, so every
+// struct is converted field by field on the way in and out.
+
+// asm-generic/stat.h, what fstat and newfstatat fill in
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stat {
+    pub st_dev: u64,
+    pub st_ino: u64,
+    pub st_mode: u32,
+    pub st_nlink: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    pub st_rdev: u64,
+    pub __pad1: u64,
+    pub st_size: i64,
+    pub st_blksize: i32,
+    pub __pad2: i32,
+    pub st_blocks: i64,
+    pub st_atime: i64,
+    pub st_atime_nsec: u64,
+    pub st_mtime: i64,
+    pub st_mtime_nsec: u64,
+    pub st_ctime: i64,
+    pub st_ctime_nsec: u64,
+    pub __unused4: u32,
+    pub __unused5: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StatxTimestamp {
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    pub __reserved: i32,
+}
+
+// linux/stat.h, the same on every architecture
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Statx {
+    pub stx_mask: u32,
+    pub stx_blksize: u32,
+    pub stx_attributes: u64,
+    pub stx_nlink: u32,
+    pub stx_uid: u32,
+    pub stx_gid: u32,
+    pub stx_mode: u16,
+    pub __spare0: u16,
+    pub stx_ino: u64,
+    pub stx_size: u64,
+    pub stx_blocks: u64,
+    pub stx_attributes_mask: u64,
+    pub stx_atime: StatxTimestamp,
+    pub stx_btime: StatxTimestamp,
+    pub stx_ctime: StatxTimestamp,
+    pub stx_mtime: StatxTimestamp,
+    pub stx_rdev_major: u32,
+    pub stx_rdev_minor: u32,
+    pub stx_dev_major: u32,
+    pub stx_dev_minor: u32,
+    pub stx_mnt_id: u64,
+    pub stx_dio_mem_align: u32,
+    pub stx_dio_offset_align: u32,
+    pub __spare3: [u64; 12],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeval {
+    pub tv_sec: i64,
+    pub tv_usec: i64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rusage {
+    pub ru_utime: Timeval,
+    pub ru_stime: Timeval,
+    pub ru_maxrss: i64,
+    pub ru_ixrss: i64,
+    pub ru_idrss: i64,
+    pub ru_isrss: i64,
+    pub ru_minflt: i64,
+    pub ru_majflt: i64,
+    pub ru_nswap: i64,
+    pub ru_inblock: i64,
+    pub ru_oublock: i64,
+    pub ru_msgsnd: i64,
+    pub ru_msgrcv: i64,
+    pub ru_nsignals: i64,
+    pub ru_nvcsw: i64,
+    pub ru_nivcsw: i64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rlimit {
+    pub rlim_cur: u64,
+    pub rlim_max: u64,
+}
+
+// linux/sysinfo.h, _f is empty with a 64-bit long
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sysinfo {
+    pub uptime: i64,
+    pub loads: [u64; 3],
+    pub totalram: u64,
+    pub freeram: u64,
+    pub sharedram: u64,
+    pub bufferram: u64,
+    pub totalswap: u64,
+    pub freeswap: u64,
+    pub procs: u16,
+    pub pad: u16,
+    pub totalhigh: u64,
+    pub freehigh: u64,
+    pub mem_unit: u32,
+}
+
+// asm/sigcontext.h, with the d extension state and without fcsr, which
+// the emulator does not keep. regs[0] is the pc.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Sigcontext {
+    pub regs: [u64; 32],
+    pub fpregs: [u64; 32],
+}
+
+// where uc_mcontext is in the rt_sigframe at a signal handler's sp: past
+// the 128-byte siginfo and the ucontext fields before it, 16-byte aligned
+pub const LINUX_SIGFRAME_MCONTEXT: u64 = 128 + 176;
+
+// asm-generic/signal.h: riscv64 has no sa_restorer, its signal return is
+// the vdso's __vdso_rt_sigreturn
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sigaction {
+    pub sa_handler: u64,
+    pub sa_flags: u64,
+    pub sa_mask: u64,
+}
+
+pub const LINUX_NSIG: usize = 64;
+pub const LINUX_SIGKILL: u64 = 9;
+pub const LINUX_SIGSTOP: u64 = 19;
+
+pub const LINUX_SIG_BLOCK: u64 = 0;
+pub const LINUX_SIG_UNBLOCK: u64 = 1;
+pub const LINUX_SIG_SETMASK: u64 = 2;
+
+// struct robust_list_head: the list, futex_offset and list_op_pending
+pub const LINUX_ROBUST_LIST_HEAD_SIZE: u64 = 24;
+
+pub const LINUX_TCGETS: u64 = 0x5401;
+pub const LINUX_TIOCGWINSZ: u64 = 0x5413;
+
+pub const LINUX_NCCS: usize = 19;
+
+// asm-generic/termbits.h, what TCGETS fills in. the libc struct termios
+// has more control characters and the speeds after them.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Termios {
+    pub c_iflag: u32,
+    pub c_oflag: u32,
+    pub c_cflag: u32,
+    pub c_lflag: u32,
+    pub c_line: u8,
+    pub c_cc: [u8; LINUX_NCCS],
+}
+
+pub const UTS_LEN: usize = 65;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Utsname {
+    pub sysname: [u8; UTS_LEN],
+    pub nodename: [u8; UTS_LEN],
+    pub release: [u8; UTS_LEN],
+    pub version: [u8; UTS_LEN],
+    pub machine: [u8; UTS_LEN],
+    pub domainname: [u8; UTS_LEN],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Iovec {
+    pub iov_base: u64,
+    pub iov_len: u64,
+}
+
+// the sizes the kernel headers give, so a wrong field shows up at build time
+const _: () = assert!(size_of::<Stat>() == 128);
+const _: () = assert!(size_of::<Statx>() == 256);
+const _: () = assert!(size_of::<Rusage>() == 144);
+const _: () = assert!(size_of::<Sysinfo>() == 112);
+const _: () = assert!(size_of::<Utsname>() == 390);
+const _: () = assert!(size_of::<Sigaction>() == 24);
+const _: () = assert!(size_of::<Termios>() == 36);
+
+// linux caps a single read or write at this, MAX_RW_COUNT
+pub const MAX_RW_COUNT: u64 = 0x7ffff000;
+// and readv/writev at this many buffers, UIO_MAXIOV
+pub const UIO_MAXIOV: u64 = 1024;
+
+pub const RLIMIT_STACK: u32 = 3;
+pub const RLIM_NLIMITS: u32 = 16;
+
+// the generic errno numbers riscv64 uses are the ones of a linux host
+#[cfg(target_os = "linux")]
+pub fn linux_errno(errno: i32) -> i32 {
+    return errno;
+}
+
+// elsewhere only the classic unix ones below 35 agree
+#[cfg(not(target_os = "linux"))]
+pub fn linux_errno(errno: i32) -> i32 {
+    return match errno {
+        1..=34 => errno,
+        libc::EAGAIN => 11,
+        libc::ENAMETOOLONG => 36,
+        libc::ENOSYS => 38,
+        libc::ENOTEMPTY => 39,
+        libc::ELOOP => 40,
+        libc::EOVERFLOW => 75,
+        libc::ENOTSUP => 95,
+        libc::ETIMEDOUT => 110,
+        _ => libc::EIO,
+    };
+}
+
+// open flags, asm-generic/fcntl.h
+pub const LINUX_O_ACCMODE: i32 = 0o3;
+pub const LINUX_O_CREAT: i32 = 0o100;
+pub const LINUX_O_EXCL: i32 = 0o200;
+pub const LINUX_O_NOCTTY: i32 = 0o400;
+pub const LINUX_O_TRUNC: i32 = 0o1000;
+pub const LINUX_O_APPEND: i32 = 0o2000;
+pub const LINUX_O_NONBLOCK: i32 = 0o4000;
+pub const LINUX_O_DSYNC: i32 = 0o10000;
+pub const LINUX_O_DIRECTORY: i32 = 0o200000;
+pub const LINUX_O_NOFOLLOW: i32 = 0o400000;
+pub const LINUX_O_CLOEXEC: i32 = 0o2000000;
+pub const LINUX_O_SYNC: i32 = 0o4010000;
+
+// the generic open flags are the host's own on linux
+#[cfg(target_os = "linux")]
+pub fn linux_open_flags(flags: i32) -> i32 {
+    return flags;
+}
+
+// elsewhere the ones with a posix equivalent are moved over
+#[cfg(not(target_os = "linux"))]
+const OPEN_FLAGS: [(i32, i32); 11] = [
+    (LINUX_O_CREAT, libc::O_CREAT),
+    (LINUX_O_EXCL, libc::O_EXCL),
+    (LINUX_O_NOCTTY, libc::O_NOCTTY),
+    (LINUX_O_TRUNC, libc::O_TRUNC),
+    (LINUX_O_APPEND, libc::O_APPEND),
+    (LINUX_O_NONBLOCK, libc::O_NONBLOCK),
+    (LINUX_O_SYNC, libc::O_SYNC),
+    (LINUX_O_DSYNC, libc::O_DSYNC),
+    (LINUX_O_DIRECTORY, libc::O_DIRECTORY),
+    (LINUX_O_NOFOLLOW, libc::O_NOFOLLOW),
+    (LINUX_O_CLOEXEC, libc::O_CLOEXEC),
+];
+
+#[cfg(not(target_os = "linux"))]
+pub fn linux_open_flags(flags: i32) -> i32 {
+    let mut host = flags & LINUX_O_ACCMODE;
+    for (linux, host_flag) in OPEN_FLAGS {
+        if flags & linux == linux {
+            host |= host_flag;
+        }
+    }
+    return host;
+}
+
+// and back, for F_GETFL
+#[cfg(target_os = "linux")]
+pub fn host_open_flags(flags: i32) -> i32 {
+    return flags;
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn host_open_flags(flags: i32) -> i32 {
+    let mut linux = flags & libc::O_ACCMODE;
+    for (linux_flag, host) in OPEN_FLAGS {
+        if flags & host == host {
+            linux |= linux_flag;
+        }
+    }
+    return linux;
+}
+
+// fcntl commands, the same on every linux architecture
+pub const LINUX_F_DUPFD: u64 = 0;
+pub const LINUX_F_GETFD: u64 = 1;
+pub const LINUX_F_SETFD: u64 = 2;
+pub const LINUX_F_GETFL: u64 = 3;
+pub const LINUX_F_SETFL: u64 = 4;
+pub const LINUX_F_DUPFD_CLOEXEC: u64 = 1030;
+pub const LINUX_FD_CLOEXEC: u64 = 1;
+
+// the *at flags the host may number differently
+pub const LINUX_AT_REMOVEDIR: u64 = 0x200;
+pub const LINUX_AT_SYMLINK_FOLLOW: u64 = 0x400;
+
+// riscv_flush_icache flags
+pub const LINUX_FLUSH_ICACHE_LOCAL: u64 = 1;
+
+// the stat field types are the host's, on some hosts the casts convert
+#[allow(clippy::unnecessary_cast)]
+pub fn linux_stat(st: &stat) -> Stat {
+    return Stat {
+        st_dev: st.st_dev as u64,
+        st_ino: st.st_ino as u64,
+        st_mode: st.st_mode as u32,
+        st_nlink: st.st_nlink as u32,
+        st_uid: st.st_uid,
+        st_gid: st.st_gid,
+        st_rdev: st.st_rdev as u64,
+        st_size: st.st_size as i64,
+        st_blksize: st.st_blksize as i32,
+        st_blocks: st.st_blocks as i64,
+        st_atime: st.st_atime as i64,
+        st_atime_nsec: st.st_atime_nsec as u64,
+        st_mtime: st.st_mtime as i64,
+        st_mtime_nsec: st.st_mtime_nsec as u64,
+        st_ctime: st.st_ctime as i64,
+        st_ctime_nsec: st.st_ctime_nsec as u64,
+        ..Default::default()
+    };
+}
+
+// a statx built from a stat, for a host without statx: the basic fields
+// are all there, btime and the mount id are not. casts as in linux_stat.
+#[allow(clippy::unnecessary_cast)]
+pub fn linux_statx_from_stat(st: &stat) -> Statx {
+    let time = |sec: i64, nsec: i64| StatxTimestamp {
+        tv_sec: sec,
+        tv_nsec: nsec as u32,
+        __reserved: 0,
+    };
+    return Statx {
+        stx_mask: STATX_BASIC_STATS,
+        stx_blksize: st.st_blksize as u32,
+        stx_nlink: st.st_nlink as u32,
+        stx_uid: st.st_uid,
+        stx_gid: st.st_gid,
+        stx_mode: st.st_mode as u16,
+        stx_ino: st.st_ino as u64,
+        stx_size: st.st_size as u64,
+        stx_blocks: st.st_blocks as u64,
+        stx_atime: time(st.st_atime as i64, st.st_atime_nsec as i64),
+        stx_ctime: time(st.st_ctime as i64, st.st_ctime_nsec as i64),
+        stx_mtime: time(st.st_mtime as i64, st.st_mtime_nsec as i64),
+        stx_rdev_major: linux_major(st.st_rdev as u64),
+        stx_rdev_minor: linux_minor(st.st_rdev as u64),
+        stx_dev_major: linux_major(st.st_dev as u64),
+        stx_dev_minor: linux_minor(st.st_dev as u64),
+        ..Default::default()
+    };
+}
+
+pub const STATX_BASIC_STATS: u32 = 0x7ff;
+
+// the glibc dev_t encoding, which is what the kernel hands out too
+fn linux_major(dev: u64) -> u32 {
+    return (((dev >> 32) & 0xfffff000) | ((dev >> 8) & 0xfff)) as u32;
+}
+
+fn linux_minor(dev: u64) -> u32 {
+    return (((dev >> 12) & 0xffffff00) | (dev & 0xff)) as u32;
+}
+
+#[cfg(target_os = "linux")]
+pub fn linux_statx(st: &libc::statx) -> Statx {
+    let time = |t: &libc::statx_timestamp| StatxTimestamp {
+        tv_sec: t.tv_sec,
+        tv_nsec: t.tv_nsec,
+        __reserved: 0,
+    };
+    return Statx {
+        stx_mask: st.stx_mask,
+        stx_blksize: st.stx_blksize,
+        stx_attributes: st.stx_attributes,
+        stx_nlink: st.stx_nlink,
+        stx_uid: st.stx_uid,
+        stx_gid: st.stx_gid,
+        stx_mode: st.stx_mode,
+        stx_ino: st.stx_ino,
+        stx_size: st.stx_size,
+        stx_blocks: st.stx_blocks,
+        stx_attributes_mask: st.stx_attributes_mask,
+        stx_atime: time(&st.stx_atime),
+        stx_btime: time(&st.stx_btime),
+        stx_ctime: time(&st.stx_ctime),
+        stx_mtime: time(&st.stx_mtime),
+        stx_rdev_major: st.stx_rdev_major,
+        stx_rdev_minor: st.stx_rdev_minor,
+        stx_dev_major: st.stx_dev_major,
+        stx_dev_minor: st.stx_dev_minor,
+        stx_mnt_id: st.stx_mnt_id,
+        stx_dio_mem_align: st.stx_dio_mem_align,
+        stx_dio_offset_align: st.stx_dio_offset_align,
+        ..Default::default()
+    };
+}
+
+// time_t and c_long are 32 bits on some hosts
+#[allow(clippy::unnecessary_cast)]
+pub fn linux_timespec(ts: &timespec) -> Timespec {
+    return Timespec {
+        tv_sec: ts.tv_sec as i64,
+        tv_nsec: ts.tv_nsec as i64,
+    };
+}
+
+// so are time_t and suseconds_t
+#[allow(clippy::unnecessary_cast)]
+pub fn linux_timeval(tv: &timeval) -> Timeval {
+    return Timeval {
+        tv_sec: tv.tv_sec as i64,
+        tv_usec: tv.tv_usec as i64,
+    };
+}
+
+// the counters are c_long, 32 bits on some hosts
+#[allow(clippy::unnecessary_cast)]
+pub fn linux_rusage(ru: &rusage) -> Rusage {
+    return Rusage {
+        ru_utime: linux_timeval(&ru.ru_utime),
+        ru_stime: linux_timeval(&ru.ru_stime),
+        ru_maxrss: ru.ru_maxrss as i64,
+        ru_ixrss: ru.ru_ixrss as i64,
+        ru_idrss: ru.ru_idrss as i64,
+        ru_isrss: ru.ru_isrss as i64,
+        ru_minflt: ru.ru_minflt as i64,
+        ru_majflt: ru.ru_majflt as i64,
+        ru_nswap: ru.ru_nswap as i64,
+        ru_inblock: ru.ru_inblock as i64,
+        ru_oublock: ru.ru_oublock as i64,
+        ru_msgsnd: ru.ru_msgsnd as i64,
+        ru_msgrcv: ru.ru_msgrcv as i64,
+        ru_nsignals: ru.ru_nsignals as i64,
+        ru_nvcsw: ru.ru_nvcsw as i64,
+        ru_nivcsw: ru.ru_nivcsw as i64,
+    };
+}
+
+// rlim_t is not u64 everywhere
+#[allow(clippy::unnecessary_cast)]
+pub fn linux_rlimit(rl: &rlimit) -> Rlimit {
+    return Rlimit {
+        rlim_cur: rl.rlim_cur as u64,
+        rlim_max: rl.rlim_max as u64,
+    };
+}
+
+pub fn host_rlimit(rl: &Rlimit) -> rlimit {
+    return rlimit {
+        rlim_cur: rl.rlim_cur as _,
+        rlim_max: rl.rlim_max as _,
+    };
+}
+
+// the sysinfo fields are c_ulong on a linux host, 32 bits on some
+#[allow(clippy::unnecessary_cast)]
+#[cfg(target_os = "linux")]
+pub fn linux_sysinfo(info: &sysinfo) -> Sysinfo {
+    return Sysinfo {
+        uptime: info.uptime as i64,
+        loads: info.loads.map(|load| load as u64),
+        totalram: info.totalram as u64,
+        freeram: info.freeram as u64,
+        sharedram: info.sharedram as u64,
+        bufferram: info.bufferram as u64,
+        totalswap: info.totalswap as u64,
+        freeswap: info.freeswap as u64,
+        procs: info.procs,
+        totalhigh: info.totalhigh as u64,
+        freehigh: info.freehigh as u64,
+        mem_unit: info.mem_unit,
+        ..Default::default()
+    };
+}
+
+// a field of utsname, cut to what fits with its nul
+fn linux_uts_field(field: &[libc::c_char]) -> [u8; UTS_LEN] {
+    let mut out = [0u8; UTS_LEN];
+    let len = field
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(field.len())
+        .min(UTS_LEN - 1);
+    for (dst, &src) in out.iter_mut().zip(field[..len].iter()) {
+        *dst = src as u8;
+    }
+    return out;
+}
+
+// the host's names, but the guest runs on linux riscv64 whatever the host
+// the flags and control characters of a linux host are the riscv64 ones
+#[cfg(target_os = "linux")]
+pub fn linux_termios(t: &libc::termios) -> Termios {
+    let mut c_cc = [0u8; LINUX_NCCS];
+    c_cc.copy_from_slice(&t.c_cc[..LINUX_NCCS]);
+    return Termios {
+        c_iflag: t.c_iflag,
+        c_oflag: t.c_oflag,
+        c_cflag: t.c_cflag,
+        c_lflag: t.c_lflag,
+        c_line: t.c_line,
+        c_cc,
+    };
+}
+
+pub fn linux_utsname(uts: &utsname) -> Utsname {
+    let mut sysname = [0u8; UTS_LEN];
+    sysname[..5].copy_from_slice(b"Linux");
+    let mut machine = [0u8; UTS_LEN];
+    machine[..7].copy_from_slice(b"riscv64");
+    #[cfg(target_os = "linux")]
+    let domainname = linux_uts_field(&uts.domainname);
+    #[cfg(not(target_os = "linux"))]
+    let domainname = [0u8; UTS_LEN];
+    return Utsname {
+        sysname,
+        nodename: linux_uts_field(&uts.nodename),
+        release: linux_uts_field(&uts.release),
+        version: linux_uts_field(&uts.version),
+        machine,
+        domainname,
+    };
+}
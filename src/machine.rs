@@ -1,16 +1,152 @@
-use std::{fs::OpenOptions, os::fd::AsRawFd};
+use std::{
+    ffi::CString,
+    fs,
+    io::{self, Read},
+    mem::size_of,
+    os::raw::c_void,
+    path::Path,
+    process::exit,
+    time::Instant,
+};
+
+use libc::{open, O_CLOEXEC, O_CREAT, O_RDONLY, O_TRUNC, O_WRONLY, SIGBUS, SIGSEGV, SIG_DFL};
 
 use crate::{
+    aot::{aot_exec, AOT_HASH},
+    cache::{cache_add, cache_block, cache_host_pc, cache_invalidate, cache_lookup},
+    elfdef::{
+        Phdr, AT_BASE, AT_CLKTCK, AT_EGID, AT_ENTRY, AT_EUID, AT_EXECFN, AT_FLAGS, AT_GID,
+        AT_HWCAP, AT_MINSIGSTKSZ, AT_NULL, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM, AT_RANDOM,
+        AT_SECURE, AT_SYSINFO_EHDR, AT_UID, PROT_READ, PROT_WRITE,
+    },
+    fd::{fd_install, File},
+    firmware::{firmware_detect, firmware_load, firmware_parse, Format},
+    guard::{guard_call, HostFault},
     interp::exec_block_interp,
-    mmu::{mmu_alloc, mmu_load_elf},
+    jit::{exec_block_jit, jit_compile_trace},
+    max,
+    mmu::{
+        mmu_copy_in, mmu_copy_out, mmu_load_elf, mmu_load_elf_image, mmu_load_interp, mmu_rebase,
+        mmu_write,
+    },
     reg::GpRegTypeT,
-    rvemu::{mmu_write, ExitReason, Machine},
+    round_down, round_up,
+    rvemu::{ExitReason, Machine, Misaligned, MmuFault, Personality},
+    stats::stats_report,
+    sys_call::sys_personality,
+    to_host,
+    trace::trace_form,
+    translate::fnv1a,
+    vdso::vdso_map,
+    vfs::{vfs_enabled, vfs_resolve},
+    vma::{vma_dump, vma_layout, vma_map, vma_range, LINUX_MAP_ANONYMOUS, LINUX_MAP_PRIVATE},
+    watch::{watch_add, watch_remove},
 };
 
+pub const JIT_THRESHOLD: u64 = 64;
+
+fn machine_compile_queued(m: &mut Machine) {
+    let pc = match m.cache.queue.pop_front() {
+        Some(pc) => pc,
+        None => return,
+    };
+    // already compiled, or the code was unmapped since it was queued
+    if cache_lookup(&m.cache, pc).is_some() || !m.cache.blocks.contains_key(&pc) {
+        return;
+    }
+
+    let start = m.stats.enabled.then(Instant::now);
+    let trace = trace_form(&mut m.cache, &m.mmu, pc);
+    let (code, pcs) = jit_compile_trace(&trace, m.mmu.host_base, m.state.misaligned);
+    cache_add(&mut m.cache, pc, &code, &pcs);
+    m.stats.compiled += 1;
+    m.stats.traced += trace.blocks as u64;
+    if let Some(start) = start {
+        m.stats.compile_time += start.elapsed();
+    }
+}
+
+fn machine_exec_block(m: &mut Machine) {
+    let pc = m.state.pc;
+    let start = m.stats.enabled.then(Instant::now);
+    // only the interpreter checks watchpoints
+    let compiled = !m.state.watching;
+    // stores check for code in State, the cache keeps track of it
+    m.state.code_start = m.cache.code_start;
+    m.state.code_end = m.cache.code_end;
+
+    if compiled && m.aot && aot_exec(&mut m.state) {
+        m.stats.aot_blocks += 1;
+        if let Some(start) = start {
+            m.stats.aot_time += start.elapsed();
+        }
+        return;
+    }
+
+    if let Some(code) = cache_lookup(&m.cache, pc).filter(|_| compiled) {
+        exec_block_jit(code, &mut m.state);
+        m.stats.jit_blocks += 1;
+        if let Some(start) = start {
+            m.stats.jit_time += start.elapsed();
+        }
+        return;
+    }
+
+    if let Err(fault) = cache_block(&mut m.cache, &m.mmu, pc) {
+        m.state.fault = Some(fault);
+        m.state.exit_reason = ExitReason::Fault;
+        return;
+    }
+    // the block may have just been decoded from writable memory
+    m.state.code_start = m.cache.code_start;
+    m.state.code_end = m.cache.code_end;
+    let block = m.cache.blocks.get_mut(&pc).unwrap();
+    block.hot += 1;
+    let promote = m.jit && !block.queued && block.hot >= m.jit_threshold;
+    block.queued |= promote;
+
+    exec_block_interp(&mut m.state, block, &mut m.stats);
+    m.stats.interp_blocks += 1;
+    if let Some(start) = start {
+        m.stats.interp_time += start.elapsed();
+    }
+
+    if promote {
+        m.cache.queue.push_back(pc);
+        m.stats.promoted += 1;
+    }
+}
+
+// a host fault in guest memory: find the guest pc, from the jitcode map for
+// native jit loads/stores and from state.pc everywhere else. jit registers
+// are not written back, so the guest can not carry on after this.
+fn machine_fault(m: &mut Machine, fault: HostFault) {
+    if let Some(pc) = cache_host_pc(&m.cache, fault.rip) {
+        m.state.pc = pc;
+    }
+    let addr = fault.addr.wrapping_sub(m.mmu.host_base);
+    m.state.fault = Some(if fault.write {
+        MmuFault::Store(addr)
+    } else {
+        MmuFault::Load(addr)
+    });
+    m.state.exit_reason = ExitReason::Fault;
+}
+
 pub fn machine_step(m: &mut Machine) -> ExitReason {
     loop {
         m.state.exit_reason = ExitReason::None;
-        exec_block_interp(&mut m.state);
+        machine_compile_queued(m);
+        if let Err(fault) = guard_call(&mut || machine_exec_block(m)) {
+            machine_fault(m, fault);
+            return ExitReason::Fault;
+        }
+        if let Some((start, end)) = m.state.stale.take() {
+            machine_invalidate(m, start, end);
+        }
+        if m.state.exit_reason == ExitReason::Fault {
+            return ExitReason::Fault;
+        }
         assert!(m.state.exit_reason != ExitReason::None);
 
         if m.state.exit_reason == ExitReason::IndirectBranch
@@ -22,44 +158,644 @@ pub fn machine_step(m: &mut Machine) -> ExitReason {
         break;
     }
     m.state.pc = m.state.reenter_pc;
-    assert!(m.state.exit_reason == ExitReason::Ecall);
-    return ExitReason::Ecall;
+    assert!(matches!(
+        m.state.exit_reason,
+        ExitReason::Ecall | ExitReason::Watchpoint
+    ));
+    return m.state.exit_reason;
 }
 
-pub fn machine_load_program(m: &mut Machine, prog: &str) {
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(prog)
-        .expect("open file failed!!");
+// stop with ExitReason::Watchpoint when the guest touches [start, start +
+// len) in one of the WATCH_* ways, returns the index to remove it by
+pub fn machine_watch(m: &mut Machine, start: u64, len: u64, access: u8) -> Result<usize, String> {
+    return watch_add(&mut m.state, start, len, access);
+}
 
-    let fd = file.as_raw_fd();
+pub fn machine_unwatch(m: &mut Machine, index: usize) {
+    watch_remove(&mut m.state, index);
+}
 
-    mmu_load_elf(&mut m.mmu, fd);
+// everything asked for on the command line, once the guest is done
+pub fn machine_report(m: &Machine) {
+    if m.stats.enabled {
+        stats_report(&m.stats);
+    }
+    if m.state.misaligned == Misaligned::Report {
+        eprintln!("rvemu-rs: {} misaligned accesses", m.state.misaligned_count);
+    }
+    if m.dump_maps {
+        eprint!("{}", vma_dump(&m.mmu));
+    }
+}
 
-    m.state.pc = m.mmu.entry;
+// the guest died on a bad access. there are no guest signal handlers, so
+// this is the default action for the signal linux would send: report
+// si_addr and si_code the way the kernel logs them and die by the signal.
+pub fn machine_fault_exit(m: &Machine) -> ! {
+    let (kind, addr) = match m.state.fault.unwrap() {
+        MmuFault::Load(addr) => ("load", addr),
+        MmuFault::Store(addr) => ("store", addr),
+        MmuFault::Fetch(addr) => ("fetch", addr),
+        MmuFault::LoadMisaligned(addr) => ("misaligned load", addr),
+        MmuFault::StoreMisaligned(addr) => ("misaligned store", addr),
+    };
+    let (sig, name, code) = match m.state.fault.unwrap() {
+        MmuFault::LoadMisaligned(_) | MmuFault::StoreMisaligned(_) => {
+            (SIGBUS, "bus error", "BUS_ADRALN")
+        }
+        _ if vma_range(&m.mmu, addr).is_some() => (SIGSEGV, "segmentation fault", "SEGV_ACCERR"),
+        _ => (SIGSEGV, "segmentation fault", "SEGV_MAPERR"),
+    };
+    eprintln!(
+        "rvemu-rs: guest {}: {} at {:#x}, pc {:#x} ({})",
+        name, kind, addr, m.state.pc, code
+    );
+    machine_report(m);
+    unsafe {
+        libc::signal(sig, SIG_DFL);
+        libc::raise(sig);
+    }
+    exit(128 + sig);
 }
 
-pub fn machine_setup(m: &mut Machine, argc: i32, argv: Vec<String>) {
-    let sz = 32 * 1024 * 1024;
-    let stack = mmu_alloc(&mut m.mmu, sz);
-    m.state.gp_regs[GpRegTypeT::Sp as usize] = stack + sz as u64;
+// guest code in [start, end) changed or went away: drop what was decoded and
+// compiled from it. translated code can not be dropped piecemeal, so it is
+// not used any more either.
+pub fn machine_invalidate(m: &mut Machine, start: u64, end: u64) {
+    cache_invalidate(&mut m.cache, start, end);
+    m.aot = false;
+}
 
-    m.state.gp_regs[GpRegTypeT::Sp as usize] -= 24;
+pub fn machine_rebase(m: &mut Machine, base: u64) -> Result<(), String> {
+    mmu_rebase(&mut m.mmu, base)?;
+    m.state.host_base = m.mmu.host_base;
+    return Ok(());
+}
 
-    let args: u64 = argc as u64 - 1;
-    let mut i: usize = args as usize;
-    while i > 0 {
-        let len = argv[i].len();
-        let addr = mmu_alloc(&mut m.mmu, (len + 1) as i64);
-        mmu_write(addr, argv[i].as_ptr(), len);
-        m.state.gp_regs[GpRegTypeT::Sp as usize] -= 8;
-        let ap = (&addr) as *const u64 as *const u8;
-        mmu_write(m.state.gp_regs[GpRegTypeT::Sp as usize], ap, 8);
-        i -= 1;
+// an absolute guest path on the host: under the sysroot if it is there,
+// as is otherwise, like the qemu-user -L prefix
+pub fn machine_path(m: &Machine, path: &str) -> String {
+    if let Some(sysroot) = m.sysroot.as_ref().filter(|_| path.starts_with('/')) {
+        let prefixed = format!("{}{}", sysroot.trim_end_matches('/'), path);
+        if Path::new(&prefixed).exists() {
+            return prefixed;
+        }
     }
+    return path.to_string();
+}
+
+// --stdin, --stdout and --stderr: the guest's fd is the host file instead
+// of the emulator's own
+pub fn machine_redirect(m: &mut Machine, fd: i32, path: &str) -> Result<(), String> {
+    let flags = match fd {
+        0 => O_RDONLY,
+        _ => O_WRONLY | O_CREAT | O_TRUNC,
+    };
+    let name = CString::new(path).map_err(|_| format!("bad path: {}", path))?;
+    let host_fd = unsafe { open(name.as_ptr(), flags | O_CLOEXEC, 0o666) };
+    if host_fd < 0 {
+        return Err(format!("{}: {}", path, io::Error::last_os_error()));
+    }
+    let file = File::Host {
+        fd: host_fd,
+        owned: true,
+        path: None,
+    };
+    fd_install(&mut m.fds, fd, file);
+    return Ok(());
+}
+
+// the riscv tls abi is variant I: tp points at the start of the tls block
+// and the thread control block sits right below it, glibc keeps its dtv
+// pointer and one private word there
+const TCB_SIZE: u64 = 16;
+
+// the main thread's tls block for a static program, with tp pointing at
+// it. ld.so does this itself for a dynamic one, and libc startup code that
+// does it again just replaces tp.
+fn machine_setup_tls(m: &mut Machine) -> Result<(), String> {
+    let tls = match m.mmu.tls {
+        Some(tls) => tls,
+        None => return Ok(()),
+    };
+    let align = max!(tls.p_align, TCB_SIZE);
+    let len = TCB_SIZE + align + tls.p_memsz;
+    let flags = LINUX_MAP_PRIVATE | LINUX_MAP_ANONYMOUS;
+    let addr = vma_map(&mut m.mmu, 0, len, PROT_READ | PROT_WRITE, flags, -1, 0)
+        .map_err(|_| "no room for the tls block".to_string())?;
+    let tp = round_up!(addr + TCB_SIZE, align);
+
+    // the rest of the block past p_filesz is tbss, already zero
+    let mut image = vec![0u8; tls.p_filesz as usize];
+    mmu_copy_out(&m.mmu, tls.p_vaddr, &mut image).map_err(|_| "bad tls segment".to_string())?;
+    mmu_copy_in(&mut m.mmu, tp, &image).unwrap();
+    m.state.gp_regs[GpRegTypeT::Tp as usize] = tp;
+    return Ok(());
+}
+
+// load the program at path, or from stdin for "-"
+pub fn machine_load_program(m: &mut Machine, prog: &str) -> Result<(), String> {
+    let mut buf = Vec::new();
+    let read = if prog == "-" {
+        io::stdin().read_to_end(&mut buf).map(|_| ())
+    } else {
+        fs::read(prog).map(|data| buf = data)
+    };
+    read.map_err(|e| format!("cannot read {}: {}", prog, e))?;
+    return machine_load_bytes(m, &buf, prog);
+}
+
+// a raw, hex or srec program: the pages it covers are all there is, so
+// there are no headers for the auxv and brk starts past the highest one
+fn machine_load_firmware(
+    m: &mut Machine,
+    buf: &[u8],
+    name: &str,
+    format: Format,
+) -> Result<(), String> {
+    let fw = firmware_parse(buf, format, m.load_addr)?;
+    let end = firmware_load(&mut m.mmu, &fw, name)?;
+    let entry = match fw.entry.or(fw.chunks.first().map(|(addr, _)| *addr)) {
+        Some(entry) => entry,
+        None => return Err("empty image".to_string()),
+    };
+    m.mmu.entry = entry;
+    m.mmu.prog_entry = entry;
+    m.mmu.base = end;
+    m.mmu.alloc = end;
+    m.mmu.host_alloc = to_host!(m.mmu.host_base, end);
+    return Ok(());
+}
 
-    m.state.gp_regs[GpRegTypeT::Sp as usize] -= 8;
-    let ap: *const u8 = (&args) as *const u64 as *const u8;
-    mmu_write(m.state.gp_regs[GpRegTypeT::Sp as usize], ap, 8);
+// one of the --image files next to the program, at addr if given
+fn machine_load_image(m: &mut Machine, path: &str, addr: Option<u64>) -> Result<(), String> {
+    let buf = fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let loaded = firmware_detect(&buf, path).and_then(|format| match format {
+        Format::Elf => mmu_load_elf_image(&mut m.mmu, &buf, addr, path).map(|_| ()),
+        format => firmware_parse(&buf, format, addr)
+            .and_then(|fw| firmware_load(&mut m.mmu, &fw, path))
+            .map(|_| ()),
+    });
+    return loaded.map_err(|e| format!("{}: {}", path, e));
+}
+
+// load an image already in memory, name is what the mappings show. it is
+// elf, or firmware as --format says or firmware_detect finds, and the
+// --image files go in with it.
+pub fn machine_load_bytes(m: &mut Machine, buf: &[u8], name: &str) -> Result<(), String> {
+    let format = match m.format {
+        Some(format) => format,
+        None => firmware_detect(buf, name).map_err(|e| format!("{}: {}", name, e))?,
+    };
+    // bare-metal images are built with newlib, there is no other libc
+    if m.personality.is_none() {
+        m.personality = Some(match format {
+            Format::Elf => sys_personality(buf),
+            _ => Personality::Newlib,
+        });
+    }
+    let interp = if format == Format::Elf {
+        if m.load_addr.is_some() {
+            return Err(format!("{}: elf programs go at their own addresses", name));
+        }
+        mmu_load_elf(&mut m.mmu, buf, name).map_err(|e| format!("{}: {}", name, e))?
+    } else {
+        machine_load_firmware(m, buf, name, format).map_err(|e| format!("{}: {}", name, e))?;
+        None
+    };
+    // before the layout, so the stack and mmap area stay clear of them
+    for (path, addr) in m.images.clone() {
+        machine_load_image(m, &path, addr)?;
+    }
+    vma_layout(&mut m.mmu)?;
+    if let Some(interp) = interp {
+        // a sandboxed guest gets its dynamic linker from inside the sandbox
+        let path = match vfs_enabled(&m.vfs) {
+            true => vfs_resolve(&m.vfs, "/", &interp, true)
+                .map(|resolved| resolved.host.to_string_lossy().into_owned())
+                .map_err(|_| format!("{}: not in the guest's filesystem", interp))?,
+            false => machine_path(m, &interp),
+        };
+        let buf = fs::read(&path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        mmu_load_interp(&mut m.mmu, &buf, &interp).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    vdso_map(&mut m.mmu)?;
+    machine_setup_tls(m)?;
+    m.state.pc = m.entry.unwrap_or(m.mmu.entry);
+
+    // only run translated blocks for the exact binary they came from, at
+    // the link-time addresses they are keyed on
+    if cfg!(feature = "aot") {
+        m.aot = fnv1a(buf) == AOT_HASH;
+        if !m.aot {
+            eprintln!(
+                "rvemu-rs: {} does not match the built-in translation, interpreting it",
+                name
+            );
+        } else if m.mmu.prog_bias != 0 {
+            m.aot = false;
+            eprintln!(
+                "rvemu-rs: {} is loaded at a bias of {:#x}, the built-in translation is not used",
+                name, m.mmu.prog_bias
+            );
+        }
+    }
+    return Ok(());
+}
+
+// the single-letter extensions in AT_HWCAP, one bit each: rv64imfdc, the
+// atomics are not emulated
+const HWCAP: u64 = 1 << (b'I' - b'A')
+    | 1 << (b'M' - b'A')
+    | 1 << (b'F' - b'A')
+    | 1 << (b'D' - b'A')
+    | 1 << (b'C' - b'A');
+
+// glibc's MINSIGSTKSZ for riscv64, enough for a signal frame without V
+const MINSIGSTKSZ: u64 = 2048;
+
+// USER_HZ, the unit of times(2); glibc's sysconf(_SC_CLK_TCK) returns it
+const CLKTCK: u64 = 100;
+
+// the initial process stack of the system v abi, as linux builds it:
+// argc, argv, envp and the auxv from sp up, the strings they point to at
+// the very top. argv[0] is the guest program.
+pub fn machine_setup(m: &mut Machine, argv: &[String], envp: &[String]) -> Result<(), String> {
+    // linux caps argv and envp at a quarter of the stack
+    let strings: u64 = argv.iter().chain(envp).map(|s| s.len() as u64 + 1).sum();
+    let pointers = (argv.len() + envp.len()) as u64 * 8;
+    if strings * 2 + pointers > m.mmu.stack_limit / 4 {
+        return Err("argument list too long".to_string());
+    }
+    m.argv = argv.to_vec();
+
+    let mut sp = m.mmu.stack_top;
+    let mut push_str = |m: &mut Machine, s: &[u8]| {
+        sp -= s.len() as u64 + 1;
+        mmu_copy_in(&mut m.mmu, sp, s).unwrap();
+        mmu_write(&mut m.mmu, sp + s.len() as u64, 0u8).unwrap();
+        return sp;
+    };
+    let execfn = push_str(m, argv[0].as_bytes());
+    let envs: Vec<u64> = envp.iter().map(|s| push_str(m, s.as_bytes())).collect();
+    let args: Vec<u64> = argv.iter().map(|s| push_str(m, s.as_bytes())).collect();
+
+    // AT_RANDOM, what glibc seeds the stack guard and pointer guard from
+    let mut random = [0u8; 16];
+    unsafe { libc::getrandom(random.as_mut_ptr() as *mut c_void, random.len(), 0) };
+    sp = round_down!(sp, 16) - random.len() as u64;
+    mmu_copy_in(&mut m.mmu, sp, &random).unwrap();
+    let random = sp;
+
+    let auxv = [
+        (AT_PHDR, m.mmu.phdr),
+        (AT_PHENT, size_of::<Phdr>() as u64),
+        (AT_PHNUM, m.mmu.phnum),
+        (AT_PAGESZ, page_size::get() as u64),
+        (AT_BASE, m.mmu.interp_base),
+        (AT_FLAGS, 0),
+        (AT_ENTRY, m.mmu.prog_entry),
+        (AT_UID, unsafe { libc::getuid() } as u64),
+        (AT_EUID, unsafe { libc::geteuid() } as u64),
+        (AT_GID, unsafe { libc::getgid() } as u64),
+        (AT_EGID, unsafe { libc::getegid() } as u64),
+        (AT_HWCAP, HWCAP),
+        (AT_CLKTCK, CLKTCK),
+        (AT_SECURE, 0),
+        (AT_RANDOM, random),
+        (AT_EXECFN, execfn),
+        (AT_MINSIGSTKSZ, MINSIGSTKSZ),
+        (AT_SYSINFO_EHDR, m.mmu.vdso),
+        (AT_NULL, 0),
+    ];
+
+    let words = 1 + args.len() + 1 + envs.len() + 1 + 2 * auxv.len();
+    sp = round_down!(sp - words as u64 * 8, 16);
+    let mut at = sp;
+    let mut push = |m: &mut Machine, val: u64| {
+        mmu_write(&mut m.mmu, at, val).unwrap();
+        at += 8;
+    };
+    push(m, args.len() as u64);
+    for addr in args.iter().chain([0].iter()) {
+        push(m, *addr);
+    }
+    for addr in envs.iter().chain([0].iter()) {
+        push(m, *addr);
+    }
+    for (key, val) in auxv {
+        push(m, key);
+        push(m, val);
+    }
+    m.state.gp_regs[GpRegTypeT::Sp as usize] = sp;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        elfdef::{
+            Ehdr, EI_CLASS, EI_DATA, ELFCLASS64, ELFDATA2LSB, ELFMAG, EM_RISCV, ET_DYN, PT_INTERP,
+            PT_LOAD,
+        },
+        rvemu::{machine_get_gp_reg, machine_set_gp_reg},
+        sys_call::{do_syscall, init_sys_call, init_sys_call_table, SYS_EXIT},
+    };
+    use std::{env, process, slice};
+
+    // a stand-in for ld.so: find AT_ENTRY, map /lib/libtest.so the way ld.so
+    // maps a library, call its entry and jump to the program with the result
+    // in s3
+    const LD_TEST: [u32; 70] = [
+        // _start:
+        0x00013283, // ld t0, 0(sp)
+        0x00329293, // slli t0, t0, 3
+        0x00510333, // add t1, sp, t0
+        0x01030313, // addi t1, t1, 16
+        // env:
+        0x00033383, // ld t2, 0(t1)
+        0x00830313, // addi t1, t1, 8
+        0xfe039ce3, // bne t2, zero, env
+        // aux:
+        0x00033383, // ld t2, 0(t1)
+        0x00833483, // ld s1, 8(t1)
+        0x01030313, // addi t1, t1, 16
+        0x0e038263, // beq t2, zero, fail
+        0x00900e13, // addi t3, zero, 9
+        0xffc396e3, // bne t2, t3, aux
+        0x00000597, // auipc a1, 0
+        0x0140006f, // jal zero, open
+        0x62696c2f, // "/lib"
+        0x62696c2f, // "/lib"
+        0x74736574, // "test"
+        0x006f732e, // ".so\0"
+        // open:
+        0x00858593, // addi a1, a1, 8
+        0xf9c00513, // addi a0, zero, -100
+        0x00000613, // addi a2, zero, 0
+        0x03800893, // addi a7, zero, 56
+        0x00000073, // ecall
+        0x0a054663, // blt a0, zero, fail
+        0x00050913, // addi s2, a0, 0
+        0xf8010113, // addi sp, sp, -128
+        0x00090513, // addi a0, s2, 0
+        0x00000597, // auipc a1, 0
+        0x0080006f, // jal zero, stat
+        0x00000000, // ""
+        // stat:
+        0x00858593, // addi a1, a1, 8
+        0x00010613, // addi a2, sp, 0
+        0x000016b7, // lui a3, 1
+        0x04f00893, // addi a7, zero, 79
+        0x00000073, // ecall
+        0x06051e63, // bne a0, zero, fail
+        0x03013a03, // ld s4, 48(sp)
+        0x00090513, // addi a0, s2, 0
+        0x00010593, // addi a1, sp, 0
+        0x04000613, // addi a2, zero, 64
+        0x00000693, // addi a3, zero, 0
+        0x04300893, // addi a7, zero, 67
+        0x00000073, // ecall
+        0x04000293, // addi t0, zero, 64
+        0x04551c63, // bne a0, t0, fail
+        0x01813a83, // ld s5, 24(sp)
+        0x08010113, // addi sp, sp, 128
+        0x00000513, // addi a0, zero, 0
+        0x000a0593, // addi a1, s4, 0
+        0x00500613, // addi a2, zero, 5
+        0x00200693, // addi a3, zero, 2
+        0x00090713, // addi a4, s2, 0
+        0x00000793, // addi a5, zero, 0
+        0x0de00893, // addi a7, zero, 222
+        0x00000073, // ecall
+        0x03451293, // slli t0, a0, 52
+        0x02029463, // bne t0, zero, fail
+        0x00050413, // addi s0, a0, 0
+        0x00090513, // addi a0, s2, 0
+        0x03900893, // addi a7, zero, 57
+        0x00000073, // ecall
+        0x00051a63, // bne a0, zero, fail
+        0x015402b3, // add t0, s0, s5
+        0x000280e7, // jalr ra, 0(t0)
+        0x00050993, // addi s3, a0, 0
+        0x00048067, // jalr zero, 0(s1)
+        // fail:
+        0x00100513, // addi a0, zero, 1
+        0x05d00893, // addi a7, zero, 93
+        0x00000073, // ecall
+    ];
+    // returns 41
+    const LIB_TEST: [u32; 2] = [
+        0x02900513, // addi a0, zero, 41
+        0x00008067, // jalr zero, 0(ra)
+    ];
+    // exits with the library's result plus one
+    const PROG_TEST: [u32; 3] = [
+        0x00198513, // addi a0, s3, 1
+        0x05d00893, // addi a7, zero, 93
+        0x00000073, // ecall
+    ];
+
+    // what static glibc and musl do before main: find AT_RANDOM and read
+    // it, set_tid_address, set_robust_list, block and restore the signals
+    // around their setup, and ask if stdout is a tty. exits with 42 when
+    // none of them fails.
+    const START_TEST: [u32; 52] = [
+        // _start:
+        0x00013283, // ld t0, 0(sp)
+        0x00329293, // slli t0, t0, 3
+        0x00510333, // add t1, sp, t0
+        0x01030313, // addi t1, t1, 16
+        // env:
+        0x00033383, // ld t2, 0(t1)
+        0x00830313, // addi t1, t1, 8
+        0xfe039ce3, // bne t2, zero, env
+        // aux:
+        0x00033383, // ld t2, 0(t1)
+        0x00833483, // ld s1, 8(t1)
+        0x01030313, // addi t1, t1, 16
+        0x08038e63, // beq t2, zero, fail
+        0x01900e13, // addi t3, zero, 25
+        0xffc396e3, // bne t2, t3, aux
+        0x0004b483, // ld s1, 0(s1)
+        0xfc010113, // addi sp, sp, -64
+        0x00010513, // addi a0, sp, 0
+        0x06000893, // addi a7, zero, 96
+        0x00000073, // ecall
+        0x06a05e63, // bge zero, a0, fail
+        0x00810513, // addi a0, sp, 8
+        0x01800593, // addi a1, zero, 24
+        0x06300893, // addi a7, zero, 99
+        0x00000073, // ecall
+        0x06051463, // bne a0, zero, fail
+        0xfff00293, // addi t0, zero, -1
+        0x00513023, // sd t0, 0(sp)
+        0x00000513, // addi a0, zero, 0
+        0x00010593, // addi a1, sp, 0
+        0x00810613, // addi a2, sp, 8
+        0x00800693, // addi a3, zero, 8
+        0x08700893, // addi a7, zero, 135
+        0x00000073, // ecall
+        0x04051263, // bne a0, zero, fail
+        0x00200513, // addi a0, zero, 2
+        0x00810593, // addi a1, sp, 8
+        0x00000613, // addi a2, zero, 0
+        0x00000073, // ecall
+        0x02051863, // bne a0, zero, fail
+        0x00100513, // addi a0, zero, 1
+        0x000055b7, // lui a1, 5
+        0x41358593, // addi a1, a1, 1043
+        0x01010613, // addi a2, sp, 16
+        0x01d00893, // addi a7, zero, 29
+        0x00000073, // ecall
+        0xfda00293, // addi t0, zero, -38
+        0x00550863, // beq a0, t0, fail
+        0x02a00513, // addi a0, zero, 42
+        0x05d00893, // addi a7, zero, 93
+        0x00000073, // ecall
+        // fail:
+        0x00100513, // addi a0, zero, 1
+        0x05d00893, // addi a7, zero, 93
+        0x00000073, // ecall
+    ];
+
+    // flw a single into ft0 and exit with it as a double's bits
+    const FLW_TEST: [u32; 6] = [
+        0x00000297, // auipc t0, 0
+        0x0142a007, // flw ft0, 20(t0)
+        0xe2000553, // fmv.x.d a0, ft0
+        0x05d00893, // addi a7, zero, 93
+        0x00000073, // ecall
+        0x3f800000, // 1.0
+    ];
+
+    fn as_bytes<T>(val: &T) -> &[u8] {
+        return unsafe { slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) };
+    }
+
+    // an ET_DYN with the code at 0x1000 in one PT_LOAD, after a PT_INTERP
+    // naming interp
+    fn test_elf(code: &[u32], interp: Option<&str>) -> Vec<u8> {
+        let mut phdrs = Vec::new();
+        let headers = (size_of::<Ehdr>() + 2 * size_of::<Phdr>()) as u64;
+        if let Some(interp) = interp {
+            phdrs.push(Phdr {
+                p_type: PT_INTERP,
+                p_flags: 4,
+                p_offset: headers,
+                p_vaddr: headers,
+                p_paddr: headers,
+                p_filesz: interp.len() as u64 + 1,
+                p_memsz: interp.len() as u64 + 1,
+                p_align: 1,
+            });
+        }
+        phdrs.push(Phdr {
+            p_type: PT_LOAD,
+            p_flags: 5,
+            p_offset: 0x1000,
+            p_vaddr: 0x1000,
+            p_paddr: 0x1000,
+            p_filesz: code.len() as u64 * 4,
+            p_memsz: code.len() as u64 * 4,
+            p_align: 0x1000,
+        });
+        let mut e_ident = [0u8; 16];
+        e_ident[..4].copy_from_slice(ELFMAG);
+        e_ident[EI_CLASS] = ELFCLASS64;
+        e_ident[EI_DATA] = ELFDATA2LSB;
+        e_ident[6] = 1;
+        let ehdr = Ehdr {
+            e_ident,
+            e_type: ET_DYN,
+            e_machine: EM_RISCV,
+            e_version: 1,
+            e_entry: 0x1000,
+            e_phoff: size_of::<Ehdr>() as u64,
+            e_shoff: 0,
+            e_flags: 0,
+            e_ehsiz: size_of::<Ehdr>() as u16,
+            e_phentsize: size_of::<Phdr>() as u16,
+            e_phnum: phdrs.len() as u16,
+            e_shentsize: 0,
+            e_shnum: 0,
+            e_shstrndx: 0,
+        };
+
+        let mut buf = as_bytes(&ehdr).to_vec();
+        for phdr in &phdrs {
+            buf.extend_from_slice(as_bytes(phdr));
+        }
+        if let Some(interp) = interp {
+            buf.resize(headers as usize, 0);
+            buf.extend_from_slice(interp.as_bytes());
+        }
+        buf.resize(0x1000, 0);
+        for insn in code {
+            buf.extend_from_slice(&insn.to_le_bytes());
+        }
+        return buf;
+    }
+
+    // a machine set up to run code as its program
+    fn test_machine(code: &[u32]) -> Machine {
+        let mut m = Machine::new();
+        machine_load_bytes(&mut m, &test_elf(code, None), "prog").unwrap();
+        machine_setup(&mut m, &["prog".to_string()], &[]).unwrap();
+        return m;
+    }
+
+    // run it the way main does, up to its exit
+    fn run(m: &mut Machine) -> u64 {
+        init_sys_call();
+        init_sys_call_table();
+        loop {
+            assert_eq!(machine_step(m), ExitReason::Ecall);
+            let n = machine_get_gp_reg(m, GpRegTypeT::A7 as i32);
+            if n == SYS_EXIT as u64 {
+                return machine_get_gp_reg(m, GpRegTypeT::A0 as i32);
+            }
+            let ret = do_syscall(m, n);
+            machine_set_gp_reg(m, GpRegTypeT::A0 as i32, ret);
+        }
+    }
+
+    #[test]
+    fn machine_interp_maps_library() {
+        let root = env::temp_dir().join(format!("rvemu-interp-{}", process::id()));
+        fs::create_dir_all(root.join("lib")).unwrap();
+        fs::write(root.join("lib/ld-test.so"), test_elf(&LD_TEST, None)).unwrap();
+        fs::write(root.join("lib/libtest.so"), test_elf(&LIB_TEST, None)).unwrap();
+
+        let mut m = Machine::new();
+        m.sysroot = Some(root.to_string_lossy().into_owned());
+        let prog = test_elf(&PROG_TEST, Some("/lib/ld-test.so"));
+        let loaded = machine_load_bytes(&mut m, &prog, "prog");
+        let setup = loaded.and_then(|_| machine_setup(&mut m, &["prog".to_string()], &[]));
+        let code = setup.map(|_| run(&mut m));
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(code, Ok(42));
+    }
+
+    #[test]
+    fn machine_libc_startup() {
+        let mut m = test_machine(&START_TEST);
+        assert_eq!(run(&mut m), 42);
+    }
+
+    #[test]
+    fn machine_fp_loads() {
+        let mut m = test_machine(&FLW_TEST);
+        assert_eq!(machine_step(&mut m), ExitReason::Ecall);
+        let a0 = machine_get_gp_reg(&m, GpRegTypeT::A0 as i32);
+        assert_eq!(a0, 0xffffffff_3f800000);
+
+        // flw and fld from the unmapped page at 0
+        for insn in [0x00002007, 0x00003007] {
+            let mut m = test_machine(&[insn, 0x00000073]);
+            assert_eq!(machine_step(&mut m), ExitReason::Fault);
+            assert_eq!(m.state.fault, Some(MmuFault::Load(0)));
+        }
+    }
 }
@@ -1,38 +1,287 @@
-use std::env;
+use std::{
+    env,
+    process::{self, exit},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use sys_call::{init_sys_call, init_sys_call_table};
 
 use crate::{
-    machine::{machine_load_program, machine_setup, machine_step},
+    bench::bench_decode,
+    firmware::firmware_format,
+    machine::{
+        machine_fault_exit, machine_load_program, machine_rebase, machine_redirect, machine_setup,
+        machine_step, machine_watch,
+    },
     reg::GpRegTypeT,
-    rvemu::{machine_get_gp_reg, machine_set_gp_reg, ExitReason, Machine},
+    rvemu::{machine_get_gp_reg, machine_set_gp_reg, ExitReason, Machine, Misaligned, Personality},
     sys_call::do_syscall,
+    translate::translate,
+    vfs::{vfs_deny, vfs_mount},
+    watch::watch_parse,
 };
 
+pub mod aot;
+pub mod bench;
+pub mod cache;
 pub mod decode;
 pub mod elfdef;
+pub mod fd;
+pub mod firmware;
+pub mod guard;
 pub mod interp;
 pub mod interp_utils;
+pub mod jit;
+pub mod linux;
 pub mod machine;
 pub mod mmu;
 pub mod reg;
 pub mod rvemu;
+pub mod softmmu;
+pub mod stats;
 pub mod sys_call;
+pub mod trace;
+pub mod translate;
+pub mod vdso;
+pub mod vfs;
+pub mod vma;
+pub mod watch;
+
+// the value of a --name=N option, decimal or 0x hex
+fn parse_num(opt: &str, name: &str, what: &str) -> u64 {
+    let val = &opt[name.len()..];
+    let num = match val.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => val.parse(),
+    };
+    return match num {
+        Ok(n) => n,
+        Err(_) => {
+            fatal!(format!("bad {}: {}", what, opt));
+            exit(1);
+        }
+    };
+}
+
+// a different layout every run, unless --aslr=SEED pins it
+fn aslr_seed() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    return now.as_nanos() as u64 ^ (process::id() as u64) << 32;
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let mut machine = Machine::new();
+
+    if args.len() > 1 && args[1] == "translate" {
+        if args.len() != 5 || args[3] != "-o" {
+            fatal!("usage: rvemu-rs translate <elf> -o <out.rs>");
+            exit(1);
+        }
+        match translate(&args[2], &args[4]) {
+            Ok(blocks) => eprintln!("translated {} blocks to {}", blocks, args[4]),
+            Err(e) => {
+                fatal!(e);
+                exit(1);
+            }
+        }
+        exit(0);
+    }
+
+    if args.len() > 1 && args[1] == "bench-decode" {
+        if args.len() != 3 {
+            fatal!("usage: rvemu-rs bench-decode <elf>");
+            exit(1);
+        }
+        if let Err(e) = bench_decode(&args[2]) {
+            fatal!(e);
+            exit(1);
+        }
+        exit(0);
+    }
+
+    // the guest gets the host environment, changed by -E and -U
+    let mut envp: Vec<String> = env::vars_os()
+        .map(|(k, v)| format!("{}={}", k.to_string_lossy(), v.to_string_lossy()))
+        .collect();
+
+    let mut i = 1;
+    while i < args.len() && args[i].starts_with("-") && args[i] != "-" {
+        match args[i].as_str() {
+            "-L" if i + 1 < args.len() => {
+                machine.sysroot = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "-E" if i + 1 < args.len() && args[i + 1].contains('=') => {
+                let name = args[i + 1].split('=').next().unwrap();
+                envp.retain(|var| var.split('=').next() != Some(name));
+                envp.push(args[i + 1].clone());
+                i += 1;
+            }
+            "-U" if i + 1 < args.len() => {
+                envp.retain(|var| var.split('=').next() != Some(&args[i + 1]));
+                i += 1;
+            }
+            "--jit" => machine.jit = true,
+            "--stats" => machine.stats.enabled = true,
+            "--maps" => machine.dump_maps = true,
+            opt if opt.starts_with("--watch=") => {
+                let watched =
+                    watch_parse(&opt["--watch=".len()..]).and_then(|(start, len, access)| {
+                        machine_watch(&mut machine, start, len, access)
+                    });
+                if let Err(e) = watched {
+                    fatal!(e);
+                    exit(1);
+                }
+            }
+            opt if opt.starts_with("--misaligned=") => {
+                machine.state.misaligned = match &opt["--misaligned=".len()..] {
+                    "emulate" => Misaligned::Emulate,
+                    "trap" => Misaligned::Trap,
+                    "report" => Misaligned::Report,
+                    _ => {
+                        fatal!(format!("bad misaligned policy: {}", opt));
+                        exit(1);
+                    }
+                }
+            }
+            "--aslr" => machine.mmu.aslr = Some(aslr_seed()),
+            opt if opt.starts_with("--aslr=") => {
+                machine.mmu.aslr = Some(parse_num(opt, "--aslr=", "seed"));
+            }
+            opt if opt.starts_with("--stack-size=") => {
+                machine.mmu.stack_limit = parse_num(opt, "--stack-size=", "stack size");
+            }
+            opt if opt.starts_with("--stack-gap=") => {
+                machine.mmu.stack_gap = parse_num(opt, "--stack-gap=", "stack gap");
+            }
+            opt if opt.starts_with("--personality=") => {
+                machine.personality = match &opt["--personality=".len()..] {
+                    "linux" => Some(Personality::Linux),
+                    "newlib" => Some(Personality::Newlib),
+                    _ => {
+                        fatal!(format!("bad personality: {}", opt));
+                        exit(1);
+                    }
+                }
+            }
+            opt if opt.starts_with("--format=") => {
+                machine.format = match firmware_format(&opt["--format=".len()..]) {
+                    Some(format) => Some(format),
+                    None => {
+                        fatal!(format!("bad format: {}", opt));
+                        exit(1);
+                    }
+                }
+            }
+            opt if opt.starts_with("--load-addr=") => {
+                machine.load_addr = Some(parse_num(opt, "--load-addr=", "load address"));
+            }
+            opt if opt.starts_with("--entry=") => {
+                machine.entry = Some(parse_num(opt, "--entry=", "entry"));
+            }
+            opt if opt.starts_with("--image=") => {
+                // FILE or FILE@ADDR
+                let image = &opt["--image=".len()..];
+                let (path, addr) = match image.rsplit_once('@') {
+                    Some((path, addr)) => (path, Some(parse_num(addr, "", "load address"))),
+                    None => (image, None),
+                };
+                machine.images.push((path.to_string(), addr));
+            }
+            opt if opt.starts_with("--stdin=")
+                || opt.starts_with("--stdout=")
+                || opt.starts_with("--stderr=") =>
+            {
+                let (name, path) = opt.split_once('=').unwrap();
+                let fd = match name {
+                    "--stdin" => 0,
+                    "--stdout" => 1,
+                    _ => 2,
+                };
+                if let Err(e) = machine_redirect(&mut machine, fd, path) {
+                    fatal!(e);
+                    exit(1);
+                }
+            }
+            opt if opt.starts_with("--root=") => {
+                if let Err(e) = vfs_mount(&mut machine.vfs, &opt["--root=".len()..], "/", false) {
+                    fatal!(e);
+                    exit(1);
+                }
+            }
+            opt if opt.starts_with("--bind=") || opt.starts_with("--ro-bind=") => {
+                // HOST:GUEST, or HOST where the guest sees it too
+                let (name, bind) = opt.split_once('=').unwrap();
+                let (host, guest) = bind.rsplit_once(':').unwrap_or((bind, bind));
+                let read_only = name == "--ro-bind";
+                if let Err(e) = vfs_mount(&mut machine.vfs, host, guest, read_only) {
+                    fatal!(e);
+                    exit(1);
+                }
+            }
+            opt if opt.starts_with("--deny=") => {
+                if let Err(e) = vfs_deny(&mut machine.vfs, &opt["--deny=".len()..]) {
+                    fatal!(e);
+                    exit(1);
+                }
+            }
+            opt if opt.starts_with("--jit-threshold=") => {
+                machine.jit_threshold = match opt["--jit-threshold=".len()..].parse() {
+                    Ok(n) => n,
+                    Err(_) => {
+                        fatal!(format!("bad threshold: {}", opt));
+                        exit(1);
+                    }
+                }
+            }
+            opt if opt.starts_with("--guest-base=") => {
+                let base = opt["--guest-base=".len()..].trim_start_matches("0x");
+                let rebased = u64::from_str_radix(base, 16)
+                    .map_err(|_| format!("bad guest base: {}", opt))
+                    .and_then(|base| machine_rebase(&mut machine, base));
+                if let Err(e) = rebased {
+                    fatal!(e);
+                    exit(1);
+                }
+            }
+            _ => {
+                fatal!(format!("unknown option: {}", args[i]));
+                exit(1);
+            }
+        }
+        i += 1;
+    }
+    args.drain(1..i);
     assert_eq!(args.len() > 1, true);
 
-    let mut machine = Machine::new();
-    machine_load_program(&mut machine, &args[1]);
-    machine_setup(&mut machine, args.len() as i32, args);
+    if let Err(e) = machine_load_program(&mut machine, &args[1]) {
+        fatal!(e);
+        exit(1);
+    }
+    if let Err(e) = machine_setup(&mut machine, &args[1..], &envp) {
+        fatal!(e);
+        exit(1);
+    }
     init_sys_call();
     init_sys_call_table();
 
     loop {
         let reason = machine_step(&mut machine);
+        if reason == ExitReason::Fault {
+            machine_fault_exit(&machine);
+        }
+        if reason == ExitReason::Watchpoint {
+            let hit = machine.state.watch_hit.take().unwrap();
+            eprintln!(
+                "rvemu-rs: watchpoint {}: {} of {} bytes at {:#x}, pc {:#x}: {:#x} -> {:#x}",
+                hit.index, hit.access, hit.size, hit.addr, hit.pc, hit.old, hit.new
+            );
+            continue;
+        }
         assert!(reason == ExitReason::Ecall);
-        let sys_call = machine_get_gp_reg(machine, GpRegTypeT::A7 as i32);
+        let sys_call = machine_get_gp_reg(&machine, GpRegTypeT::A7 as i32);
         let ret = do_syscall(&mut machine, sys_call);
         machine_set_gp_reg(&mut machine, GpRegTypeT::A0 as i32, ret);
     }
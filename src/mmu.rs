@@ -1,34 +1,91 @@
-use std::{
-    fs::File,
-    io::{Read, Seek, SeekFrom},
-    mem::size_of,
-    os::{fd::FromRawFd, raw::c_void},
-    slice,
-};
+use std::{ffi::CString, mem::size_of, os::raw::c_void, ptr};
 
-use libc::{mmap, munmap, MAP_ANONYMOUS, MAP_FIXED, MAP_PRIVATE};
+use libc::{
+    mmap, munmap, ENOMEM, MAP_ANONYMOUS, MAP_FAILED, MAP_FIXED, MAP_FIXED_NOREPLACE, MAP_NORESERVE,
+    MAP_PRIVATE,
+};
 
 use crate::{
     elfdef::{
-        Ehdr, Phdr, EI_CLASS, ELFCLASS64, ELFMAG, EM_RISCV, PF_R, PF_W, PF_X, PROT_EXEC, PROT_READ,
-        PROT_WRITE, PT_LOAD,
+        Ehdr, Phdr, EI_CLASS, EI_DATA, ELFCLASS64, ELFDATA2LSB, ELFMAG, EM_RISCV, ET_DYN, ET_EXEC,
+        PF_R, PF_W, PF_X, PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE, PT_GNU_STACK, PT_INTERP,
+        PT_LOAD, PT_PHDR, PT_TLS,
     },
-    fatal, max, round_down, round_up,
-    rvemu::{get_ptr, Mmu},
+    guard::{guard_register, guard_unregister, GUARD_SIZE},
+    round_down, round_up,
+    rvemu::{get_ptr, Mmu, MmuFault},
     to_guest, to_host,
+    vma::{
+        vma_elf_base, vma_find, vma_heap_grow, vma_heap_limit, vma_heap_shrink, vma_map, vma_place,
+        vma_protect, vma_range, LINUX_MAP_ANONYMOUS, LINUX_MAP_FIXED_NOREPLACE, LINUX_MAP_PRIVATE,
+    },
 };
 
-pub fn load_phdr(phdr: &mut Phdr, ehdr: &Ehdr, i: i64, file: &mut File) {
-    let size_phdr = size_of::<Phdr>();
-    let seek = SeekFrom::Start(ehdr.e_phoff + ((ehdr.e_phentsize as i64) * i) as u64);
-    if let Err(_) = file.seek(seek) {
-        fatal!("seek file failed");
-    }
+// size of the guest address space, the user half of sv39
+pub const GUEST_SPACE: u64 = 1 << 38;
 
-    let mut buf = unsafe { slice::from_raw_parts_mut(phdr as *mut Phdr as *mut u8, size_phdr) };
-    if let Err(_) = file.read(&mut buf) {
-        fatal!("file too small");
+// reserve the whole guest address space up front, at base or wherever the
+// host puts it, so nothing else in the process can land inside. segments
+// and allocations are then mapped over the reservation with MAP_FIXED.
+// the reservation is flanked by guard regions and registered with the
+// guard, so a wild access near it becomes a guest fault.
+pub fn mmu_reserve(base: u64) -> Result<u64, String> {
+    let len = GUEST_SPACE + 2 * GUARD_SIZE;
+    let mut flags = MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE;
+    let mut start = 0;
+    if base != 0 {
+        if base < GUARD_SIZE {
+            return Err(format!("cannot reserve guest memory at {:#x}", base));
+        }
+        flags |= MAP_FIXED_NOREPLACE;
+        start = base - GUARD_SIZE;
+    }
+    let ptr = unsafe {
+        mmap(
+            get_ptr(start) as *mut c_void,
+            len as usize,
+            PROT_NONE,
+            flags,
+            -1,
+            0,
+        )
+    };
+    if ptr == MAP_FAILED {
+        return Err(format!(
+            "cannot reserve guest memory at {:#x}: {}",
+            base,
+            std::io::Error::last_os_error()
+        ));
+    }
+    // kernels before 4.17 take MAP_FIXED_NOREPLACE as a hint
+    if base != 0 && ptr as u64 != start {
+        unsafe { munmap(ptr, len as usize) };
+        return Err(format!("cannot reserve guest memory at {:#x}", base));
     }
+    guard_register(ptr as u64, ptr as u64 + len);
+    return Ok(ptr as u64 + GUARD_SIZE);
+}
+
+// drop the reservation made by mmu_reserve, guards included
+pub fn mmu_release(host_base: u64) {
+    let start = host_base - GUARD_SIZE;
+    guard_unregister(start);
+    unsafe {
+        munmap(
+            get_ptr(start) as *mut c_void,
+            (GUEST_SPACE + 2 * GUARD_SIZE) as usize,
+        )
+    };
+}
+
+// move the reservation to base, only before anything is mapped into it
+pub fn mmu_rebase(mmu: &mut Mmu, base: u64) -> Result<(), String> {
+    assert!(mmu.host_alloc == mmu.host_base);
+    let host_base = mmu_reserve(base)?;
+    mmu_release(mmu.host_base);
+    mmu.host_base = host_base;
+    mmu.host_alloc = host_base;
+    return Ok(());
 }
 
 pub fn flags_to_mmap_prot(flags: i32) -> i32 {
@@ -38,108 +95,390 @@ pub fn flags_to_mmap_prot(flags: i32) -> i32 {
     return r | w | x;
 }
 
-pub fn mmu_load_segment(mmu: &mut Mmu, phdr: Phdr, fd: i32) {
-    let page_size = page_size::get();
-    let vaddr: u64 = to_host!(phdr.p_vaddr);
-    let aligned_vaddr: u64 = round_down!(vaddr, page_size);
-    let filesz = phdr.p_memsz + vaddr - aligned_vaddr;
-    let memsz = phdr.p_memsz + vaddr - aligned_vaddr;
-    let prot = flags_to_mmap_prot(phdr.p_flags as i32);
+// the header and program headers of an elf image, checked against buf so
+// the loader can index it freely
+pub fn mmu_parse_elf(buf: &[u8]) -> Result<(Ehdr, Vec<Phdr>), String> {
+    if buf.len() < size_of::<Ehdr>() || ELFMAG[..] != buf[..4] {
+        return Err("not an elf file".to_string());
+    }
+    let ehdr: Ehdr = unsafe { ptr::read_unaligned(buf.as_ptr() as *const Ehdr) };
+    if ehdr.e_ident[EI_CLASS] != ELFCLASS64
+        || ehdr.e_ident[EI_DATA] != ELFDATA2LSB
+        || ehdr.e_machine != EM_RISCV
+    {
+        return Err("only little-endian riscv64 elf files are supported".to_string());
+    }
+    if ehdr.e_type != ET_EXEC && ehdr.e_type != ET_DYN {
+        return Err(format!("elf type {} is not executable", ehdr.e_type));
+    }
+    if ehdr.e_phentsize as usize != size_of::<Phdr>() {
+        return Err(format!("bad program header size {}", ehdr.e_phentsize));
+    }
+    let table = (ehdr.e_phnum as u64)
+        .checked_mul(size_of::<Phdr>() as u64)
+        .and_then(|len| len.checked_add(ehdr.e_phoff));
+    if table.is_none_or(|end| end > buf.len() as u64) {
+        return Err("program headers out of file".to_string());
+    }
 
-    let ptr_align = get_ptr(aligned_vaddr);
-    let ptr = unsafe {
-        mmap(
-            ptr_align as *mut c_void,
-            filesz.try_into().unwrap(),
-            prot,
-            MAP_PRIVATE | MAP_FIXED,
-            fd,
-            round_down!(phdr.p_offset, page_size) as i64,
-        )
-    };
-    assert_eq!(ptr as u64, aligned_vaddr);
+    let mut phdrs = Vec::new();
+    for i in 0..ehdr.e_phnum as usize {
+        let off = ehdr.e_phoff as usize + i * size_of::<Phdr>();
+        let phdr: Phdr = unsafe { ptr::read_unaligned(buf[off..].as_ptr() as *const Phdr) };
+        if phdr.p_type == PT_TLS
+            && (phdr.p_filesz > phdr.p_memsz
+                || (phdr.p_align > 1 && !phdr.p_align.is_power_of_two()))
+        {
+            return Err(format!("bad tls segment {}", i));
+        }
+        if phdr.p_type != PT_LOAD {
+            phdrs.push(phdr);
+            continue;
+        }
+        let file_end = phdr.p_offset.checked_add(phdr.p_filesz);
+        let mem_end = phdr.p_vaddr.checked_add(phdr.p_memsz);
+        if phdr.p_filesz > phdr.p_memsz
+            || file_end.is_none_or(|end| end > buf.len() as u64)
+            || mem_end.is_none_or(|end| end > GUEST_SPACE)
+            || (phdr.p_align > 1 && !phdr.p_align.is_power_of_two())
+        {
+            return Err(format!("bad segment {} at {:#x}", i, phdr.p_vaddr));
+        }
+        phdrs.push(phdr);
+    }
 
-    let remianing_bss = round_up!(memsz, page_size) - round_up!(filesz, page_size);
-    if remianing_bss > 0 {
-        let ptr_align = get_ptr(aligned_vaddr + round_up!(filesz, page_size));
-        let ptr = unsafe {
-            mmap(
-                ptr_align as *mut c_void,
-                remianing_bss as usize,
-                prot,
-                MAP_ANONYMOUS | MAP_PRIVATE | MAP_FIXED,
-                -1,
-                0,
+    // the elf spec has loadable segments sorted by address
+    let mut end = 0;
+    for phdr in phdrs
+        .iter()
+        .filter(|p| p.p_type == PT_LOAD && p.p_memsz > 0)
+    {
+        if phdr.p_vaddr < end {
+            return Err(format!("overlapping segment at {:#x}", phdr.p_vaddr));
+        }
+        end = phdr.p_vaddr + phdr.p_memsz;
+    }
+    if end == 0 {
+        return Err("nothing to load".to_string());
+    }
+    return Ok((ehdr, phdrs));
+}
+
+// map the pages of a segment writable and copy its file bytes in. the
+// first page may already hold the end of the segment before it. the rest
+// of memsz is bss, left as the fresh zero pages.
+fn mmu_load_segment(mmu: &mut Mmu, phdr: &Phdr, buf: &[u8], name: &str) -> Result<(), String> {
+    let pz = page_size::get() as u64;
+    let mut start = round_down!(phdr.p_vaddr, pz);
+    let end = round_up!(phdr.p_vaddr + phdr.p_memsz, pz);
+    if let Some(prev) = vma_find(mmu, start) {
+        start = prev.end;
+    }
+
+    if start < end {
+        let flags = LINUX_MAP_PRIVATE | LINUX_MAP_ANONYMOUS | LINUX_MAP_FIXED_NOREPLACE;
+        vma_map(
+            mmu,
+            start,
+            end - start,
+            PROT_READ | PROT_WRITE,
+            flags,
+            -1,
+            0,
+        )
+        .map_err(|errno| {
+            format!(
+                "cannot map segment at {:#x}: {}",
+                phdr.p_vaddr,
+                std::io::Error::from_raw_os_error(errno)
             )
+        })?;
+        let vma = mmu.vmas.get_mut(&start).unwrap();
+        vma.anonymous = false;
+        vma.offset = if start < phdr.p_vaddr {
+            phdr.p_offset.saturating_sub(phdr.p_vaddr - start)
+        } else {
+            phdr.p_offset + (start - phdr.p_vaddr)
         };
-        assert_eq!(ptr as u64, aligned_vaddr + round_up!(filesz, page_size));
+        vma.name = name.to_string();
     }
 
-    mmu.host_alloc = max!(mmu.host_alloc, aligned_vaddr + round_up!(memsz, page_size));
-    mmu.alloc = to_guest!(mmu.host_alloc);
-    mmu.base = mmu.alloc;
+    let data = &buf[phdr.p_offset as usize..(phdr.p_offset + phdr.p_filesz) as usize];
+    mmu_copy_in(mmu, phdr.p_vaddr, data).unwrap();
+    return Ok(());
 }
 
-pub fn mmu_load_elf(mut mmu: &mut Mmu, fd: i32) {
-    const SIZE_EHDR: usize = size_of::<Ehdr>();
-    let mut buf: [u8; SIZE_EHDR] = [0; 64];
-    let mut file = unsafe { File::from_raw_fd(fd) };
+// lowest and highest page of the loadable segments
+pub fn mmu_elf_span(phdrs: &[Phdr]) -> (u64, u64) {
+    let pz = page_size::get() as u64;
+    let loads = phdrs
+        .iter()
+        .filter(|p| p.p_type == PT_LOAD && p.p_memsz > 0);
+    let lo = loads.clone().map(|p| p.p_vaddr).min().unwrap();
+    let hi = loads.map(|p| p.p_vaddr + p.p_memsz).max().unwrap();
+    return (round_down!(lo, pz), round_up!(hi, pz));
+}
 
-    let rs = file.read(&mut buf[..]);
-    if rs.unwrap() != SIZE_EHDR {
-        fatal!("file too small");
+// copy the loadable segments in, moved up by bias, and give them their
+// protections. returns the end of the highest one.
+fn mmu_load_image(
+    mmu: &mut Mmu,
+    buf: &[u8],
+    phdrs: &[Phdr],
+    bias: u64,
+    name: &str,
+) -> Result<u64, String> {
+    let pz = page_size::get() as u64;
+    let (_, hi) = mmu_elf_span(phdrs);
+    if hi.checked_add(bias).is_none_or(|end| end > GUEST_SPACE) {
+        return Err(format!("does not fit at {:#x}", bias));
     }
+    let loads: Vec<Phdr> = phdrs
+        .iter()
+        .filter(|p| p.p_type == PT_LOAD && p.p_memsz > 0)
+        .map(|p| Phdr {
+            p_vaddr: p.p_vaddr + bias,
+            ..*p
+        })
+        .collect();
 
-    let ehdr: Ehdr = unsafe { std::ptr::read(buf.as_ptr() as *const Ehdr) };
-    if !ELFMAG.eq(&buf[..4]) {
-        fatal!("bad elf file")
+    for phdr in loads.iter() {
+        mmu_load_segment(mmu, phdr, buf, name)?;
     }
 
-    if ehdr.e_machine != EM_RISCV || ehdr.e_ident[EI_CLASS] != ELFCLASS64 {
-        fatal!("only riscv64 elf file is supported");
+    // now the final protections, a page shared by two segments gets both
+    let mut prev: Option<(u64, i32)> = None;
+    for phdr in loads.iter() {
+        let start = round_down!(phdr.p_vaddr, pz);
+        let end = round_up!(phdr.p_vaddr + phdr.p_memsz, pz);
+        let mut prot = flags_to_mmap_prot(phdr.p_flags as i32);
+        vma_protect(mmu, start, end - start, prot).unwrap();
+        if let Some((prev_end, prev_prot)) = prev.filter(|&(prev_end, _)| prev_end > start) {
+            prot |= prev_prot;
+            vma_protect(mmu, start, prev_end - start, prot).unwrap();
+        }
+        prev = Some((end, flags_to_mmap_prot(phdr.p_flags as i32)));
     }
+    return Ok(hi + bias);
+}
 
-    mmu.entry = ehdr.e_entry;
+// the PT_INTERP path of an image, if it asks for a dynamic linker
+fn mmu_elf_interp(buf: &[u8], phdrs: &[Phdr]) -> Result<Option<String>, String> {
+    let phdr = match phdrs.iter().find(|p| p.p_type == PT_INTERP) {
+        Some(phdr) => phdr,
+        None => return Ok(None),
+    };
+    let end = phdr.p_offset.checked_add(phdr.p_filesz);
+    if phdr.p_filesz < 2 || end.is_none_or(|end| end > buf.len() as u64) {
+        return Err("bad interpreter path".to_string());
+    }
+    let path = &buf[phdr.p_offset as usize..end.unwrap() as usize - 1];
+    if buf[end.unwrap() as usize - 1] != 0 || path.contains(&0) {
+        return Err("bad interpreter path".to_string());
+    }
+    return Ok(Some(String::from_utf8_lossy(path).into_owned()));
+}
 
-    let mut phdr: Phdr = Phdr::new();
-    for i in 0..ehdr.e_phnum {
-        load_phdr(&mut phdr, &ehdr, i.into(), &mut file);
-        if phdr.p_type == PT_LOAD {
-            mmu_load_segment(&mut mmu, phdr, fd);
+// another elf image next to the program, a bootloader and what it boots:
+// at its own addresses, or an ET_DYN one at load_addr. it gets no dynamic
+// linker and no tls. returns its entry.
+pub fn mmu_load_elf_image(
+    mmu: &mut Mmu,
+    buf: &[u8],
+    load_addr: Option<u64>,
+    name: &str,
+) -> Result<u64, String> {
+    let pz = page_size::get() as u64;
+    let (ehdr, phdrs) = mmu_parse_elf(buf)?;
+    let bias = match (ehdr.e_type, load_addr) {
+        (ET_DYN, Some(addr)) if addr % pz == 0 => addr
+            .checked_sub(mmu_elf_span(&phdrs).0)
+            .ok_or(format!("cannot load at {:#x}", addr))?,
+        (ET_DYN, Some(addr)) => {
+            return Err(format!("load address {:#x} is not page aligned", addr))
+        }
+        (ET_DYN, None) => {
+            return Err("a position independent image needs a load address".to_string())
         }
+        (_, Some(_)) => return Err("only a position independent image can be moved".to_string()),
+        (_, None) => 0,
+    };
+    mmu_load_image(mmu, buf, &phdrs, bias, name)?;
+    return Ok(ehdr.e_entry + bias);
+}
+
+// load the program from memory by copying it in, so the file needs no
+// particular alignment and does not have to stay around. an ET_DYN
+// program goes at the load bias linux would pick, brk starts right after
+// it. returns the dynamic linker it asks for, see mmu_load_interp.
+pub fn mmu_load_elf(mmu: &mut Mmu, buf: &[u8], name: &str) -> Result<Option<String>, String> {
+    let (ehdr, phdrs) = mmu_parse_elf(buf)?;
+    let interp = mmu_elf_interp(buf, &phdrs)?;
+    let bias = if ehdr.e_type == ET_DYN {
+        vma_elf_base(mmu) - mmu_elf_span(&phdrs).0
+    } else {
+        0
+    };
+    let end = mmu_load_image(mmu, buf, &phdrs, bias, name)?;
+
+    // where the program headers ended up, for AT_PHDR
+    let phdr = match phdrs.iter().find(|p| p.p_type == PT_PHDR) {
+        Some(p) => p.p_vaddr,
+        None => phdrs
+            .iter()
+            .find(|p| {
+                p.p_type == PT_LOAD
+                    && ehdr.e_phoff >= p.p_offset
+                    && ehdr.e_phoff < p.p_offset + p.p_filesz
+            })
+            .map_or(0, |p| p.p_vaddr + (ehdr.e_phoff - p.p_offset)),
+    };
+
+    mmu.tls = phdrs
+        .iter()
+        .find(|p| p.p_type == PT_TLS && p.p_memsz > 0 && interp.is_none())
+        .map(|p| Phdr {
+            p_vaddr: p.p_vaddr + bias,
+            ..*p
+        });
+    mmu.exec_stack = phdrs
+        .iter()
+        .any(|p| p.p_type == PT_GNU_STACK && p.p_flags as i32 & PF_X != 0);
+    mmu.entry = ehdr.e_entry + bias;
+    mmu.prog_entry = mmu.entry;
+    mmu.prog_bias = bias;
+    mmu.phdr = if phdr != 0 { phdr + bias } else { 0 };
+    mmu.phnum = ehdr.e_phnum as u64;
+    mmu.base = end;
+    mmu.alloc = end;
+    mmu.host_alloc = to_host!(mmu.host_base, end);
+    return Ok(interp);
+}
+
+// load the dynamic linker wherever mmap would put it, and start there
+// instead of at the program entry. it finds the program from the auxv.
+pub fn mmu_load_interp(mmu: &mut Mmu, buf: &[u8], name: &str) -> Result<(), String> {
+    let (ehdr, phdrs) = mmu_parse_elf(buf)?;
+    if mmu_elf_interp(buf, &phdrs)?.is_some() {
+        return Err("the interpreter has an interpreter".to_string());
     }
+    let (lo, hi) = mmu_elf_span(&phdrs);
+    let bias = if ehdr.e_type == ET_DYN {
+        vma_place(mmu, hi - lo).ok_or("no room for the interpreter".to_string())? - lo
+    } else {
+        0
+    };
+    mmu_load_image(mmu, buf, &phdrs, bias, name)?;
+    mmu.interp_base = bias;
+    mmu.entry = ehdr.e_entry + bias;
+    return Ok(());
 }
 
-pub fn mmu_alloc(mmu: &mut Mmu, sz: i64) -> u64 {
+// move the break by sz and hand back the old one. the pages up to the new
+// break are mapped and the ones past it given back. ENOMEM, with the break
+// left alone, when that does not fit below the next mapping.
+pub fn mmu_alloc(mmu: &mut Mmu, sz: i64) -> Result<u64, i32> {
     let pz = page_size::get();
     let base = mmu.alloc;
     assert!(base >= mmu.base);
 
-    mmu.alloc += sz as u64;
-    assert!(mmu.alloc >= mmu.base);
-    if sz > 0 && mmu.alloc > to_guest!(mmu.host_alloc) {
+    let alloc = base.wrapping_add(sz as u64);
+    if (sz < 0 && alloc < mmu.base) || (sz > 0 && alloc < base) {
+        return Err(ENOMEM);
+    }
+    let host_end = to_guest!(mmu.host_base, mmu.host_alloc);
+    let end = round_up!(alloc, pz);
+    if end > host_end {
+        if end > vma_heap_limit(mmu) {
+            return Err(ENOMEM);
+        }
         let ptr = get_ptr(mmu.host_alloc);
         let ret = unsafe {
             mmap(
                 ptr as *mut c_void,
-                round_up!(sz, pz) as usize,
+                (end - host_end) as usize,
                 (PROT_READ | PROT_WRITE) as i32,
-                MAP_ANONYMOUS | MAP_PRIVATE,
+                MAP_ANONYMOUS | MAP_PRIVATE | MAP_FIXED,
                 -1i32,
                 0,
             )
         };
-        if ret.is_null() {
-            fatal!("mmap failed!")
+        if ret == MAP_FAILED {
+            return Err(ENOMEM);
         }
-        mmu.host_alloc += round_up!(sz, pz);
-    } else if sz < 0 && round_up!(mmu.alloc, pz) < to_guest!(mmu.host_alloc) {
-        let len = to_guest!(mmu.host_alloc) - round_up!(mmu.alloc, pz);
-        let ptr = get_ptr(mmu.alloc);
-        if unsafe { munmap(ptr as *mut c_void, len as usize) } == -1 {
-            fatal!("munmap failed!")
+        mmu.host_alloc += end - host_end;
+        vma_heap_grow(mmu, host_end, end);
+    } else if end < host_end {
+        // hand the pages back but keep the range reserved
+        let len = host_end - end;
+        let ptr = get_ptr(to_host!(mmu.host_base, end));
+        let ret = unsafe {
+            mmap(
+                ptr as *mut c_void,
+                len as usize,
+                PROT_NONE,
+                MAP_ANONYMOUS | MAP_PRIVATE | MAP_FIXED | MAP_NORESERVE,
+                -1i32,
+                0,
+            )
+        };
+        if ret == MAP_FAILED {
+            return Err(ENOMEM);
         }
         mmu.host_alloc -= len;
+        vma_heap_shrink(mmu, end, host_end);
+    }
+    mmu.alloc = alloc;
+    return Ok(base);
+}
+
+// first address of [addr, addr + len) that is not mapped with prot
+pub fn mmu_check(mmu: &Mmu, addr: u64, len: u64, prot: i32) -> Result<(), u64> {
+    let end = addr.checked_add(len).ok_or(addr)?;
+    let mut addr = addr;
+    while addr < end {
+        addr = match vma_range(mmu, addr) {
+            Some((end, have)) if have & prot == prot => end,
+            _ => return Err(addr),
+        };
+    }
+    return Ok(());
+}
+
+pub fn mmu_copy_out(mmu: &Mmu, addr: u64, buf: &mut [u8]) -> Result<(), MmuFault> {
+    mmu_check(mmu, addr, buf.len() as u64, PROT_READ).map_err(MmuFault::Load)?;
+    let ptr = get_ptr(to_host!(mmu.host_base, addr));
+    unsafe { ptr.copy_to_nonoverlapping(buf.as_mut_ptr(), buf.len()) };
+    return Ok(());
+}
+
+pub fn mmu_copy_in(mmu: &mut Mmu, addr: u64, buf: &[u8]) -> Result<(), MmuFault> {
+    mmu_check(mmu, addr, buf.len() as u64, PROT_WRITE).map_err(MmuFault::Store)?;
+    let ptr = get_ptr(to_host!(mmu.host_base, addr));
+    unsafe { ptr.copy_from_nonoverlapping(buf.as_ptr(), buf.len()) };
+    return Ok(());
+}
+
+pub fn mmu_read<T: Copy>(mmu: &Mmu, addr: u64) -> Result<T, MmuFault> {
+    mmu_check(mmu, addr, size_of::<T>() as u64, PROT_READ).map_err(MmuFault::Load)?;
+    let ptr = get_ptr(to_host!(mmu.host_base, addr)) as *const T;
+    return Ok(unsafe { ptr::read_unaligned(ptr) });
+}
+
+pub fn mmu_write<T: Copy>(mmu: &mut Mmu, addr: u64, val: T) -> Result<(), MmuFault> {
+    mmu_check(mmu, addr, size_of::<T>() as u64, PROT_WRITE).map_err(MmuFault::Store)?;
+    let ptr = get_ptr(to_host!(mmu.host_base, addr)) as *mut T;
+    unsafe { ptr::write_unaligned(ptr, val) };
+    return Ok(());
+}
+
+pub fn mmu_read_cstr(mmu: &Mmu, addr: u64) -> Result<CString, MmuFault> {
+    let mut buf = Vec::new();
+    loop {
+        let c: u8 = mmu_read(mmu, addr + buf.len() as u64)?;
+        if c == 0 {
+            return Ok(CString::new(buf).unwrap());
+        }
+        buf.push(c);
     }
-    return base;
 }
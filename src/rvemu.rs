@@ -1,10 +1,21 @@
+use std::collections::BTreeMap;
+
 use crate::{
+    cache::Cache,
+    elfdef::Phdr,
+    fd::FdTable,
+    firmware::Format,
+    linux::{Sigaction, LINUX_NSIG},
+    machine::JIT_THRESHOLD,
+    mmu::{mmu_release, mmu_reserve, GUEST_SPACE},
     reg::{FpRegT, FpRegTypeT, GpRegTypeT},
-    to_host,
+    stats::Stats,
+    vfs::Vfs,
+    vma::{Vma, STACK_GAP, STACK_LIMIT},
+    watch::{WatchHit, Watchpoint, MAX_WATCHPOINTS},
 };
-use std::ptr;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InsnType {
     InsnLb,
     InsnLh,
@@ -142,7 +153,19 @@ pub enum InsnType {
     NumInsns,
 }
 
-#[derive(Debug, Clone, Copy)]
+// adjacent instruction pairs the decoder dispatches as one operation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fusion {
+    None,
+    LuiAddi,
+    AuipcJalr,
+    AuipcLd,
+    SlliSrli,
+    MulhMul,
+    NumFusions,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Insn {
     pub rd: i8,
     pub rs1: i8,
@@ -171,31 +194,119 @@ impl Insn {
     }
 }
 
-#[derive(Clone, Copy)]
 pub struct Mmu {
+    // host address of guest address 0, the start of the reservation
+    pub host_base: u64,
     pub entry: u64,
     pub host_alloc: u64,
     pub alloc: u64,
     pub base: u64,
+    // guest mappings by start address, loaded segments included
+    pub vmas: BTreeMap<u64, Vma>,
+    // RLIMIT_STACK, all of it is mapped up front
+    pub stack_limit: u64,
+    // kept free below the stack, linux stack_guard_gap
+    pub stack_gap: u64,
+    // randomize the layout from this seed, advanced on every use
+    pub aslr: Option<u64>,
+    // set by vma_layout: the initial sp and where mmap starts looking down
+    pub stack_top: u64,
+    pub mmap_base: u64,
+    // for the auxv: the program entry and headers, and the load bias of
+    // the dynamic linker, 0 without one
+    pub prog_entry: u64,
+    pub phdr: u64,
+    pub phnum: u64,
+    pub interp_base: u64,
+    // the load bias of the program, 0 unless it is ET_DYN
+    pub prog_bias: u64,
+    // the PT_TLS of a static program, moved by its load bias, and whether
+    // its PT_GNU_STACK asks for an executable stack
+    pub tls: Option<Phdr>,
+    pub exec_stack: bool,
+    // where the vdso is mapped, for AT_SYSINFO_EHDR
+    pub vdso: u64,
 }
 
 impl Mmu {
     pub fn new() -> Mmu {
+        let host_base = mmu_reserve(0).unwrap();
         Mmu {
+            host_base,
             entry: 0,
-            host_alloc: 0,
+            host_alloc: host_base,
             alloc: 0,
             base: 0,
+            vmas: BTreeMap::new(),
+            stack_limit: STACK_LIMIT,
+            stack_gap: STACK_GAP,
+            aslr: None,
+            stack_top: GUEST_SPACE,
+            mmap_base: GUEST_SPACE,
+            prog_entry: 0,
+            phdr: 0,
+            phnum: 0,
+            interp_base: 0,
+            prog_bias: 0,
+            tls: None,
+            exec_stack: false,
+            vdso: 0,
         }
     }
 }
 
+impl Drop for Mmu {
+    fn drop(&mut self) {
+        mmu_release(self.host_base);
+    }
+}
+
+// an access that touched unmapped guest memory or memory without the right
+// permission, with the first bad address
 #[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MmuFault {
+    Load(u64),
+    Store(u64),
+    Fetch(u64),
+    // not naturally aligned, under Misaligned::Trap
+    LoadMisaligned(u64),
+    StoreMisaligned(u64),
+}
+
+// what to do with a load/store that is not naturally aligned. jalr targets
+// are left out: jalr, fused with auipc or not, clears bit 0 of the target
+// and with the C extension every even address is a valid one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Misaligned {
+    // do the access, like linux emulating it in its trap handler
+    Emulate,
+    // raise an address-misaligned exception, like strict hardware
+    Trap,
+    // do the access, but count and log it with its pc
+    Report,
+}
+
+// the syscall conventions of the c library a program was built with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Personality {
+    // glibc or musl: the linux riscv64 abi
+    Linux,
+    // newlib with libgloss: linux numbers for the most part, but newlib's
+    // open flags and errno values and the old numbers from 1024 up
+    Newlib,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
 pub enum ExitReason {
     None,
     DirectBranch,
     IndirectBranch,
     Ecall,
+    // a guest load/store hit unmapped memory, see State::fault
+    Fault,
+    // a watchpoint triggered, see State::watch_hit
+    Watchpoint,
 }
 
 #[allow(dead_code)]
@@ -212,6 +323,27 @@ pub struct State {
     pub gp_regs: [u64; GpRegTypeT::NumGpRegS as usize],
     pub pc: u64,
     pub reenter_pc: u64,
+    // copy of Mmu::host_base for the insn handlers
+    pub host_base: u64,
+    // set along with ExitReason::Fault
+    pub fault: Option<MmuFault>,
+    pub misaligned: Misaligned,
+    // misaligned accesses seen under Misaligned::Report
+    pub misaligned_count: u64,
+    pub watchpoints: [Option<Watchpoint>; MAX_WATCHPOINTS],
+    // any watchpoint is set, the only check on the fast path
+    pub watching: bool,
+    // set along with ExitReason::Watchpoint
+    pub watch_hit: Option<WatchHit>,
+    // pc of the insn that stopped on an exec watchpoint, it runs on resume
+    pub watch_resume: Option<u64>,
+    // the range of guest memory that is writable and has code the cache
+    // decoded in it, a store there ends the block
+    pub code_start: u64,
+    pub code_end: u64,
+    // guest code that changed under the cache, dropped from it before the
+    // guest carries on
+    pub stale: Option<(u64, u64)>,
     pub fp_regs: [FpRegT; FpRegTypeT::NumFpRegs as usize],
 }
 
@@ -222,22 +354,83 @@ impl State {
             gp_regs: [0; GpRegTypeT::NumGpRegS as usize],
             pc: 0,
             reenter_pc: 0,
+            host_base: 0,
+            fault: None,
+            misaligned: Misaligned::Emulate,
+            misaligned_count: 0,
+            watchpoints: [None; MAX_WATCHPOINTS],
+            watching: false,
+            watch_hit: None,
+            watch_resume: None,
+            code_start: u64::MAX,
+            code_end: 0,
+            stale: None,
             fp_regs: [FpRegT::new(); FpRegTypeT::NumFpRegs as usize],
         }
     }
 }
 
-#[derive(Clone, Copy)]
 pub struct Machine {
     pub state: State,
     pub mmu: Mmu,
+    pub cache: Cache,
+    pub jit: bool,
+    pub jit_threshold: u64,
+    pub aot: bool,
+    pub stats: Stats,
+    // print the guest mappings when the guest exits
+    pub dump_maps: bool,
+    // -L: where absolute guest paths are looked up first
+    pub sysroot: Option<String>,
+    // --format: what the program is, detected when not given
+    pub format: Option<Format>,
+    // --load-addr: where a raw program goes, or how far hex and srec
+    // records are moved
+    pub load_addr: Option<u64>,
+    // --entry: where to start instead of the entry of the program
+    pub entry: Option<u64>,
+    // --image: more images loaded next to the program, each at its own
+    // load address
+    pub images: Vec<(String, Option<u64>)>,
+    // --personality, or what sys_personality makes of the program
+    pub personality: Option<Personality>,
+    // the guest's argv, for getmainvars
+    pub argv: Vec<String>,
+    // what the guest's fds are on the host
+    pub fds: FdTable,
+    // --root, --bind, --ro-bind and --deny: the files the guest can see
+    pub vfs: Vfs,
+    // what rt_sigaction and rt_sigprocmask set. signals are never
+    // delivered, the guest only reads back what it set.
+    pub sigactions: [Sigaction; LINUX_NSIG],
+    pub sigmask: u64,
 }
 
 impl Machine {
     pub fn new() -> Machine {
+        let mut state = State::new();
+        let mmu = Mmu::new();
+        state.host_base = mmu.host_base;
         Machine {
-            state: State::new(),
-            mmu: Mmu::new(),
+            state,
+            mmu,
+            cache: Cache::new(),
+            jit: false,
+            jit_threshold: JIT_THRESHOLD,
+            aot: false,
+            stats: Stats::new(),
+            dump_maps: false,
+            sysroot: None,
+            format: None,
+            load_addr: None,
+            entry: None,
+            images: Vec::new(),
+            personality: None,
+            argv: Vec::new(),
+            fds: FdTable::new(),
+            vfs: Vfs::new(),
+            sigactions: [Sigaction::default(); LINUX_NSIG],
+            sigmask: 0,
         }
     }
 }
@@ -249,12 +442,7 @@ macro_rules! fatal {
     };
 }
 
-pub fn mmu_write(addr: u64, data: *const u8, len: usize) {
-    let ptr = get_ptr(to_host!(addr));
-    unsafe { ptr.copy_from(data, len) }
-}
-
-pub fn machine_get_gp_reg(m: Machine, reg: i32) -> u64 {
+pub fn machine_get_gp_reg(m: &Machine, reg: i32) -> u64 {
     assert!(reg >= 0 && reg <= GpRegTypeT::NumGpRegS as i32);
     return m.state.gp_regs[reg as usize];
 }
@@ -266,6 +454,5 @@ pub fn machine_set_gp_reg(m: &mut Machine, reg: i32, data: u64) {
 
 #[inline]
 pub fn get_ptr(addr: u64) -> *mut u8 {
-    let ptr: *mut u8 = ptr::null_mut();
-    unsafe { ptr.add(addr as usize) }
+    addr as usize as *mut u8
 }
@@ -0,0 +1,547 @@
+use crate::{
+    mmu::{mmu_read, mmu_write},
+    rvemu::Mmu,
+};
+
+// satp.MODE, bits 63:60
+pub const SATP_MODE_BARE: u64 = 0;
+pub const SATP_MODE_SV39: u64 = 8;
+pub const SATP_MODE_SV48: u64 = 9;
+pub const SATP_MODE_SV57: u64 = 10;
+
+const SATP_ASID_SHIFT: u64 = 44;
+const SATP_ASID_MASK: u64 = 0xffff;
+const SATP_PPN_MASK: u64 = (1 << 44) - 1;
+
+pub const PTE_V: u64 = 1 << 0;
+pub const PTE_R: u64 = 1 << 1;
+pub const PTE_W: u64 = 1 << 2;
+pub const PTE_X: u64 = 1 << 3;
+pub const PTE_U: u64 = 1 << 4;
+pub const PTE_G: u64 = 1 << 5;
+pub const PTE_A: u64 = 1 << 6;
+pub const PTE_D: u64 = 1 << 7;
+
+const PTE_PPN_SHIFT: u64 = 10;
+const PTE_PPN_MASK: u64 = (1 << 44) - 1;
+// bits 63:54: N (svnapot), PBMT (svpbmt) and reserved, none of which are
+// implemented, so all must be zero
+const PTE_HIGH_MASK: u64 = 0x3ff << 54;
+
+const PAGE_SHIFT: u64 = 12;
+const LEVEL_BITS: u64 = 9;
+
+// exception causes, written to scause/mcause by whoever takes the trap
+pub const CAUSE_FETCH_ACCESS: u64 = 1;
+pub const CAUSE_LOAD_ACCESS: u64 = 5;
+pub const CAUSE_STORE_ACCESS: u64 = 7;
+pub const CAUSE_FETCH_PAGE_FAULT: u64 = 12;
+pub const CAUSE_LOAD_PAGE_FAULT: u64 = 13;
+pub const CAUSE_STORE_PAGE_FAULT: u64 = 15;
+
+const TLB_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priv {
+    User,
+    Supervisor,
+    Machine,
+}
+
+// what the translated address is used for. AMOs and sc are stores, lr is a
+// load.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+// a failed translation, cause and tval of the exception to raise
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MmuTrap {
+    pub cause: u64,
+    pub tval: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TlbEntry {
+    valid: bool,
+    // va >> (12 + 9 * level), so a superpage is one entry
+    tag: u64,
+    level: u64,
+    asid: u16,
+    // the leaf pte, permissions are checked on every hit since priv, SUM
+    // and MXR change without an sfence.vma
+    pte: u64,
+}
+
+impl TlbEntry {
+    const fn new() -> TlbEntry {
+        TlbEntry {
+            valid: false,
+            tag: 0,
+            level: 0,
+            asid: 0,
+            pte: 0,
+        }
+    }
+}
+
+pub struct SoftMmu {
+    pub satp: u64,
+    pub prv: Priv,
+    // mstatus.SUM: supervisor may load/store user pages
+    pub sum: bool,
+    // mstatus.MXR: loads from execute-only pages succeed
+    pub mxr: bool,
+    // direct mapped by the low bits of the 4k vpn
+    tlb: [TlbEntry; TLB_SIZE],
+    pub tlb_hits: u64,
+    pub tlb_misses: u64,
+}
+
+impl SoftMmu {
+    pub fn new() -> SoftMmu {
+        SoftMmu {
+            satp: 0,
+            prv: Priv::Machine,
+            sum: false,
+            mxr: false,
+            tlb: [TlbEntry::new(); TLB_SIZE],
+            tlb_hits: 0,
+            tlb_misses: 0,
+        }
+    }
+}
+
+impl Default for SoftMmu {
+    fn default() -> SoftMmu {
+        return SoftMmu::new();
+    }
+}
+
+// page table levels of a satp mode, 0 for bare
+fn softmmu_levels(mode: u64) -> u64 {
+    return match mode {
+        SATP_MODE_SV39 => 3,
+        SATP_MODE_SV48 => 4,
+        SATP_MODE_SV57 => 5,
+        _ => 0,
+    };
+}
+
+pub fn softmmu_mode(smmu: &SoftMmu) -> u64 {
+    return smmu.satp >> 60;
+}
+
+pub fn softmmu_asid(smmu: &SoftMmu) -> u16 {
+    return ((smmu.satp >> SATP_ASID_SHIFT) & SATP_ASID_MASK) as u16;
+}
+
+// satp is WARL: a write with an unsupported mode has no effect. like
+// hardware, the tlb is not flushed, that takes an sfence.vma.
+pub fn softmmu_set_satp(smmu: &mut SoftMmu, satp: u64) -> bool {
+    let mode = satp >> 60;
+    if mode != SATP_MODE_BARE && softmmu_levels(mode) == 0 {
+        return false;
+    }
+    smmu.satp = satp;
+    return true;
+}
+
+fn softmmu_trap(access: Access, page_fault: bool, va: u64) -> MmuTrap {
+    let cause = match (access, page_fault) {
+        (Access::Fetch, true) => CAUSE_FETCH_PAGE_FAULT,
+        (Access::Load, true) => CAUSE_LOAD_PAGE_FAULT,
+        (Access::Store, true) => CAUSE_STORE_PAGE_FAULT,
+        (Access::Fetch, false) => CAUSE_FETCH_ACCESS,
+        (Access::Load, false) => CAUSE_LOAD_ACCESS,
+        (Access::Store, false) => CAUSE_STORE_ACCESS,
+    };
+    return MmuTrap { cause, tval: va };
+}
+
+// may the leaf pte be used for access at the current privilege
+fn softmmu_allowed(smmu: &SoftMmu, pte: u64, access: Access) -> bool {
+    let user = pte & PTE_U != 0;
+    let ok = match smmu.prv {
+        Priv::User => user,
+        // supervisor never executes user pages, SUM only covers load/store
+        Priv::Supervisor => !user || (smmu.sum && access != Access::Fetch),
+        Priv::Machine => true,
+    };
+    return ok
+        && match access {
+            Access::Fetch => pte & PTE_X != 0,
+            Access::Load => pte & PTE_R != 0 || (smmu.mxr && pte & PTE_X != 0),
+            Access::Store => pte & PTE_W != 0,
+        };
+}
+
+fn softmmu_pa(pte: u64, level: u64, va: u64) -> u64 {
+    let shift = PAGE_SHIFT + LEVEL_BITS * level;
+    let base = ((pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK) << PAGE_SHIFT;
+    return (base & !((1 << shift) - 1)) | (va & ((1 << shift) - 1));
+}
+
+// the hardware page table walk of the privileged spec, 4.3.2, with
+// hardware A/D updates (svadu). returns the leaf pte and its level.
+fn softmmu_walk(
+    smmu: &SoftMmu,
+    mmu: &mut Mmu,
+    va: u64,
+    access: Access,
+) -> Result<(u64, u64), MmuTrap> {
+    let levels = softmmu_levels(softmmu_mode(smmu));
+    let page_fault = softmmu_trap(access, true, va);
+
+    // bits above the va must all be copies of its top bit
+    let va_bits = PAGE_SHIFT + LEVEL_BITS * levels;
+    if ((va as i64) << (64 - va_bits) >> (64 - va_bits)) as u64 != va {
+        return Err(page_fault);
+    }
+
+    let mut table = (smmu.satp & SATP_PPN_MASK) << PAGE_SHIFT;
+    let mut level = levels - 1;
+    // G on a pointer makes everything below it global
+    let mut global = 0;
+    loop {
+        let vpn = (va >> (PAGE_SHIFT + LEVEL_BITS * level)) & ((1 << LEVEL_BITS) - 1);
+        let pte_addr = table + vpn * 8;
+        let pte: u64 = mmu_read(mmu, pte_addr).map_err(|_| softmmu_trap(access, false, va))?;
+
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte & PTE_HIGH_MASK != 0 {
+            return Err(page_fault);
+        }
+
+        if pte & (PTE_R | PTE_X) == 0 {
+            // pointer to the next level, A, D and U are reserved here
+            if pte & (PTE_A | PTE_D | PTE_U) != 0 || level == 0 {
+                return Err(page_fault);
+            }
+            global |= pte & PTE_G;
+            table = ((pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK) << PAGE_SHIFT;
+            level -= 1;
+            continue;
+        }
+
+        if !softmmu_allowed(smmu, pte, access) {
+            return Err(page_fault);
+        }
+        // a superpage must be aligned to its size
+        let low = (1 << (LEVEL_BITS * level)) - 1;
+        if (pte >> PTE_PPN_SHIFT) & low != 0 {
+            return Err(page_fault);
+        }
+
+        let mut new = pte | PTE_A;
+        if access == Access::Store {
+            new |= PTE_D;
+        }
+        if new != pte {
+            mmu_write(mmu, pte_addr, new).map_err(|_| softmmu_trap(access, false, va))?;
+        }
+        return Ok((new | global, level));
+    }
+}
+
+fn softmmu_tlb_index(va: u64) -> usize {
+    return ((va >> PAGE_SHIFT) as usize) % TLB_SIZE;
+}
+
+fn softmmu_tlb_match(entry: &TlbEntry, va: u64, asid: u16) -> bool {
+    return entry.valid
+        && entry.tag == va >> (PAGE_SHIFT + LEVEL_BITS * entry.level)
+        && (entry.pte & PTE_G != 0 || entry.asid == asid);
+}
+
+// translate va for access, the physical address is a guest address of mmu
+pub fn softmmu_translate(
+    smmu: &mut SoftMmu,
+    mmu: &mut Mmu,
+    va: u64,
+    access: Access,
+) -> Result<u64, MmuTrap> {
+    if smmu.prv == Priv::Machine || softmmu_mode(smmu) == SATP_MODE_BARE {
+        return Ok(va);
+    }
+
+    let asid = softmmu_asid(smmu);
+    let index = softmmu_tlb_index(va);
+    let entry = smmu.tlb[index];
+    // a store through a clean entry walks again to set D
+    if softmmu_tlb_match(&entry, va, asid) && (access != Access::Store || entry.pte & PTE_D != 0) {
+        if !softmmu_allowed(smmu, entry.pte, access) {
+            return Err(softmmu_trap(access, true, va));
+        }
+        smmu.tlb_hits += 1;
+        return Ok(softmmu_pa(entry.pte, entry.level, va));
+    }
+
+    smmu.tlb_misses += 1;
+    let (pte, level) = softmmu_walk(smmu, mmu, va, access)?;
+    smmu.tlb[index] = TlbEntry {
+        valid: true,
+        tag: va >> (PAGE_SHIFT + LEVEL_BITS * level),
+        level,
+        asid,
+        pte,
+    };
+    return Ok(softmmu_pa(pte, level, va));
+}
+
+// sfence.vma rs1, rs2: x0 for rs1 is every address, x0 for rs2 is every
+// address space. global mappings are only flushed when no asid is given.
+pub fn softmmu_sfence_vma(smmu: &mut SoftMmu, va: Option<u64>, asid: Option<u16>) {
+    for entry in smmu.tlb.iter_mut() {
+        let va_hit = match va {
+            Some(va) => entry.tag == va >> (PAGE_SHIFT + LEVEL_BITS * entry.level),
+            None => true,
+        };
+        let asid_hit = match asid {
+            Some(asid) => entry.pte & PTE_G == 0 && entry.asid == asid,
+            None => true,
+        };
+        if va_hit && asid_hit {
+            entry.valid = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        elfdef::{PROT_READ, PROT_WRITE},
+        vma::{vma_map, LINUX_MAP_ANONYMOUS, LINUX_MAP_FIXED, LINUX_MAP_PRIVATE},
+    };
+
+    // the page tables live in guest memory of mmu, which is physical
+    // memory here
+    const ROOT: u64 = 0x100000;
+    const L1: u64 = 0x101000;
+    const L0: u64 = 0x102000;
+    const ROOT48: u64 = 0x103000;
+    const PAGE: u64 = 0x110000;
+
+    fn pte(pa: u64, flags: u64) -> u64 {
+        return (pa >> PAGE_SHIFT) << PTE_PPN_SHIFT | flags | PTE_V;
+    }
+
+    fn set_pte(mmu: &mut Mmu, table: u64, vpn: u64, pte: u64) {
+        mmu_write(mmu, table + vpn * 8, pte).unwrap();
+    }
+
+    fn get_pte(mmu: &Mmu, table: u64, vpn: u64) -> u64 {
+        return mmu_read(mmu, table + vpn * 8).unwrap();
+    }
+
+    // sv39 from ROOT, and sv48 from ROOT48 with ROOT below it, so the same
+    // va below 1 << 38 maps the same way in both:
+    //   0x400000   4k page at PAGE through L1[2] and L0[0]
+    //   0x600000   2M superpage at 0x200000, L1[3]
+    //   0x800000   2M superpage at 0x201000, misaligned, L1[4]
+    //   0x40000000 1G superpage at 0x40000000, ROOT[1]
+    //   0x80000000 1G superpage at 0x200000, misaligned, ROOT[2]
+    fn softmmu_setup(mode: u64) -> (SoftMmu, Mmu) {
+        let mut mmu = Mmu::new();
+        let flags = LINUX_MAP_PRIVATE | LINUX_MAP_ANONYMOUS | LINUX_MAP_FIXED;
+        vma_map(
+            &mut mmu,
+            ROOT,
+            0x100000,
+            PROT_READ | PROT_WRITE,
+            flags,
+            -1,
+            0,
+        )
+        .unwrap();
+        let rwx = PTE_R | PTE_W | PTE_X;
+        set_pte(&mut mmu, ROOT, 0, pte(L1, 0));
+        set_pte(&mut mmu, ROOT, 1, pte(0x40000000, rwx));
+        set_pte(&mut mmu, ROOT, 2, pte(0x200000, rwx));
+        set_pte(&mut mmu, L1, 2, pte(L0, 0));
+        set_pte(&mut mmu, L1, 3, pte(0x200000, rwx));
+        set_pte(&mut mmu, L1, 4, pte(0x201000, rwx));
+        set_pte(&mut mmu, L0, 0, pte(PAGE, PTE_R | PTE_W));
+        set_pte(&mut mmu, ROOT48, 0, pte(ROOT, 0));
+
+        let mut smmu = SoftMmu::new();
+        let root = if mode == SATP_MODE_SV48 { ROOT48 } else { ROOT };
+        assert!(softmmu_set_satp(&mut smmu, mode << 60 | root >> PAGE_SHIFT));
+        smmu.prv = Priv::Supervisor;
+        return (smmu, mmu);
+    }
+
+    fn page_fault(cause: u64, va: u64) -> Result<u64, MmuTrap> {
+        return Err(MmuTrap { cause, tval: va });
+    }
+
+    #[test]
+    fn softmmu_levels_and_superpages() {
+        for mode in [SATP_MODE_SV39, SATP_MODE_SV48] {
+            let (mut smmu, mut mmu) = softmmu_setup(mode);
+            let mut load = |va| softmmu_translate(&mut smmu, &mut mmu, va, Access::Load);
+            assert_eq!(load(0x400123), Ok(PAGE + 0x123));
+            assert_eq!(load(0x612345), Ok(0x212345));
+            assert_eq!(load(0x40123456), Ok(0x40123456));
+            // a superpage pte with ppn bits below its size set
+            assert_eq!(load(0x800000), page_fault(CAUSE_LOAD_PAGE_FAULT, 0x800000));
+            assert_eq!(
+                load(0x80000000),
+                page_fault(CAUSE_LOAD_PAGE_FAULT, 0x80000000)
+            );
+            // nothing at ROOT[3]
+            assert_eq!(
+                load(0xc0000000),
+                page_fault(CAUSE_LOAD_PAGE_FAULT, 0xc0000000)
+            );
+            assert_ne!(get_pte(&mmu, L0, 0) & PTE_A, 0);
+            assert_eq!(get_pte(&mmu, L0, 0) & PTE_D, 0);
+        }
+
+        // the va must be sign extended from bit 38 under sv39, 47 under sv48
+        let va = 1 << 40;
+        let (mut smmu, mut mmu) = softmmu_setup(SATP_MODE_SV39);
+        let ret = softmmu_translate(&mut smmu, &mut mmu, va, Access::Load);
+        assert_eq!(ret, page_fault(CAUSE_LOAD_PAGE_FAULT, va));
+        let (mut smmu, mut mmu) = softmmu_setup(SATP_MODE_SV48);
+        let ret = softmmu_translate(&mut smmu, &mut mmu, va, Access::Load);
+        assert_eq!(ret, page_fault(CAUSE_LOAD_PAGE_FAULT, va));
+        let ret = softmmu_translate(&mut smmu, &mut mmu, 1 << 47, Access::Load);
+        assert_eq!(ret, page_fault(CAUSE_LOAD_PAGE_FAULT, 1 << 47));
+
+        // unsupported modes leave satp alone
+        assert!(!softmmu_set_satp(&mut smmu, 5 << 60));
+        assert_eq!(softmmu_mode(&smmu), SATP_MODE_SV48);
+    }
+
+    #[test]
+    fn softmmu_permissions() {
+        let (mut smmu, mut mmu) = softmmu_setup(SATP_MODE_SV39);
+        let user = 0x400000;
+        let exec = 0x401000;
+        set_pte(&mut mmu, L0, 0, pte(PAGE, PTE_R | PTE_W | PTE_X | PTE_U));
+        set_pte(&mut mmu, L0, 1, pte(PAGE, PTE_X));
+        let mut translate = |smmu: &mut SoftMmu, va, access| {
+            return softmmu_translate(smmu, &mut mmu, va, access);
+        };
+
+        // supervisor only gets at user pages with SUM, and never runs them
+        let fault = page_fault(CAUSE_LOAD_PAGE_FAULT, user);
+        assert_eq!(translate(&mut smmu, user, Access::Load), fault);
+        smmu.sum = true;
+        assert_eq!(translate(&mut smmu, user, Access::Load), Ok(PAGE));
+        assert_eq!(translate(&mut smmu, user, Access::Store), Ok(PAGE));
+        let fault = page_fault(CAUSE_FETCH_PAGE_FAULT, user);
+        assert_eq!(translate(&mut smmu, user, Access::Fetch), fault);
+        // SUM is checked on a tlb hit too
+        smmu.sum = false;
+        let fault = page_fault(CAUSE_STORE_PAGE_FAULT, user);
+        assert_eq!(translate(&mut smmu, user, Access::Store), fault);
+
+        // user mode only gets at user pages
+        smmu.prv = Priv::User;
+        assert_eq!(translate(&mut smmu, user, Access::Fetch), Ok(PAGE));
+        let fault = page_fault(CAUSE_FETCH_PAGE_FAULT, exec);
+        assert_eq!(translate(&mut smmu, exec, Access::Fetch), fault);
+
+        // execute-only pages can be loaded from with MXR
+        smmu.prv = Priv::Supervisor;
+        assert_eq!(translate(&mut smmu, exec, Access::Fetch), Ok(PAGE));
+        let fault = page_fault(CAUSE_LOAD_PAGE_FAULT, exec);
+        assert_eq!(translate(&mut smmu, exec, Access::Load), fault);
+        smmu.mxr = true;
+        assert_eq!(translate(&mut smmu, exec, Access::Load), Ok(PAGE));
+
+        // machine mode does not translate
+        smmu.prv = Priv::Machine;
+        assert_eq!(translate(&mut smmu, 1 << 40, Access::Store), Ok(1 << 40));
+    }
+
+    #[test]
+    fn softmmu_dirty_rewalk() {
+        let (mut smmu, mut mmu) = softmmu_setup(SATP_MODE_SV39);
+        let va = 0x400008;
+        assert_eq!(
+            softmmu_translate(&mut smmu, &mut mmu, va, Access::Load),
+            Ok(PAGE + 8)
+        );
+        assert_eq!(get_pte(&mmu, L0, 0) & PTE_D, 0);
+        assert_eq!(
+            softmmu_translate(&mut smmu, &mut mmu, va, Access::Load),
+            Ok(PAGE + 8)
+        );
+        assert_eq!((smmu.tlb_hits, smmu.tlb_misses), (1, 1));
+
+        // the entry is clean, a store walks again and sets D
+        assert_eq!(
+            softmmu_translate(&mut smmu, &mut mmu, va, Access::Store),
+            Ok(PAGE + 8)
+        );
+        assert_eq!((smmu.tlb_hits, smmu.tlb_misses), (1, 2));
+        assert_ne!(get_pte(&mmu, L0, 0) & PTE_D, 0);
+        assert_eq!(
+            softmmu_translate(&mut smmu, &mut mmu, va, Access::Store),
+            Ok(PAGE + 8)
+        );
+        assert_eq!((smmu.tlb_hits, smmu.tlb_misses), (2, 2));
+
+        // the walk sees a pte turned read-only under the clean entry
+        let va = 0x612345;
+        assert_eq!(
+            softmmu_translate(&mut smmu, &mut mmu, va, Access::Load),
+            Ok(0x212345)
+        );
+        set_pte(&mut mmu, L1, 3, pte(0x200000, PTE_R | PTE_A));
+        let fault = page_fault(CAUSE_STORE_PAGE_FAULT, va);
+        assert_eq!(
+            softmmu_translate(&mut smmu, &mut mmu, va, Access::Store),
+            fault
+        );
+    }
+
+    #[test]
+    fn softmmu_sfence() {
+        let (mut smmu, mut mmu) = softmmu_setup(SATP_MODE_SV39);
+        let page = 0x400000;
+        // not in the same direct mapped tlb slot as page
+        let global = 0x40001000;
+        set_pte(&mut mmu, ROOT, 1, pte(0x40000000, PTE_R | PTE_G));
+        smmu.satp |= 1 << SATP_ASID_SHIFT;
+        assert_eq!(softmmu_asid(&smmu), 1);
+        let load = |smmu: &mut SoftMmu, mmu: &mut Mmu, va| {
+            return softmmu_translate(smmu, mmu, va, Access::Load).unwrap();
+        };
+        load(&mut smmu, &mut mmu, page);
+        load(&mut smmu, &mut mmu, global);
+
+        // the tlb keeps the old mapping until an sfence.vma for it
+        set_pte(&mut mmu, L0, 0, pte(PAGE + 0x1000, PTE_R));
+        set_pte(&mut mmu, ROOT, 1, pte(0x80000000, PTE_R | PTE_G));
+        assert_eq!(load(&mut smmu, &mut mmu, page), PAGE);
+        softmmu_sfence_vma(&mut smmu, Some(global), None);
+        assert_eq!(load(&mut smmu, &mut mmu, page), PAGE);
+        assert_eq!(load(&mut smmu, &mut mmu, global), 0x80001000);
+
+        // another asid does not see asid 1's entries, global ones it does
+        smmu.satp = smmu.satp & !(SATP_ASID_MASK << SATP_ASID_SHIFT) | 2 << SATP_ASID_SHIFT;
+        set_pte(&mut mmu, ROOT, 1, pte(0xc0000000, PTE_R | PTE_G));
+        assert_eq!(load(&mut smmu, &mut mmu, page), PAGE + 0x1000);
+        assert_eq!(load(&mut smmu, &mut mmu, global), 0x80001000);
+        softmmu_sfence_vma(&mut smmu, Some(global), None);
+        assert_eq!(load(&mut smmu, &mut mmu, global), 0xc0001000);
+
+        // flushing an asid keeps the global entries, even ones it filled
+        set_pte(&mut mmu, L0, 0, pte(PAGE + 0x2000, PTE_R));
+        set_pte(&mut mmu, ROOT, 1, pte(0x100000000, PTE_R | PTE_G));
+        softmmu_sfence_vma(&mut smmu, None, Some(2));
+        assert_eq!(load(&mut smmu, &mut mmu, page), PAGE + 0x2000);
+        assert_eq!(load(&mut smmu, &mut mmu, global), 0xc0001000);
+
+        // flushing everything does not
+        softmmu_sfence_vma(&mut smmu, None, None);
+        assert_eq!(load(&mut smmu, &mut mmu, global), 0x100001000);
+    }
+}
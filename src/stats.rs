@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use crate::rvemu::Fusion;
+
+pub struct Stats {
+    pub enabled: bool,
+    pub aot_blocks: u64,
+    pub interp_blocks: u64,
+    pub jit_blocks: u64,
+    pub promoted: u64,
+    pub compiled: u64,
+    pub traced: u64,
+    pub fused: [u64; Fusion::NumFusions as usize],
+    pub aot_time: Duration,
+    pub interp_time: Duration,
+    pub jit_time: Duration,
+    pub compile_time: Duration,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            enabled: false,
+            aot_blocks: 0,
+            interp_blocks: 0,
+            jit_blocks: 0,
+            promoted: 0,
+            compiled: 0,
+            traced: 0,
+            fused: [0; Fusion::NumFusions as usize],
+            aot_time: Duration::ZERO,
+            interp_time: Duration::ZERO,
+            jit_time: Duration::ZERO,
+            compile_time: Duration::ZERO,
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        return Stats::new();
+    }
+}
+
+fn percent(part: Duration, total: Duration) -> f64 {
+    if total.is_zero() {
+        return 0.0;
+    }
+    return part.as_secs_f64() * 100.0 / total.as_secs_f64();
+}
+
+pub fn stats_report(stats: &Stats) {
+    let total = stats.aot_time + stats.interp_time + stats.jit_time + stats.compile_time;
+
+    eprintln!("rvemu-rs stats:");
+    eprintln!("  blocks promoted:  {}", stats.promoted);
+    eprintln!("  blocks in traces: {}", stats.traced);
+    for kind in [
+        Fusion::LuiAddi,
+        Fusion::AuipcJalr,
+        Fusion::AuipcLd,
+        Fusion::SlliSrli,
+        Fusion::MulhMul,
+    ] {
+        let name = format!("{:?}:", kind);
+        eprintln!("  fused {:<10} {}", name, stats.fused[kind as usize]);
+    }
+    eprintln!(
+        "  aot:     {:>12} blocks  {:>6.2}% of time",
+        stats.aot_blocks,
+        percent(stats.aot_time, total)
+    );
+    eprintln!(
+        "  interp:  {:>12} blocks  {:>6.2}% of time",
+        stats.interp_blocks,
+        percent(stats.interp_time, total)
+    );
+    eprintln!(
+        "  jit:     {:>12} blocks  {:>6.2}% of time",
+        stats.jit_blocks,
+        percent(stats.jit_time, total)
+    );
+    eprintln!(
+        "  compile: {:>12} blocks  {:>6.2}% of time",
+        stats.compiled,
+        percent(stats.compile_time, total)
+    );
+}
@@ -1,19 +1,61 @@
-use std::{os::raw::c_void, process::exit, ptr};
+use std::{
+    env,
+    ffi::{CString, OsStr},
+    fs, io, mem,
+    os::unix::ffi::OsStrExt,
+    process::exit,
+};
 
 use libc::{
-    c_char, close, gettimeofday, lseek, open, openat, read, stat, timeval, timezone, O_APPEND,
-    O_CREAT, O_EXCL, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY,
+    clock_getres, clock_gettime, fcntl, gettimeofday, lseek, openat, stat, timespec, timeval,
+    timezone, AT_FDCWD, AT_REMOVEDIR, AT_SYMLINK_FOLLOW, AT_SYMLINK_NOFOLLOW, EFAULT, EINVAL,
+    ENODEV, ENOENT, ENOMEM, ENOSYS, ENOTDIR, ENOTTY, EPERM, ERANGE, ESPIPE, F_GETFL, F_SETFL,
+    O_ACCMODE, O_APPEND, O_CLOEXEC, O_CREAT, O_DIRECTORY, O_EXCL, O_NOCTTY, O_NOFOLLOW, O_NONBLOCK,
+    O_RDONLY, O_RDWR, O_SYNC, O_TRUNC, O_WRONLY, PATH_MAX, S_IFIFO, W_OK,
 };
 use rvemu_rs::rewrite_flag;
 
 use crate::{
-    fatal,
-    mmu::mmu_alloc,
-    reg::GpRegTypeT::{A0, A1, A2, A7},
-    rvemu::{get_ptr, machine_get_gp_reg, Machine},
-    to_host,
+    elfdef::{
+        elf_read, Nhdr, Shdr, Sym, EI_OSABI, ELFOSABI_LINUX, NT_GNU_ABI_TAG, PROT_READ, PROT_WRITE,
+        PT_INTERP, PT_NOTE, SHT_SYMTAB,
+    },
+    fd::{fd_close, fd_dup, fd_dup3, fd_get, fd_host, fd_new, fd_read, fd_write, File},
+    linux::{
+        host_open_flags, host_rlimit, linux_errno, linux_open_flags, linux_rlimit, linux_rusage,
+        linux_stat, linux_timespec, linux_timeval, linux_utsname, Iovec, Rlimit, Sigaction,
+        Sigcontext, Statx, Termios, LINUX_AT_REMOVEDIR, LINUX_AT_SYMLINK_FOLLOW, LINUX_FD_CLOEXEC,
+        LINUX_FLUSH_ICACHE_LOCAL, LINUX_F_DUPFD, LINUX_F_DUPFD_CLOEXEC, LINUX_F_GETFD,
+        LINUX_F_GETFL, LINUX_F_SETFD, LINUX_F_SETFL, LINUX_NSIG, LINUX_O_CLOEXEC,
+        LINUX_ROBUST_LIST_HEAD_SIZE, LINUX_SIGFRAME_MCONTEXT, LINUX_SIGKILL, LINUX_SIGSTOP,
+        LINUX_SIG_BLOCK, LINUX_SIG_SETMASK, LINUX_SIG_UNBLOCK, LINUX_TCGETS, LINUX_TIOCGWINSZ,
+        MAX_RW_COUNT, RLIMIT_STACK, RLIM_NLIMITS, UIO_MAXIOV,
+    },
+    machine::{machine_fault_exit, machine_invalidate, machine_path, machine_report},
+    mmu::{
+        mmu_alloc, mmu_check, mmu_copy_in, mmu_copy_out, mmu_parse_elf, mmu_read, mmu_read_cstr,
+        mmu_write, GUEST_SPACE,
+    },
+    reg::{
+        FpRegT,
+        GpRegTypeT::{Sp, A0, A1, A2, A3, A4, A5, A7},
+    },
+    round_up,
+    rvemu::{machine_get_gp_reg, Machine, MmuFault, Personality},
+    vfs::{vfs_enabled, vfs_resolve, vfs_writable},
+    vma::{
+        vma_has_exec, vma_heap_limit, vma_map, vma_protect, vma_remap, vma_unmap,
+        LINUX_MAP_ANONYMOUS, LINUX_MAP_FIXED,
+    },
 };
 
+#[cfg(not(target_os = "linux"))]
+use crate::linux::linux_statx_from_stat;
+#[cfg(target_os = "linux")]
+use crate::linux::{linux_statx, linux_sysinfo, linux_termios};
+#[cfg(target_os = "macos")]
+use std::os::raw::c_void;
+
 pub const SYS_EXIT: usize = 93;
 pub const SYS_EXIT_GROUP: usize = 94;
 pub const SYS_GETPID: usize = 172;
@@ -48,8 +90,10 @@ pub const SYS_MUNMAP: usize = 215;
 pub const SYS_MREMAP: usize = 216;
 pub const SYS_MPROTECT: usize = 226;
 pub const SYS_PRLIMIT64: usize = 261;
+pub const SYS_RISCV_FLUSH_ICACHE: usize = 259;
 pub const SYS_GETMAINVARS: usize = 2011;
 pub const SYS_RT_SIGACTION: usize = 134;
+pub const SYS_READV: usize = 65;
 pub const SYS_WRITEV: usize = 66;
 pub const SYS_GETTIMEOFDAY: usize = 169;
 pub const SYS_TIMES: usize = 153;
@@ -65,6 +109,9 @@ pub const SYS_GETRLIMIT: usize = 163;
 pub const SYS_SETRLIMIT: usize = 164;
 pub const SYS_GETRUSAGE: usize = 165;
 pub const SYS_CLOCK_GETTIME: usize = 113;
+pub const SYS_CLOCK_GETRES: usize = 114;
+pub const SYS_GETCPU: usize = 168;
+pub const SYS_RT_SIGRETURN: usize = 139;
 pub const SYS_SET_TID_ADDRESS: usize = 96;
 pub const SYS_SET_ROBUST_LIST: usize = 99;
 pub const SYS_MADVISE: usize = 233;
@@ -83,97 +130,546 @@ pub const SYS_TIME: usize = 1062;
 #[macro_export]
 macro_rules! get {
     ($reg:tt, $name:ident, $m:ident) => {
-        let $name: u64 = machine_get_gp_reg(*$m, $reg as i32);
+        let $name: u64 = machine_get_gp_reg($m, $reg as i32);
     };
 }
 
 #[macro_export]
 macro_rules! get_mut {
     ($reg:tt, $name:ident, $m:ident) => {
-        let mut $name: u64 = machine_get_gp_reg(*$m, $reg as i32);
+        let mut $name: u64 = machine_get_gp_reg($m, $reg as i32);
     };
 }
 
-#[macro_export]
-macro_rules! vec {
-    ( $( $x:expr ),* ) => {
-        {
-            let mut temp_vec = Vec::new();
-            $(
-                temp_vec.push($x);
-            )*
-            temp_vec
-        }
+// a bad guest pointer fails the syscall the way it does on linux
+fn sys_fault(_fault: MmuFault) -> u64 {
+    return -EFAULT as u64;
+}
+
+fn sys_result(ret: Result<u64, i32>) -> u64 {
+    return match ret {
+        Ok(val) => val,
+        Err(errno) => -linux_errno(errno) as u64,
+    };
+}
+
+// the guest sees -errno where the host libc returns -1 and sets errno
+fn sys_host(ret: i64) -> u64 {
+    if ret < 0 {
+        let errno = io::Error::last_os_error().raw_os_error().unwrap();
+        return -linux_errno(errno) as u64;
+    }
+    return ret as u64;
+}
+
+// a path argument as the host takes it: relative to dir_fd, or with a
+// sandbox an absolute host path and the guest path it was resolved to
+struct HostPath {
+    dir_fd: i32,
+    name: CString,
+    guest: Option<String>,
+}
+
+// a path argument relative to dir_fd. under a sandbox it is resolved in
+// the guest's view, follow for a symlink at the end and write for a call
+// that changes what the path names. otherwise shared libraries come from
+// the sysroot.
+fn sys_path_at(
+    m: &Machine,
+    dir_fd: u64,
+    ptr: u64,
+    follow: bool,
+    write: bool,
+) -> Result<HostPath, u64> {
+    let name = mmu_read_cstr(&m.mmu, ptr).map_err(sys_fault)?;
+    // AT_EMPTY_PATH, the call is on dir_fd itself
+    let empty = name.as_bytes().is_empty() && dir_fd as i32 != AT_FDCWD;
+    if empty || !vfs_enabled(&m.vfs) {
+        let name = match name.to_str() {
+            Ok(path) if !path.is_empty() => CString::new(machine_path(m, path)).unwrap(),
+            _ => name,
+        };
+        let dir_fd = sys_dir_fd(m, dir_fd)?;
+        return Ok(HostPath {
+            dir_fd,
+            name,
+            guest: None,
+        });
+    }
+
+    let path = name.to_str().map_err(|_| -ENOENT as u64)?;
+    let base = match fd_get(&m.fds, dir_fd) {
+        _ if path.starts_with('/') || dir_fd as i32 == AT_FDCWD => m.vfs.cwd.clone(),
+        Ok(file) => match &*file.borrow() {
+            File::Host {
+                path: Some(path), ..
+            } => path.clone(),
+            _ => return Err(-ENOTDIR as u64),
+        },
+        Err(errno) => return Err(sys_result(Err(errno))),
     };
+    let resolved = vfs_resolve(&m.vfs, &base, path, follow)
+        .and_then(|resolved| match write {
+            true => vfs_writable(&resolved).map(|_| resolved),
+            false => Ok(resolved),
+        })
+        .map_err(|errno| sys_result(Err(errno)))?;
+    let name = CString::new(resolved.host.as_os_str().as_bytes()).unwrap();
+    return Ok(HostPath {
+        dir_fd: AT_FDCWD,
+        name,
+        guest: Some(resolved.guest),
+    });
+}
+
+fn sys_path(m: &Machine, ptr: u64, follow: bool, write: bool) -> Result<HostPath, u64> {
+    return sys_path_at(m, AT_FDCWD as u64, ptr, follow, write);
 }
 
 pub fn sys_unimplemented(m: &mut Machine) -> u64 {
-    fatal!(format!(
-        "unimplemented syscall: {}",
-        machine_get_gp_reg(*m, A7 as i32)
-    ));
-    0
+    eprintln!(
+        "rvemu-rs: unimplemented syscall: {}",
+        machine_get_gp_reg(m, A7 as i32)
+    );
+    return -ENOSYS as u64;
 }
 
 #[allow(dead_code)]
 pub fn sys_exit(m: &mut Machine) -> u64 {
     get!(A0, code, m);
+    machine_report(m);
     exit(code as i32);
 }
 
+// a guest dirfd as a host one, AT_FDCWD being the emulator's cwd
+fn sys_dir_fd(m: &Machine, dir_fd: u64) -> Result<i32, u64> {
+    if dir_fd as i32 == AT_FDCWD {
+        return Ok(AT_FDCWD);
+    }
+    return fd_host(&m.fds, dir_fd, ENOTDIR).map_err(|errno| sys_result(Err(errno)));
+}
+
+// a host fd the guest just opened, under the lowest free guest fd. the
+// host side is always close-on-exec, the guest's O_CLOEXEC goes in the
+// table.
+fn sys_new_fd(m: &mut Machine, host_fd: i32, path: Option<String>, cloexec: bool) -> u64 {
+    if host_fd < 0 {
+        return sys_host(host_fd as i64);
+    }
+    let file = File::Host {
+        fd: host_fd,
+        owned: true,
+        path,
+    };
+    return sys_result(fd_new(&mut m.fds, file, cloexec).map(|fd| fd as u64));
+}
+
 pub fn sys_close(m: &mut Machine) -> u64 {
     get!(A0, fd, m);
-    if fd > 2 {
-        return unsafe { close(0) as u64 };
+    return sys_result(fd_close(&mut m.fds, fd).map(|_| 0));
+}
+
+pub fn sys_dup(m: &mut Machine) -> u64 {
+    get!(A0, fd, m);
+    return sys_result(fd_dup(&mut m.fds, fd, 0, false).map(|fd| fd as u64));
+}
+
+pub fn sys_dup3(m: &mut Machine) -> u64 {
+    get!(A0, old, m);
+    get!(A1, new, m);
+    get!(A2, flags, m);
+    if flags as i32 & !LINUX_O_CLOEXEC != 0 {
+        return -EINVAL as u64;
     }
-    return 0;
+    let cloexec = flags as i32 & LINUX_O_CLOEXEC != 0;
+    return sys_result(fd_dup3(&mut m.fds, old, new, cloexec).map(|fd| fd as u64));
+}
+
+pub fn sys_fcntl(m: &mut Machine) -> u64 {
+    get!(A0, fd, m);
+    get!(A1, cmd, m);
+    get!(A2, arg, m);
+    let file = match fd_get(&m.fds, fd) {
+        Ok(file) => file,
+        Err(errno) => return sys_result(Err(errno)),
+    };
+    let ret = match cmd {
+        LINUX_F_DUPFD => fd_dup(&mut m.fds, fd, arg, false).map(|fd| fd as u64),
+        LINUX_F_DUPFD_CLOEXEC => fd_dup(&mut m.fds, fd, arg, true).map(|fd| fd as u64),
+        LINUX_F_GETFD => {
+            let cloexec = m.fds.fds[&(fd as i32)].cloexec;
+            Ok(if cloexec { LINUX_FD_CLOEXEC } else { 0 })
+        }
+        LINUX_F_SETFD => {
+            m.fds.fds.get_mut(&(fd as i32)).unwrap().cloexec = arg & LINUX_FD_CLOEXEC != 0;
+            Ok(0)
+        }
+        LINUX_F_GETFL => match *file.borrow() {
+            File::Host { fd, .. } => {
+                let ret = unsafe { fcntl(fd, F_GETFL) };
+                if ret < 0 {
+                    return sys_host(ret as i64);
+                }
+                Ok(host_open_flags(ret) as u64)
+            }
+            File::Input { .. } => Ok(host_open_flags(O_RDONLY) as u64),
+            File::Output { .. } => Ok(host_open_flags(O_WRONLY) as u64),
+        },
+        // only O_APPEND and O_NONBLOCK can change
+        LINUX_F_SETFL => match *file.borrow() {
+            File::Host { fd, .. } => {
+                let flags = sys_open_flags(m, arg) & (O_APPEND | O_NONBLOCK);
+                return sys_host(unsafe { fcntl(fd, F_SETFL, flags) } as i64);
+            }
+            _ => Ok(0),
+        },
+        _ => Err(EINVAL),
+    };
+    return sys_result(ret);
+}
+
+// write len bytes at ptr to fd, at offset for pwrite64
+fn sys_write_at(m: &mut Machine, fd: u64, ptr: u64, len: u64, offset: Option<u64>) -> u64 {
+    let file = match fd_get(&m.fds, fd) {
+        Ok(file) => file,
+        Err(errno) => return sys_result(Err(errno)),
+    };
+    let len = len.min(MAX_RW_COUNT);
+    if let Err(addr) = mmu_check(&m.mmu, ptr, len, PROT_READ) {
+        return sys_fault(MmuFault::Load(addr));
+    }
+    let mut buf = vec![0u8; len as usize];
+    mmu_copy_out(&m.mmu, ptr, &mut buf).unwrap();
+    let ret = fd_write(&mut file.borrow_mut(), &buf, offset);
+    return sys_result(ret.map(|len| len as u64));
 }
 
 pub fn sys_write(m: &mut Machine) -> u64 {
     get!(A0, fd, m);
     get!(A1, ptr, m);
     get!(A2, len, m);
-    let ptr = get_ptr(to_host!(ptr)) as *const c_void;
-    return unsafe { libc::write(fd as i32, ptr, len as usize) } as u64;
+    return sys_write_at(m, fd, ptr, len, None);
+}
+
+pub fn sys_pwrite(m: &mut Machine) -> u64 {
+    get!(A0, fd, m);
+    get!(A1, ptr, m);
+    get!(A2, len, m);
+    get!(A3, offset, m);
+    if (offset as i64) < 0 {
+        return -EINVAL as u64;
+    }
+    return sys_write_at(m, fd, ptr, len, Some(offset));
+}
+
+// the iovec array of readv/writev, each buffer checked for access
+fn sys_iovecs(m: &Machine, addr: u64, count: u64, prot: i32) -> Result<Vec<Iovec>, u64> {
+    if count > UIO_MAXIOV {
+        return Err(-EINVAL as u64);
+    }
+    let mut iovs = Vec::new();
+    let mut total = 0u64;
+    for i in 0..count {
+        let iov: Iovec = mmu_read(&m.mmu, addr + i * 16).map_err(sys_fault)?;
+        total = match total.checked_add(iov.iov_len) {
+            Some(total) if total as i64 >= 0 => total,
+            _ => return Err(-EINVAL as u64),
+        };
+        if let Err(addr) = mmu_check(&m.mmu, iov.iov_base, iov.iov_len, prot) {
+            return Err(sys_fault(MmuFault::Load(addr)));
+        }
+        iovs.push(iov);
+    }
+    return Ok(iovs);
+}
+
+// gathered into one host write, so it is as atomic as the guest expects
+pub fn sys_writev(m: &mut Machine) -> u64 {
+    get!(A0, fd, m);
+    get!(A1, iov_addr, m);
+    get!(A2, count, m);
+    let file = match fd_get(&m.fds, fd) {
+        Ok(file) => file,
+        Err(errno) => return sys_result(Err(errno)),
+    };
+    let iovs = match sys_iovecs(m, iov_addr, count, PROT_READ) {
+        Ok(iovs) => iovs,
+        Err(ret) => return ret,
+    };
+    let mut buf = Vec::new();
+    for iov in iovs.iter() {
+        let len = iov.iov_len.min(MAX_RW_COUNT - buf.len() as u64);
+        let mut data = vec![0u8; len as usize];
+        mmu_copy_out(&m.mmu, iov.iov_base, &mut data).unwrap();
+        buf.extend_from_slice(&data);
+    }
+    let ret = fd_write(&mut file.borrow_mut(), &buf, None);
+    return sys_result(ret.map(|len| len as u64));
+}
+
+pub fn sys_readv(m: &mut Machine) -> u64 {
+    get!(A0, fd, m);
+    get!(A1, iov_addr, m);
+    get!(A2, count, m);
+    let file = match fd_get(&m.fds, fd) {
+        Ok(file) => file,
+        Err(errno) => return sys_result(Err(errno)),
+    };
+    let iovs = match sys_iovecs(m, iov_addr, count, PROT_WRITE) {
+        Ok(iovs) => iovs,
+        Err(ret) => return ret,
+    };
+    let total = iovs
+        .iter()
+        .map(|iov| iov.iov_len)
+        .sum::<u64>()
+        .min(MAX_RW_COUNT);
+    let mut buf = vec![0u8; total as usize];
+    let ret = fd_read(&mut file.borrow_mut(), &mut buf, None);
+    if let Ok(len) = ret {
+        let mut data = &buf[..len];
+        for iov in iovs.iter() {
+            let len = data.len().min(iov.iov_len as usize);
+            mmu_copy_in(&mut m.mmu, iov.iov_base, &data[..len]).unwrap();
+            data = &data[len..];
+        }
+    }
+    return sys_result(ret.map(|len| len as u64));
+}
+
+fn sys_put_stat(m: &mut Machine, addr: u64, ret: i32, st: &stat) -> u64 {
+    if ret < 0 {
+        return sys_host(ret as i64);
+    }
+    if let Err(fault) = mmu_write(&mut m.mmu, addr, linux_stat(st)) {
+        return sys_fault(fault);
+    }
+    return 0;
 }
 
 pub fn sys_fstat(m: &mut Machine) -> u64 {
     get!(A0, fd, m);
     get!(A1, addr, m);
 
-    let ptr = get_ptr(to_host!(addr)) as *mut stat;
-    let ret = unsafe { libc::fstat(fd as i32, ptr) as u64 };
-    return ret;
+    let file = match fd_get(&m.fds, fd) {
+        Ok(file) => file,
+        Err(errno) => return sys_result(Err(errno)),
+    };
+    let mut st: stat = unsafe { mem::zeroed() };
+    let ret = match *file.borrow() {
+        File::Host { fd, .. } => unsafe { libc::fstat(fd, &mut st) },
+        // an in-memory file looks like a pipe
+        _ => {
+            st.st_mode = S_IFIFO | 0o600;
+            st.st_blksize = page_size::get() as _;
+            0
+        }
+    };
+    return sys_put_stat(m, addr, ret, &st);
+}
+
+// newfstatat, the AT_* flags are the same on a linux host
+pub fn sys_fstatat(m: &mut Machine) -> u64 {
+    get!(A0, dir_fd, m);
+    get!(A1, name_ptr, m);
+    get!(A2, addr, m);
+    get!(A3, flags, m);
+    let follow = flags as i32 & AT_SYMLINK_NOFOLLOW == 0;
+    let path = match sys_path_at(m, dir_fd, name_ptr, follow, false) {
+        Ok(path) => path,
+        Err(ret) => return ret,
+    };
+
+    let mut st: stat = unsafe { mem::zeroed() };
+    let ret = unsafe { libc::fstatat(path.dir_fd, path.name.as_ptr(), &mut st, flags as i32) };
+    return sys_put_stat(m, addr, ret, &st);
 }
 
 #[cfg(target_os = "linux")]
-pub fn sys_gettimeofday(m: &mut Machine) -> u64 {
-    get!(A0, tv_addr, m);
-    get!(A1, tz_addr, m);
+fn host_statx(dir_fd: i32, name: &CString, flags: i32, mask: u32) -> Result<Statx, i32> {
+    let mut stx: libc::statx = unsafe { mem::zeroed() };
+    if unsafe { libc::statx(dir_fd, name.as_ptr(), flags, mask, &mut stx) } < 0 {
+        return Err(io::Error::last_os_error().raw_os_error().unwrap());
+    }
+    return Ok(linux_statx(&stx));
+}
 
-    let tv = get_ptr(tv_addr) as *mut timeval;
-    let mut tz: *mut timezone = ptr::null_mut();
-    if tz_addr != 0 {
-        let pp: *mut u8 = ptr::null_mut();
-        let pp = unsafe { pp.add(to_host!(tv_addr) as usize) } as *mut timezone;
-        tz = pp;
+#[cfg(not(target_os = "linux"))]
+fn host_statx(dir_fd: i32, name: &CString, flags: i32, _mask: u32) -> Result<Statx, i32> {
+    let mut st: stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstatat(dir_fd, name.as_ptr(), &mut st, flags) } < 0 {
+        return Err(io::Error::last_os_error().raw_os_error().unwrap());
+    }
+    return Ok(linux_statx_from_stat(&st));
+}
+
+pub fn sys_statx(m: &mut Machine) -> u64 {
+    get!(A0, dir_fd, m);
+    get!(A1, name_ptr, m);
+    get!(A2, flags, m);
+    get!(A3, mask, m);
+    get!(A4, addr, m);
+    let follow = flags as i32 & AT_SYMLINK_NOFOLLOW == 0;
+    let path = match sys_path_at(m, dir_fd, name_ptr, follow, false) {
+        Ok(path) => path,
+        Err(ret) => return ret,
+    };
+
+    let stx = match host_statx(path.dir_fd, &path.name, flags as i32, mask as u32) {
+        Ok(stx) => stx,
+        Err(errno) => return sys_result(Err(errno)),
+    };
+    if let Err(fault) = mmu_write(&mut m.mmu, addr, stx) {
+        return sys_fault(fault);
     }
-    return unsafe { gettimeofday(tv, tz as *mut timezone) } as u64;
+    return 0;
+}
+
+#[cfg(target_os = "linux")]
+fn host_gettimeofday(tv: &mut timeval, tz: &mut [i32; 2]) -> i32 {
+    return unsafe { gettimeofday(tv, tz.as_mut_ptr() as *mut timezone) };
 }
 
 #[cfg(target_os = "macos")]
+fn host_gettimeofday(tv: &mut timeval, tz: &mut [i32; 2]) -> i32 {
+    return unsafe { gettimeofday(tv, tz.as_mut_ptr() as *mut c_void) };
+}
+
 pub fn sys_gettimeofday(m: &mut Machine) -> u64 {
     get!(A0, tv_addr, m);
     get!(A1, tz_addr, m);
 
-    let tv = get_ptr(tv_addr) as *mut timeval;
-    let mut tz: *mut timezone = ptr::null_mut();
+    let mut tv: timeval = unsafe { mem::zeroed() };
+    // struct timezone is two ints, libc only declares it opaque
+    let mut tz = [0i32; 2];
+    if host_gettimeofday(&mut tv, &mut tz) < 0 {
+        return sys_host(-1);
+    }
+    if tv_addr != 0 {
+        if let Err(fault) = mmu_write(&mut m.mmu, tv_addr, linux_timeval(&tv)) {
+            return sys_fault(fault);
+        }
+    }
     if tz_addr != 0 {
-        let pp: *mut u8 = ptr::null_mut();
-        let pp = unsafe { pp.add(to_host!(tv_addr) as usize) } as *mut timezone;
-        tz = pp;
+        if let Err(fault) = mmu_write(&mut m.mmu, tz_addr, tz) {
+            return sys_fault(fault);
+        }
+    }
+    return 0;
+}
+
+pub fn sys_getrusage(m: &mut Machine) -> u64 {
+    get!(A0, who, m);
+    get!(A1, addr, m);
+
+    let mut ru: libc::rusage = unsafe { mem::zeroed() };
+    if unsafe { libc::getrusage(who as i32, &mut ru) } < 0 {
+        return sys_host(-1);
+    }
+    if let Err(fault) = mmu_write(&mut m.mmu, addr, linux_rusage(&ru)) {
+        return sys_fault(fault);
+    }
+    return 0;
+}
+
+// get and maybe set one limit of this process. the stack limit is the
+// guest's own, see Mmu::stack_limit, the rest are the emulator's.
+fn sys_rlimit(m: &mut Machine, resource: u64, new_addr: u64, old_addr: u64) -> u64 {
+    if resource >= RLIM_NLIMITS as u64 {
+        return -EINVAL as u64;
+    }
+    let new: Option<Rlimit> = match new_addr {
+        0 => None,
+        addr => match mmu_read(&m.mmu, addr) {
+            Ok(new) => Some(new),
+            Err(fault) => return sys_fault(fault),
+        },
+    };
+    if new.is_some_and(|new| new.rlim_cur > new.rlim_max) {
+        return -EINVAL as u64;
+    }
+
+    let mut host: libc::rlimit = unsafe { mem::zeroed() };
+    if unsafe { libc::getrlimit(resource as _, &mut host) } < 0 {
+        return sys_host(-1);
+    }
+    let mut old = linux_rlimit(&host);
+    if resource == RLIMIT_STACK as u64 {
+        old.rlim_cur = m.mmu.stack_limit;
+    }
+
+    if let Some(new) = new {
+        if resource == RLIMIT_STACK as u64 {
+            if new.rlim_max > old.rlim_max {
+                return -EPERM as u64;
+            }
+            m.mmu.stack_limit = new.rlim_cur;
+        } else if unsafe { libc::setrlimit(resource as _, &host_rlimit(&new)) } < 0 {
+            return sys_host(-1);
+        }
+    }
+    if old_addr != 0 {
+        if let Err(fault) = mmu_write(&mut m.mmu, old_addr, old) {
+            return sys_fault(fault);
+        }
+    }
+    return 0;
+}
+
+pub fn sys_getrlimit(m: &mut Machine) -> u64 {
+    get!(A0, resource, m);
+    get!(A1, addr, m);
+    return sys_rlimit(m, resource, 0, addr);
+}
+
+pub fn sys_setrlimit(m: &mut Machine) -> u64 {
+    get!(A0, resource, m);
+    get!(A1, addr, m);
+    return sys_rlimit(m, resource, addr, 0);
+}
+
+// only for this process, there is no other guest process to look at
+pub fn sys_prlimit64(m: &mut Machine) -> u64 {
+    get!(A0, pid, m);
+    get!(A1, resource, m);
+    get!(A2, new_addr, m);
+    get!(A3, old_addr, m);
+    if pid != 0 && pid != std::process::id() as u64 {
+        return -EPERM as u64;
+    }
+    return sys_rlimit(m, resource, new_addr, old_addr);
+}
+
+#[cfg(target_os = "linux")]
+pub fn sys_sysinfo(m: &mut Machine) -> u64 {
+    get!(A0, addr, m);
+
+    let mut info: libc::sysinfo = unsafe { mem::zeroed() };
+    if unsafe { libc::sysinfo(&mut info) } < 0 {
+        return sys_host(-1);
+    }
+    if let Err(fault) = mmu_write(&mut m.mmu, addr, linux_sysinfo(&info)) {
+        return sys_fault(fault);
+    }
+    return 0;
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn sys_sysinfo(_m: &mut Machine) -> u64 {
+    return -(libc::ENOSYS as i64) as u64;
+}
+
+pub fn sys_uname(m: &mut Machine) -> u64 {
+    get!(A0, addr, m);
+
+    let mut uts: libc::utsname = unsafe { mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } < 0 {
+        return sys_host(-1);
+    }
+    if let Err(fault) = mmu_write(&mut m.mmu, addr, linux_utsname(&uts)) {
+        return sys_fault(fault);
     }
-    return unsafe { gettimeofday(tv, tz as *mut c_void) } as u64;
+    return 0;
 }
 
 pub fn sys_brk(m: &mut Machine) -> u64 {
@@ -181,12 +677,114 @@ pub fn sys_brk(m: &mut Machine) -> u64 {
     if addr == 0 {
         addr = m.mmu.alloc;
     }
-    assert!(addr >= m.mmu.base);
-    let incr = (addr - m.mmu.alloc) as i64;
-    mmu_alloc(&mut m.mmu, incr);
+    // brk fails by handing back the old break
+    if addr < m.mmu.base || round_up!(addr, page_size::get()) > vma_heap_limit(&m.mmu) {
+        return m.mmu.alloc;
+    }
+    let incr = addr.wrapping_sub(m.mmu.alloc) as i64;
+    if mmu_alloc(&mut m.mmu, incr).is_err() {
+        return m.mmu.alloc;
+    }
     return addr;
 }
 
+pub fn sys_mmap(m: &mut Machine) -> u64 {
+    get!(A0, addr, m);
+    get!(A1, len, m);
+    get!(A2, prot, m);
+    get!(A3, flags, m);
+    get!(A4, fd, m);
+    get!(A5, offset, m);
+    let fd = match flags as i32 & LINUX_MAP_ANONYMOUS {
+        0 => match fd_host(&m.fds, fd, ENODEV) {
+            Ok(fd) => fd,
+            Err(errno) => return sys_result(Err(errno)),
+        },
+        _ => -1,
+    };
+    let end = addr.saturating_add(len);
+    let exec = flags as i32 & LINUX_MAP_FIXED != 0 && vma_has_exec(&m.mmu, addr, end);
+    let ret = vma_map(&mut m.mmu, addr, len, prot as i32, flags as i32, fd, offset);
+    if ret.is_ok() && exec {
+        machine_invalidate(m, addr, end);
+    }
+    return sys_result(ret);
+}
+
+pub fn sys_munmap(m: &mut Machine) -> u64 {
+    get!(A0, addr, m);
+    get!(A1, len, m);
+    let exec = vma_has_exec(&m.mmu, addr, addr.saturating_add(len));
+    let ret = vma_unmap(&mut m.mmu, addr, len);
+    if ret.is_ok() && exec {
+        machine_invalidate(m, addr, addr.saturating_add(len));
+    }
+    return sys_result(ret.map(|_| 0));
+}
+
+pub fn sys_mremap(m: &mut Machine) -> u64 {
+    get!(A0, addr, m);
+    get!(A1, old_len, m);
+    get!(A2, new_len, m);
+    get!(A3, flags, m);
+    get!(A4, new_addr, m);
+    let exec = vma_has_exec(&m.mmu, addr, addr.saturating_add(old_len));
+    let ret = vma_remap(&mut m.mmu, addr, old_len, new_len, flags as i32, new_addr);
+    if ret.is_ok() && exec {
+        machine_invalidate(m, addr, addr.saturating_add(old_len));
+    }
+    return sys_result(ret);
+}
+
+pub fn sys_mprotect(m: &mut Machine) -> u64 {
+    get!(A0, addr, m);
+    get!(A1, len, m);
+    get!(A2, prot, m);
+    let exec = vma_has_exec(&m.mmu, addr, addr.saturating_add(len));
+    let ret = vma_protect(&mut m.mmu, addr, len, prot as i32);
+    if ret.is_ok() && exec {
+        machine_invalidate(m, addr, addr.saturating_add(len));
+    }
+    return sys_result(ret.map(|_| 0));
+}
+
+// back from a signal handler through the vdso trampoline: the registers,
+// pc included, come from the rt_sigframe at sp. a frame that is not there
+// is a SIGSEGV, as on linux.
+pub fn sys_rt_sigreturn(m: &mut Machine) -> u64 {
+    let sp = machine_get_gp_reg(m, Sp as i32);
+    let ctx: Sigcontext = match mmu_read(&m.mmu, sp.wrapping_add(LINUX_SIGFRAME_MCONTEXT)) {
+        Ok(ctx) => ctx,
+        Err(fault) => {
+            m.state.fault = Some(fault);
+            machine_fault_exit(m);
+        }
+    };
+    m.state.gp_regs[1..].copy_from_slice(&ctx.regs[1..]);
+    m.state.pc = ctx.regs[0];
+    for (reg, &bits) in m.state.fp_regs.iter_mut().zip(ctx.fpregs.iter()) {
+        *reg = FpRegT {
+            v: bits,
+            w: bits as u32,
+            d: f64::from_bits(bits),
+            f: f32::from_bits(bits as u32),
+        };
+    }
+    // the caller puts this back in a0
+    return ctx.regs[A0 as usize];
+}
+
+// what linux programs do instead of fence.i. the kernel flushes
+// everything whatever the range, so does this.
+pub fn sys_riscv_flush_icache(m: &mut Machine) -> u64 {
+    get!(A2, flags, m);
+    if flags & !LINUX_FLUSH_ICACHE_LOCAL != 0 {
+        return sys_result(Err(EINVAL));
+    }
+    machine_invalidate(m, 0, u64::MAX);
+    return 0;
+}
+
 pub const NEWLIB_O_RDONLY: i32 = 0x0;
 pub const NEWLIB_O_WRONLY: i32 = 0x1;
 pub const NEWLIB_O_RDWR: i32 = 0x2;
@@ -194,6 +792,12 @@ pub const NEWLIB_O_APPEND: i32 = 0x8;
 pub const NEWLIB_O_CREAT: i32 = 0x200;
 pub const NEWLIB_O_TRUNC: i32 = 0x400;
 pub const NEWLIB_O_EXCL: i32 = 0x800;
+pub const NEWLIB_O_SYNC: i32 = 0x2000;
+pub const NEWLIB_O_NONBLOCK: i32 = 0x4000;
+pub const NEWLIB_O_NOCTTY: i32 = 0x8000;
+pub const NEWLIB_O_CLOEXEC: i32 = 0x40000;
+pub const NEWLIB_O_NOFOLLOW: i32 = 0x100000;
+pub const NEWLIB_O_DIRECTORY: i32 = 0x200000;
 
 pub fn convert_flags(flags: i32) -> i32 {
     let mut host_flags: i32 = 0;
@@ -205,41 +809,604 @@ pub fn convert_flags(flags: i32) -> i32 {
     rewrite_flag!(O_CREAT);
     rewrite_flag!(O_TRUNC);
     rewrite_flag!(O_EXCL);
+    rewrite_flag!(O_SYNC);
+    rewrite_flag!(O_NONBLOCK);
+    rewrite_flag!(O_NOCTTY);
+    rewrite_flag!(O_CLOEXEC);
+    rewrite_flag!(O_NOFOLLOW);
+    rewrite_flag!(O_DIRECTORY);
 
     return host_flags;
 }
 
+// newlib's errno.h agrees with linux up to ERANGE, libgloss hands the
+// rest to the program as they come
+pub fn newlib_errno(errno: i32) -> i32 {
+    return match errno {
+        35 => 45,   // EDEADLK
+        36 => 91,   // ENAMETOOLONG
+        37 => 46,   // ENOLCK
+        38 => 88,   // ENOSYS
+        39 => 90,   // ENOTEMPTY
+        40 => 92,   // ELOOP
+        75 => 139,  // EOVERFLOW
+        84 => 138,  // EILSEQ
+        95 => 134,  // ENOTSUP
+        110 => 116, // ETIMEDOUT
+        _ => errno,
+    };
+}
+
+// the open flags of the guest's libc as host flags
+fn sys_open_flags(m: &Machine, flags: u64) -> i32 {
+    return match m.personality {
+        Some(Personality::Newlib) => convert_flags(flags as i32),
+        _ => linux_open_flags(flags as i32),
+    };
+}
+
+// the personality of an elf program: linux when its OSABI says so, when
+// it has a dynamic linker or when it has the GNU ABI tag note glibc puts
+// in, newlib when it has newlib's reentrancy symbols. a program with
+// neither, a stripped static musl one say, is taken for linux.
+pub fn sys_personality(buf: &[u8]) -> Personality {
+    let (ehdr, phdrs) = match mmu_parse_elf(buf) {
+        Ok(elf) => elf,
+        Err(_) => return Personality::Linux,
+    };
+    if ehdr.e_ident[EI_OSABI] == ELFOSABI_LINUX || phdrs.iter().any(|p| p.p_type == PT_INTERP) {
+        return Personality::Linux;
+    }
+    for note in phdrs.iter().filter(|p| p.p_type == PT_NOTE) {
+        let mut off = note.p_offset;
+        let end = note.p_offset.saturating_add(note.p_filesz);
+        while let Some(nhdr) = elf_read::<Nhdr>(buf, off).filter(|_| off < end) {
+            let name = off + 12;
+            if nhdr.n_type == NT_GNU_ABI_TAG
+                && buf.get(name as usize..name as usize + 4) == Some(b"GNU\0")
+            {
+                return Personality::Linux;
+            }
+            off = name + round_up!(nhdr.n_namesz as u64, 4) + round_up!(nhdr.n_descsz as u64, 4);
+        }
+    }
+
+    let shdr = |i: u64| elf_read::<Shdr>(buf, ehdr.e_shoff.saturating_add(i * 64));
+    for i in 0..ehdr.e_shnum as u64 {
+        let symtab = match shdr(i).filter(|sh| sh.sh_type == SHT_SYMTAB) {
+            Some(symtab) => symtab,
+            None => continue,
+        };
+        let strtab = match shdr(symtab.sh_link as u64) {
+            Some(strtab) => strtab,
+            None => continue,
+        };
+        for j in 0..symtab.sh_size / 24 {
+            let sym = match elf_read::<Sym>(buf, symtab.sh_offset.saturating_add(j * 24)) {
+                Some(sym) => sym,
+                None => break,
+            };
+            let name = strtab.sh_offset.saturating_add(sym.st_name as u64) as usize;
+            let name = buf.get(name..).unwrap_or(&[]);
+            let name = &name[..name.iter().position(|&c| c == 0).unwrap_or(0)];
+            if name == b"_impure_ptr" || name == b"_global_impure_ptr" {
+                return Personality::Newlib;
+            }
+        }
+    }
+    return Personality::Linux;
+}
+
+pub fn sys_clock_gettime(m: &mut Machine) -> u64 {
+    get!(A0, clock, m);
+    get!(A1, tp_addr, m);
+
+    let mut tp: timespec = unsafe { mem::zeroed() };
+    if unsafe { clock_gettime(clock as i32, &mut tp) } != 0 {
+        return sys_host(-1);
+    }
+    if let Err(fault) = mmu_write(&mut m.mmu, tp_addr, linux_timespec(&tp)) {
+        return sys_fault(fault);
+    }
+    return 0;
+}
+
+pub fn sys_clock_getres(m: &mut Machine) -> u64 {
+    get!(A0, clock, m);
+    get!(A1, res_addr, m);
+
+    let mut res: timespec = unsafe { mem::zeroed() };
+    if unsafe { clock_getres(clock as i32, &mut res) } != 0 {
+        return sys_host(-1);
+    }
+    if res_addr != 0 {
+        if let Err(fault) = mmu_write(&mut m.mmu, res_addr, linux_timespec(&res)) {
+            return sys_fault(fault);
+        }
+    }
+    return 0;
+}
+
+// there is one hart, cpu 0 on node 0
+pub fn sys_getcpu(m: &mut Machine) -> u64 {
+    get!(A0, cpu_addr, m);
+    get!(A1, node_addr, m);
+    for addr in [cpu_addr, node_addr] {
+        if addr != 0 {
+            if let Err(fault) = mmu_write(&mut m.mmu, addr, 0u32) {
+                return sys_fault(fault);
+            }
+        }
+    }
+    return 0;
+}
+
+pub fn sys_getpid(_m: &mut Machine) -> u64 {
+    return unsafe { libc::getpid() } as u64;
+}
+
+// the guest runs on the emulator's main thread, so its only thread has
+// the tid of the process
+pub fn sys_gettid(m: &mut Machine) -> u64 {
+    return sys_getpid(m);
+}
+
+// with a single thread nothing ever clears the tid at exit
+pub fn sys_set_tid_address(m: &mut Machine) -> u64 {
+    return sys_gettid(m);
+}
+
+pub fn sys_set_robust_list(m: &mut Machine) -> u64 {
+    get!(A1, len, m);
+    if len != LINUX_ROBUST_LIST_HEAD_SIZE {
+        return -EINVAL as u64;
+    }
+    return 0;
+}
+
+pub fn sys_rt_sigaction(m: &mut Machine) -> u64 {
+    get!(A0, sig, m);
+    get!(A1, act_addr, m);
+    get!(A2, old_addr, m);
+    get!(A3, size, m);
+    if size != 8 || sig == 0 || sig > LINUX_NSIG as u64 {
+        return -EINVAL as u64;
+    }
+    if act_addr != 0 && (sig == LINUX_SIGKILL || sig == LINUX_SIGSTOP) {
+        return -EINVAL as u64;
+    }
+    let old = m.sigactions[sig as usize - 1];
+    if act_addr != 0 {
+        match mmu_read::<Sigaction>(&m.mmu, act_addr) {
+            Ok(act) => m.sigactions[sig as usize - 1] = act,
+            Err(fault) => return sys_fault(fault),
+        }
+    }
+    if old_addr != 0 {
+        if let Err(fault) = mmu_write(&mut m.mmu, old_addr, old) {
+            return sys_fault(fault);
+        }
+    }
+    return 0;
+}
+
+pub fn sys_rt_sigprocmask(m: &mut Machine) -> u64 {
+    get!(A0, how, m);
+    get!(A1, set_addr, m);
+    get!(A2, old_addr, m);
+    get!(A3, size, m);
+    if size != 8 {
+        return -EINVAL as u64;
+    }
+    let old = m.sigmask;
+    if set_addr != 0 {
+        let set = match mmu_read::<u64>(&m.mmu, set_addr) {
+            Ok(set) => set,
+            Err(fault) => return sys_fault(fault),
+        };
+        let mask = match how {
+            LINUX_SIG_BLOCK => old | set,
+            LINUX_SIG_UNBLOCK => old & !set,
+            LINUX_SIG_SETMASK => set,
+            _ => return -EINVAL as u64,
+        };
+        // SIGKILL and SIGSTOP cannot be blocked
+        let unblockable = (1 << (LINUX_SIGKILL - 1)) | (1 << (LINUX_SIGSTOP - 1));
+        m.sigmask = mask & !unblockable;
+    }
+    if old_addr != 0 {
+        if let Err(fault) = mmu_write(&mut m.mmu, old_addr, old) {
+            return sys_fault(fault);
+        }
+    }
+    return 0;
+}
+
+// only advice: the pages are never given back, and MADV_DONTNEED leaves
+// their contents alone
+pub fn sys_madvise(m: &mut Machine) -> u64 {
+    get!(A0, addr, m);
+    get!(A1, len, m);
+    if addr % page_size::get() as u64 != 0 {
+        return -EINVAL as u64;
+    }
+    if addr.checked_add(len).is_none_or(|end| end > GUEST_SPACE) {
+        return -ENOMEM as u64;
+    }
+    return 0;
+}
+
+#[cfg(target_os = "linux")]
+fn host_tcgets(fd: i32) -> Result<Termios, i32> {
+    let mut t: libc::termios = unsafe { mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut t) } < 0 {
+        return Err(io::Error::last_os_error().raw_os_error().unwrap());
+    }
+    return Ok(linux_termios(&t));
+}
+
+// the termios of other hosts does not line up with the kernel's
+#[cfg(not(target_os = "linux"))]
+fn host_tcgets(_fd: i32) -> Result<Termios, i32> {
+    return Err(ENOTTY);
+}
+
+// what libc asks of a tty: isatty through TCGETS, and the window size.
+// everything else is not a tty request the guest gets to make.
+pub fn sys_ioctl(m: &mut Machine) -> u64 {
+    get!(A0, fd, m);
+    get!(A1, req, m);
+    get!(A2, arg, m);
+    let host_fd = match fd_host(&m.fds, fd, ENOTTY) {
+        Ok(host_fd) => host_fd,
+        Err(errno) => return sys_result(Err(errno)),
+    };
+    let ret = match req {
+        LINUX_TCGETS => host_tcgets(host_fd).map(|t| mmu_write(&mut m.mmu, arg, t)),
+        LINUX_TIOCGWINSZ => {
+            let mut ws: libc::winsize = unsafe { mem::zeroed() };
+            match unsafe { libc::ioctl(host_fd, libc::TIOCGWINSZ, &mut ws) } {
+                0 => Ok(mmu_write(&mut m.mmu, arg, ws)),
+                _ => return sys_host(-1),
+            }
+        }
+        _ => Err(ENOTTY),
+    };
+    return match ret {
+        Ok(Ok(())) => 0,
+        Ok(Err(fault)) => sys_fault(fault),
+        Err(errno) => sys_result(Err(errno)),
+    };
+}
+
+fn sys_open_at(m: &mut Machine, dir_fd: u64, name_ptr: u64, flags: u64, mode: u64) -> u64 {
+    let flags = sys_open_flags(m, flags);
+    let write = flags & O_ACCMODE != O_RDONLY || flags & (O_CREAT | O_TRUNC) != 0;
+    let follow = flags & O_NOFOLLOW == 0 && flags & (O_CREAT | O_EXCL) != O_CREAT | O_EXCL;
+    let path = match sys_path_at(m, dir_fd, name_ptr, follow, write) {
+        Ok(path) => path,
+        Err(ret) => return ret,
+    };
+    let ret = unsafe {
+        openat(
+            path.dir_fd,
+            path.name.as_ptr(),
+            flags | O_CLOEXEC,
+            mode as u32,
+        )
+    };
+    return sys_new_fd(m, ret, path.guest, flags & O_CLOEXEC != 0);
+}
+
 pub fn sys_openat(m: &mut Machine) -> u64 {
     get!(A0, dir_fd, m);
     get!(A1, name_ptr, m);
     get!(A2, flags, m);
-    let ptr = get_ptr(to_host!(name_ptr)) as *const c_char;
-    return unsafe { openat(dir_fd as i32, ptr, flags as i32) } as u64;
+    get!(A3, mode, m);
+    return sys_open_at(m, dir_fd, name_ptr, flags, mode);
+}
+
+fn sys_link_at(m: &mut Machine, old: (u64, u64), new: (u64, u64), flags: u64) -> u64 {
+    if flags & !LINUX_AT_SYMLINK_FOLLOW != 0 {
+        return -EINVAL as u64;
+    }
+    let follow = flags & LINUX_AT_SYMLINK_FOLLOW != 0;
+    let (old, new) = match (
+        sys_path_at(m, old.0, old.1, follow, false),
+        sys_path_at(m, new.0, new.1, false, true),
+    ) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err(ret), _) | (_, Err(ret)) => return ret,
+    };
+    let flags = if follow { AT_SYMLINK_FOLLOW } else { 0 };
+    let ret = unsafe {
+        libc::linkat(
+            old.dir_fd,
+            old.name.as_ptr(),
+            new.dir_fd,
+            new.name.as_ptr(),
+            flags,
+        )
+    };
+    return sys_host(ret as i64);
+}
+
+pub fn sys_linkat(m: &mut Machine) -> u64 {
+    get!(A0, old_dir_fd, m);
+    get!(A1, old_ptr, m);
+    get!(A2, new_dir_fd, m);
+    get!(A3, new_ptr, m);
+    get!(A4, flags, m);
+    return sys_link_at(m, (old_dir_fd, old_ptr), (new_dir_fd, new_ptr), flags);
+}
+
+fn sys_unlink_at(m: &mut Machine, dir_fd: u64, name_ptr: u64, flags: u64) -> u64 {
+    if flags & !LINUX_AT_REMOVEDIR != 0 {
+        return -EINVAL as u64;
+    }
+    let path = match sys_path_at(m, dir_fd, name_ptr, false, true) {
+        Ok(path) => path,
+        Err(ret) => return ret,
+    };
+    let flags = if flags != 0 { AT_REMOVEDIR } else { 0 };
+    let ret = unsafe { libc::unlinkat(path.dir_fd, path.name.as_ptr(), flags) };
+    return sys_host(ret as i64);
+}
+
+pub fn sys_unlinkat(m: &mut Machine) -> u64 {
+    get!(A0, dir_fd, m);
+    get!(A1, name_ptr, m);
+    get!(A2, flags, m);
+    return sys_unlink_at(m, dir_fd, name_ptr, flags);
+}
+
+fn sys_mkdir_at(m: &mut Machine, dir_fd: u64, name_ptr: u64, mode: u64) -> u64 {
+    let path = match sys_path_at(m, dir_fd, name_ptr, false, true) {
+        Ok(path) => path,
+        Err(ret) => return ret,
+    };
+    let ret = unsafe { libc::mkdirat(path.dir_fd, path.name.as_ptr(), mode as _) };
+    return sys_host(ret as i64);
 }
 
+pub fn sys_mkdirat(m: &mut Machine) -> u64 {
+    get!(A0, dir_fd, m);
+    get!(A1, name_ptr, m);
+    get!(A2, mode, m);
+    return sys_mkdir_at(m, dir_fd, name_ptr, mode);
+}
+
+// asking for W_OK on a read-only mount is EROFS, as on linux
+fn sys_access_at(m: &mut Machine, dir_fd: u64, name_ptr: u64, mode: u64) -> u64 {
+    let write = mode as i32 & W_OK != 0;
+    let path = match sys_path_at(m, dir_fd, name_ptr, true, write) {
+        Ok(path) => path,
+        Err(ret) => return ret,
+    };
+    let ret = unsafe { libc::faccessat(path.dir_fd, path.name.as_ptr(), mode as i32, 0) };
+    return sys_host(ret as i64);
+}
+
+pub fn sys_faccessat(m: &mut Machine) -> u64 {
+    get!(A0, dir_fd, m);
+    get!(A1, name_ptr, m);
+    get!(A2, mode, m);
+    return sys_access_at(m, dir_fd, name_ptr, mode);
+}
+
+// the link text as it is, a sandboxed guest reads it in its own view
+pub fn sys_readlinkat(m: &mut Machine) -> u64 {
+    get!(A0, dir_fd, m);
+    get!(A1, name_ptr, m);
+    get!(A2, buf_ptr, m);
+    get!(A3, size, m);
+    if size as i64 <= 0 {
+        return -EINVAL as u64;
+    }
+    let path = match sys_path_at(m, dir_fd, name_ptr, false, false) {
+        Ok(path) => path,
+        Err(ret) => return ret,
+    };
+    let mut buf = vec![0u8; size.min(PATH_MAX as u64) as usize];
+    let ret = unsafe {
+        libc::readlinkat(
+            path.dir_fd,
+            path.name.as_ptr(),
+            buf.as_mut_ptr() as *mut _,
+            buf.len(),
+        )
+    };
+    if ret > 0 {
+        if let Err(fault) = mmu_copy_in(&mut m.mmu, buf_ptr, &buf[..ret as usize]) {
+            return sys_fault(fault);
+        }
+    }
+    return sys_host(ret as i64);
+}
+
+// a sandboxed guest only moves in its own view, the emulator stays put
+pub fn sys_chdir(m: &mut Machine) -> u64 {
+    get!(A0, name_ptr, m);
+    let path = match sys_path(m, name_ptr, true, false) {
+        Ok(path) => path,
+        Err(ret) => return ret,
+    };
+    let guest = match path.guest {
+        Some(guest) => guest,
+        None => return sys_host(unsafe { libc::chdir(path.name.as_ptr()) } as i64),
+    };
+    match fs::metadata(OsStr::from_bytes(path.name.as_bytes())) {
+        Ok(meta) if meta.is_dir() => {}
+        Ok(_) => return -ENOTDIR as u64,
+        Err(e) => return sys_result(Err(e.raw_os_error().unwrap_or(ENOENT))),
+    }
+    m.vfs.cwd = guest;
+    return 0;
+}
+
+// the syscall returns the length with the NUL, not the pointer
+pub fn sys_getcwd(m: &mut Machine) -> u64 {
+    get!(A0, buf_ptr, m);
+    get!(A1, size, m);
+    let cwd = match vfs_enabled(&m.vfs) {
+        true => m.vfs.cwd.clone(),
+        false => match env::current_dir() {
+            Ok(cwd) => cwd.to_string_lossy().into_owned(),
+            Err(e) => return sys_result(Err(e.raw_os_error().unwrap_or(ENOENT))),
+        },
+    };
+    let cwd = CString::new(cwd).unwrap();
+    let bytes = cwd.as_bytes_with_nul();
+    if (bytes.len() as u64) > size {
+        return -ERANGE as u64;
+    }
+    if let Err(fault) = mmu_copy_in(&mut m.mmu, buf_ptr, bytes) {
+        return sys_fault(fault);
+    }
+    return bytes.len() as u64;
+}
+
+// the calls libgloss makes from 1024 up, the ones linux dropped for their
+// *at versions
+
 pub fn sys_open(m: &mut Machine) -> u64 {
     get!(A0, name_ptr, m);
     get!(A1, flags, m);
-    let ptr = get_ptr(to_host!(name_ptr)) as *const c_char;
-    let ret = unsafe { open(ptr, flags as i32) } as u64;
-    return ret;
+    get!(A2, mode, m);
+    return sys_open_at(m, AT_FDCWD as u64, name_ptr, flags, mode);
+}
+
+pub fn sys_link(m: &mut Machine) -> u64 {
+    get!(A0, old_ptr, m);
+    get!(A1, new_ptr, m);
+    let cwd = AT_FDCWD as u64;
+    return sys_link_at(m, (cwd, old_ptr), (cwd, new_ptr), 0);
+}
+
+pub fn sys_unlink(m: &mut Machine) -> u64 {
+    get!(A0, name_ptr, m);
+    return sys_unlink_at(m, AT_FDCWD as u64, name_ptr, 0);
+}
+
+pub fn sys_mkdir(m: &mut Machine) -> u64 {
+    get!(A0, name_ptr, m);
+    get!(A1, mode, m);
+    return sys_mkdir_at(m, AT_FDCWD as u64, name_ptr, mode);
+}
+
+pub fn sys_access(m: &mut Machine) -> u64 {
+    get!(A0, name_ptr, m);
+    get!(A1, mode, m);
+    return sys_access_at(m, AT_FDCWD as u64, name_ptr, mode);
+}
+
+// libgloss asks for the kernel's struct stat and converts it itself, so
+// this is the same Stat as fstat
+fn sys_stat_path(m: &mut Machine, flags: i32) -> u64 {
+    get!(A0, name_ptr, m);
+    get!(A1, addr, m);
+    let path = match sys_path(m, name_ptr, flags == 0, false) {
+        Ok(path) => path,
+        Err(ret) => return ret,
+    };
+    let mut st: stat = unsafe { mem::zeroed() };
+    let ret = unsafe { libc::fstatat(path.dir_fd, path.name.as_ptr(), &mut st, flags) };
+    return sys_put_stat(m, addr, ret, &st);
+}
+
+pub fn sys_stat(m: &mut Machine) -> u64 {
+    return sys_stat_path(m, 0);
+}
+
+pub fn sys_lstat(m: &mut Machine) -> u64 {
+    return sys_stat_path(m, AT_SYMLINK_NOFOLLOW);
+}
+
+pub fn sys_time(m: &mut Machine) -> u64 {
+    get!(A0, addr, m);
+    let now = unsafe { libc::time(std::ptr::null_mut()) } as i64;
+    if addr != 0 {
+        if let Err(fault) = mmu_write(&mut m.mmu, addr, now) {
+            return sys_fault(fault);
+        }
+    }
+    return now as u64;
+}
+
+// the riscv-pk call old newlib startup code gets argc and argv from,
+// laid out at buf the way pk does: argc, argv, a NULL, an empty envp and
+// the strings
+pub fn sys_getmainvars(m: &mut Machine) -> u64 {
+    get!(A0, buf, m);
+    get!(A1, limit, m);
+    let argc = m.argv.len() as u64;
+    let mut words = vec![0u64; argc as usize + 3];
+    words[0] = argc;
+    let mut strings = Vec::new();
+    let mut at = buf + (argc + 3) * 8;
+    for (i, arg) in m.argv.iter().enumerate() {
+        words[i + 1] = at;
+        strings.extend_from_slice(arg.as_bytes());
+        strings.push(0);
+        at += arg.len() as u64 + 1;
+    }
+    let mut bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    bytes.extend_from_slice(&strings);
+    if bytes.len() as u64 > limit {
+        return -ENOMEM as u64;
+    }
+    if let Err(fault) = mmu_copy_in(&mut m.mmu, buf, &bytes) {
+        return sys_fault(fault);
+    }
+    return 0;
 }
 
 pub fn sys_lseek(m: &mut Machine) -> u64 {
     get!(A0, fd, m);
     get!(A1, offset, m);
     get!(A2, when_ce, m);
+    let fd = match fd_host(&m.fds, fd, ESPIPE) {
+        Ok(fd) => fd,
+        Err(errno) => return sys_result(Err(errno)),
+    };
 
-    return unsafe { lseek(fd as i32, offset as i64, when_ce as i32) as u64 };
+    return sys_host(unsafe { lseek(fd, offset as i64, when_ce as i32) });
+}
+
+// read up to count bytes from fd to buf_ptr, at offset for pread64
+fn sys_read_at(m: &mut Machine, fd: u64, buf_ptr: u64, count: u64, offset: Option<u64>) -> u64 {
+    let file = match fd_get(&m.fds, fd) {
+        Ok(file) => file,
+        Err(errno) => return sys_result(Err(errno)),
+    };
+    let count = count.min(MAX_RW_COUNT);
+    if let Err(addr) = mmu_check(&m.mmu, buf_ptr, count, PROT_WRITE) {
+        return sys_fault(MmuFault::Store(addr));
+    }
+    let mut buf = vec![0u8; count as usize];
+    let ret = fd_read(&mut file.borrow_mut(), &mut buf, offset);
+    if let Ok(len) = ret {
+        mmu_copy_in(&mut m.mmu, buf_ptr, &buf[..len]).unwrap();
+    }
+    return sys_result(ret.map(|len| len as u64));
 }
 
 pub fn sys_read(m: &mut Machine) -> u64 {
     get!(A0, fd, m);
     get!(A1, buf_ptr, m);
     get!(A2, count, m);
+    return sys_read_at(m, fd, buf_ptr, count, None);
+}
 
-    let ptr = get_ptr(to_host!(buf_ptr)) as *mut c_void;
-    return unsafe { read(fd as i32, ptr, count as usize) as u64 };
+pub fn sys_pread(m: &mut Machine) -> u64 {
+    get!(A0, fd, m);
+    get!(A1, buf_ptr, m);
+    get!(A2, count, m);
+    get!(A3, offset, m);
+    if (offset as i64) < 0 {
+        return -EINVAL as u64;
+    }
+    return sys_read_at(m, fd, buf_ptr, count, Some(offset));
 }
 
 pub static mut SYSCALL_TABLE: [Option<fn(&mut Machine) -> u64>; 2011] =
@@ -252,30 +1419,172 @@ pub fn init_sys_call() {
     unsafe { SYSCALL_TABLE[SYS_WRITE] = Some(sys_write) };
     unsafe { SYSCALL_TABLE[SYS_OPENAT] = Some(sys_openat) };
     unsafe { SYSCALL_TABLE[SYS_CLOSE] = Some(sys_close) };
+    unsafe { SYSCALL_TABLE[SYS_DUP] = Some(sys_dup) };
+    unsafe { SYSCALL_TABLE[SYS_DUP3] = Some(sys_dup3) };
+    unsafe { SYSCALL_TABLE[SYS_FCNTL] = Some(sys_fcntl) };
     unsafe { SYSCALL_TABLE[SYS_FSTAT] = Some(sys_fstat) };
     unsafe { SYSCALL_TABLE[SYS_LSEEK] = Some(sys_lseek) };
     unsafe { SYSCALL_TABLE[SYS_BRK] = Some(sys_brk) };
     unsafe { SYSCALL_TABLE[SYS_GETTIMEOFDAY] = Some(sys_gettimeofday) };
+    unsafe { SYSCALL_TABLE[SYS_CLOCK_GETTIME] = Some(sys_clock_gettime) };
+    unsafe { SYSCALL_TABLE[SYS_CLOCK_GETRES] = Some(sys_clock_getres) };
+    unsafe { SYSCALL_TABLE[SYS_GETCPU] = Some(sys_getcpu) };
+    unsafe { SYSCALL_TABLE[SYS_RT_SIGRETURN] = Some(sys_rt_sigreturn) };
+    unsafe { SYSCALL_TABLE[SYS_MMAP] = Some(sys_mmap) };
+    unsafe { SYSCALL_TABLE[SYS_MUNMAP] = Some(sys_munmap) };
+    unsafe { SYSCALL_TABLE[SYS_MREMAP] = Some(sys_mremap) };
+    unsafe { SYSCALL_TABLE[SYS_MPROTECT] = Some(sys_mprotect) };
+    unsafe { SYSCALL_TABLE[SYS_RISCV_FLUSH_ICACHE] = Some(sys_riscv_flush_icache) };
+    unsafe { SYSCALL_TABLE[SYS_READV] = Some(sys_readv) };
+    unsafe { SYSCALL_TABLE[SYS_WRITEV] = Some(sys_writev) };
+    unsafe { SYSCALL_TABLE[SYS_PREAD] = Some(sys_pread) };
+    unsafe { SYSCALL_TABLE[SYS_PWRITE] = Some(sys_pwrite) };
+    unsafe { SYSCALL_TABLE[SYS_FSTATAT] = Some(sys_fstatat) };
+    unsafe { SYSCALL_TABLE[SYS_LINKAT] = Some(sys_linkat) };
+    unsafe { SYSCALL_TABLE[SYS_UNLINKAT] = Some(sys_unlinkat) };
+    unsafe { SYSCALL_TABLE[SYS_MKDIRAT] = Some(sys_mkdirat) };
+    unsafe { SYSCALL_TABLE[SYS_FACCESSAT] = Some(sys_faccessat) };
+    unsafe { SYSCALL_TABLE[SYS_READLINKAT] = Some(sys_readlinkat) };
+    unsafe { SYSCALL_TABLE[SYS_CHDIR] = Some(sys_chdir) };
+    unsafe { SYSCALL_TABLE[SYS_GETCWD] = Some(sys_getcwd) };
+    unsafe { SYSCALL_TABLE[SYS_STATX] = Some(sys_statx) };
+    unsafe { SYSCALL_TABLE[SYS_GETRUSAGE] = Some(sys_getrusage) };
+    unsafe { SYSCALL_TABLE[SYS_GETRLIMIT] = Some(sys_getrlimit) };
+    unsafe { SYSCALL_TABLE[SYS_SETRLIMIT] = Some(sys_setrlimit) };
+    unsafe { SYSCALL_TABLE[SYS_PRLIMIT64] = Some(sys_prlimit64) };
+    unsafe { SYSCALL_TABLE[SYS_SYSINFO] = Some(sys_sysinfo) };
+    unsafe { SYSCALL_TABLE[SYS_UNAME] = Some(sys_uname) };
+    unsafe { SYSCALL_TABLE[SYS_GETPID] = Some(sys_getpid) };
+    unsafe { SYSCALL_TABLE[SYS_GETTID] = Some(sys_gettid) };
+    unsafe { SYSCALL_TABLE[SYS_SET_TID_ADDRESS] = Some(sys_set_tid_address) };
+    unsafe { SYSCALL_TABLE[SYS_SET_ROBUST_LIST] = Some(sys_set_robust_list) };
+    unsafe { SYSCALL_TABLE[SYS_RT_SIGACTION] = Some(sys_rt_sigaction) };
+    unsafe { SYSCALL_TABLE[SYS_RT_SIGPROCMASK] = Some(sys_rt_sigprocmask) };
+    unsafe { SYSCALL_TABLE[SYS_MADVISE] = Some(sys_madvise) };
+    unsafe { SYSCALL_TABLE[SYS_IOCTL] = Some(sys_ioctl) };
 }
 
 pub static mut OLD_SYSCALL_TABLE: [Option<fn(&mut Machine) -> u64>; 39] =
     [Some(sys_unimplemented); 39];
 
 pub fn init_sys_call_table() {
-    unsafe { SYSCALL_TABLE[SYS_OPEN - OLD_SYSCALL_THRESHOLD] = Some(sys_exit) };
+    unsafe { OLD_SYSCALL_TABLE[SYS_OPEN - OLD_SYSCALL_THRESHOLD] = Some(sys_open) };
+    unsafe { OLD_SYSCALL_TABLE[SYS_LINK - OLD_SYSCALL_THRESHOLD] = Some(sys_link) };
+    unsafe { OLD_SYSCALL_TABLE[SYS_UNLINK - OLD_SYSCALL_THRESHOLD] = Some(sys_unlink) };
+    unsafe { OLD_SYSCALL_TABLE[SYS_MKDIR - OLD_SYSCALL_THRESHOLD] = Some(sys_mkdir) };
+    unsafe { OLD_SYSCALL_TABLE[SYS_ACCESS - OLD_SYSCALL_THRESHOLD] = Some(sys_access) };
+    unsafe { OLD_SYSCALL_TABLE[SYS_STAT - OLD_SYSCALL_THRESHOLD] = Some(sys_stat) };
+    unsafe { OLD_SYSCALL_TABLE[SYS_LSTAT - OLD_SYSCALL_THRESHOLD] = Some(sys_lstat) };
+}
+
+// the handler of syscall n under the guest's personality. the old numbers
+// and getmainvars are newlib's, linux has nothing there.
+fn sys_handler(m: &Machine, n: u64) -> fn(&mut Machine) -> u64 {
+    let newlib = m.personality == Some(Personality::Newlib);
+    let f = if n < OLD_SYSCALL_THRESHOLD as u64 {
+        unsafe { SYSCALL_TABLE[n as usize] }
+    } else if newlib && n == SYS_GETMAINVARS as u64 {
+        Some(sys_getmainvars as fn(&mut Machine) -> u64)
+    } else if newlib && n == SYS_TIME as u64 {
+        Some(sys_time as fn(&mut Machine) -> u64)
+    } else if newlib && n >= OLD_SYSCALL_THRESHOLD as u64 {
+        let old = n - OLD_SYSCALL_THRESHOLD as u64;
+        unsafe { OLD_SYSCALL_TABLE.get(old as usize).copied().flatten() }
+    } else {
+        None
+    };
+    return f.unwrap_or(sys_unimplemented);
 }
 
 pub fn do_syscall(m: &mut Machine, n: u64) -> u64 {
-    let mut f: Option<fn(&mut Machine) -> u64> = None;
-    if n < unsafe { SYSCALL_TABLE.len() } as u64 {
-        f = unsafe { SYSCALL_TABLE[n as usize] }
-    } else if (n as isize - OLD_SYSCALL_THRESHOLD as isize)
-        < unsafe { OLD_SYSCALL_TABLE.len() } as isize
-    {
-        f = unsafe { OLD_SYSCALL_TABLE[(n as isize - OLD_SYSCALL_THRESHOLD as isize) as usize] }
-    }
-    if f.is_none() {
-        fatal!("unknown syscall");
-    }
-    return f.unwrap()(m);
+    let ret = sys_handler(m, n)(m);
+    if m.personality == Some(Personality::Newlib) && (ret as i64) < 0 && (ret as i64) >= -4095 {
+        return -newlib_errno(-(ret as i64) as i32) as u64;
+    }
+    return ret;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        elfdef::PROT_READ,
+        rvemu::machine_set_gp_reg,
+        vma::{LINUX_MAP_FIXED, LINUX_MAP_PRIVATE},
+    };
+
+    const BASE: u64 = 0x100000;
+
+    fn syscall(m: &mut Machine, n: usize, args: &[u64]) -> u64 {
+        for (i, &arg) in args.iter().enumerate() {
+            machine_set_gp_reg(m, A0 as i32 + i as i32, arg);
+        }
+        return do_syscall(m, n as u64);
+    }
+
+    // what static glibc and musl do before main
+    #[test]
+    fn sys_call_libc_startup() {
+        let mut m = Machine::new();
+        let flags = LINUX_MAP_PRIVATE | LINUX_MAP_ANONYMOUS | LINUX_MAP_FIXED;
+        let len = page_size::get() as u64;
+        let prot = PROT_READ | PROT_WRITE;
+        assert_eq!(vma_map(&mut m.mmu, BASE, len, prot, flags, -1, 0), Ok(BASE));
+        init_sys_call();
+
+        let pid = std::process::id() as u64;
+        assert_eq!(syscall(&mut m, SYS_SET_TID_ADDRESS, &[BASE]), pid);
+        assert_eq!(syscall(&mut m, SYS_GETTID, &[]), pid);
+        assert_eq!(syscall(&mut m, SYS_GETPID, &[]), pid);
+        assert_eq!(syscall(&mut m, SYS_SET_ROBUST_LIST, &[BASE, 24]), 0);
+        assert_eq!(
+            syscall(&mut m, SYS_SET_ROBUST_LIST, &[BASE, 23]),
+            -EINVAL as u64
+        );
+
+        // block SIGINT and SIGKILL, only SIGINT sticks
+        let set = (1u64 << 1) | (1 << 8);
+        mmu_write(&mut m.mmu, BASE, set).unwrap();
+        let (set_addr, old_addr) = (BASE, BASE + 8);
+        let args = [LINUX_SIG_BLOCK, set_addr, old_addr, 8];
+        assert_eq!(syscall(&mut m, SYS_RT_SIGPROCMASK, &args), 0);
+        assert_eq!(mmu_read::<u64>(&m.mmu, old_addr), Ok(0));
+        let args = [LINUX_SIG_SETMASK, 0, old_addr, 8];
+        assert_eq!(syscall(&mut m, SYS_RT_SIGPROCMASK, &args), 0);
+        assert_eq!(mmu_read::<u64>(&m.mmu, old_addr), Ok(1 << 1));
+        let args = [3, set_addr, 0, 8];
+        assert_eq!(syscall(&mut m, SYS_RT_SIGPROCMASK, &args), -EINVAL as u64);
+
+        // the action the guest set is the one it reads back
+        let act = Sigaction {
+            sa_handler: 0x1234,
+            sa_flags: 0x4000000,
+            sa_mask: 1,
+        };
+        mmu_write(&mut m.mmu, BASE, act).unwrap();
+        let (act_addr, old_addr) = (BASE, BASE + 32);
+        assert_eq!(syscall(&mut m, SYS_RT_SIGACTION, &[2, act_addr, 0, 8]), 0);
+        assert_eq!(syscall(&mut m, SYS_RT_SIGACTION, &[2, 0, old_addr, 8]), 0);
+        let old: Sigaction = mmu_read(&m.mmu, old_addr).unwrap();
+        assert_eq!(
+            (old.sa_handler, old.sa_flags, old.sa_mask),
+            (0x1234, 0x4000000, 1)
+        );
+        let args = [LINUX_SIGKILL, act_addr, 0, 8];
+        assert_eq!(syscall(&mut m, SYS_RT_SIGACTION, &args), -EINVAL as u64);
+
+        assert_eq!(syscall(&mut m, SYS_MADVISE, &[BASE, len, 4]), 0);
+        assert_eq!(
+            syscall(&mut m, SYS_MADVISE, &[BASE + 1, len, 4]),
+            -EINVAL as u64
+        );
+        assert_eq!(
+            syscall(&mut m, SYS_IOCTL, &[99, LINUX_TCGETS, BASE]),
+            -libc::EBADF as u64
+        );
+        assert_eq!(
+            syscall(&mut m, SYS_IOCTL, &[1, 0x1234, BASE]),
+            -ENOTTY as u64
+        );
+    }
 }
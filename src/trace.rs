@@ -0,0 +1,121 @@
+use std::collections::HashSet;
+
+use crate::{
+    cache::{cache_block, Cache},
+    interp::{insn_is_branch, BlockInsn},
+    rvemu::{InsnType, Mmu},
+};
+
+pub const MAX_TRACE_INSNS: usize = 512;
+pub const MAX_TRACE_BLOCKS: usize = 16;
+
+#[derive(Clone, Copy)]
+pub struct TraceInsn {
+    pub entry: BlockInsn,
+    // the trace carries on at the branch/jump target instead of the next insn
+    pub follow: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceEnd {
+    // the last insn leaves the trace by itself (jal, jalr, ecall)
+    Terminator,
+    // fall through to a pc outside of the trace
+    Exit(u64),
+    // jump back to the head of the trace
+    Loop,
+}
+
+pub struct Trace {
+    pub pc: u64,
+    pub insns: Vec<TraceInsn>,
+    pub blocks: usize,
+    pub end: TraceEnd,
+}
+
+impl Trace {
+    pub fn new(pc: u64) -> Trace {
+        Trace {
+            pc,
+            insns: Vec::new(),
+            blocks: 0,
+            end: TraceEnd::Terminator,
+        }
+    }
+}
+
+fn insn_len(entry: &BlockInsn) -> u64 {
+    return if entry.insn.rvc { 2 } else { 4 };
+}
+
+fn branch_target(entry: &BlockInsn) -> u64 {
+    return entry.pc.wrapping_add(entry.insn.imm as u64);
+}
+
+// follow the hot successor of every conditional branch and direct jump,
+// starting from the block at pc, until the trace closes a loop, reaches an
+// indirect jump/ecall or grows too long.
+pub fn trace_form(cache: &mut Cache, mmu: &Mmu, pc: u64) -> Trace {
+    let mut trace = Trace::new(pc);
+    let mut visited: HashSet<u64> = HashSet::new();
+    let mut next = pc;
+
+    loop {
+        // the blocks have all run before, unless the code went away since
+        let block = match cache_block(cache, mmu, next) {
+            Ok(block) => block,
+            Err(_) => {
+                trace.end = TraceEnd::Exit(next);
+                break;
+            }
+        };
+        visited.insert(next);
+        trace.blocks += 1;
+
+        let mut successor = None;
+        for entry in block.insns.iter() {
+            let follow = if insn_is_branch(entry.insn.i_type) {
+                entry.taken > entry.not_taken
+            } else {
+                matches!(entry.insn.i_type, InsnType::InsnJal)
+            };
+            trace.insns.push(TraceInsn {
+                entry: *entry,
+                follow,
+            });
+
+            if follow {
+                successor = Some(branch_target(entry));
+                break;
+            }
+            if entry.insn.cont {
+                break;
+            }
+        }
+
+        let last = trace.insns.last().unwrap().entry;
+        let successor = match successor {
+            Some(pc) => pc,
+            None if last.insn.cont => {
+                trace.end = TraceEnd::Terminator;
+                break;
+            }
+            None => last.pc + insn_len(&last),
+        };
+
+        if successor == trace.pc {
+            trace.end = TraceEnd::Loop;
+            break;
+        }
+        if visited.contains(&successor)
+            || trace.blocks >= MAX_TRACE_BLOCKS
+            || trace.insns.len() >= MAX_TRACE_INSNS
+        {
+            trace.end = TraceEnd::Exit(successor);
+            break;
+        }
+        next = successor;
+    }
+
+    return trace;
+}
@@ -0,0 +1,344 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write,
+    fs,
+    mem::size_of,
+};
+
+use crate::{
+    decode::insn_decode_checked,
+    elfdef::{Ehdr, Phdr, EI_CLASS, ELFCLASS64, ELFMAG, EM_RISCV, PF_X, PT_LOAD},
+    interp::{insn_is_branch, MAX_BLOCK_INSNS},
+    rvemu::{Insn, InsnType},
+};
+
+pub struct Segment {
+    pub vaddr: u64,
+    pub data: Vec<u8>,
+}
+
+pub struct Image {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+}
+
+pub fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
+pub fn image_load(buf: &[u8]) -> Result<Image, String> {
+    if buf.len() < size_of::<Ehdr>() || ELFMAG[..] != buf[..4] {
+        return Err("bad elf file".to_string());
+    }
+    let ehdr: Ehdr = unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const Ehdr) };
+    if ehdr.e_machine != EM_RISCV || ehdr.e_ident[EI_CLASS] != ELFCLASS64 {
+        return Err("only riscv64 elf file is supported".to_string());
+    }
+
+    let mut image = Image {
+        entry: ehdr.e_entry,
+        segments: Vec::new(),
+    };
+    for i in 0..ehdr.e_phnum as u64 {
+        let off = (ehdr.e_phoff + i * ehdr.e_phentsize as u64) as usize;
+        if off + size_of::<Phdr>() > buf.len() {
+            return Err("file too small".to_string());
+        }
+        let phdr: Phdr = unsafe { std::ptr::read_unaligned(buf[off..].as_ptr() as *const Phdr) };
+        if phdr.p_type != PT_LOAD || (phdr.p_flags as i32 & PF_X) == 0 {
+            continue;
+        }
+        let start = phdr.p_offset as usize;
+        let end = start + phdr.p_filesz as usize;
+        if end > buf.len() {
+            return Err("segment out of file".to_string());
+        }
+        image.segments.push(Segment {
+            vaddr: phdr.p_vaddr,
+            data: buf[start..end].to_vec(),
+        });
+    }
+    return Ok(image);
+}
+
+fn image_fetch(image: &Image, pc: u64) -> Option<u32> {
+    for seg in image.segments.iter() {
+        if pc < seg.vaddr || pc + 2 > seg.vaddr + seg.data.len() as u64 {
+            continue;
+        }
+        let off = (pc - seg.vaddr) as usize;
+        let mut bytes = [0u8; 4];
+        let n = (seg.data.len() - off).min(4);
+        bytes[..n].copy_from_slice(&seg.data[off..off + n]);
+        return Some(u32::from_le_bytes(bytes));
+    }
+    return None;
+}
+
+pub struct AotBlock {
+    pub insns: Vec<(u64, u32, Insn)>,
+    // pc to continue at when the block does not end in a jump or ecall
+    pub next: Option<u64>,
+}
+
+fn translate_block(image: &Image, pc: u64) -> AotBlock {
+    let mut block = AotBlock {
+        insns: Vec::new(),
+        next: None,
+    };
+    let mut pc = pc;
+    while block.insns.len() < MAX_BLOCK_INSNS {
+        let insn = image_fetch(image, pc).and_then(|data| Some((data, insn_decode_checked(data)?)));
+        let (data, insn) = match insn {
+            Some(insn) => insn,
+            None => {
+                block.next = Some(pc);
+                return block;
+            }
+        };
+        block.insns.push((pc, data, insn));
+        if insn.cont {
+            return block;
+        }
+        pc += if insn.rvc { 2 } else { 4 };
+    }
+    block.next = Some(pc);
+    return block;
+}
+
+// every pc the runtime may dispatch on after leaving a block
+fn block_successors(block: &AotBlock) -> Vec<u64> {
+    let mut succ = Vec::new();
+    for (pc, _, insn) in block.insns.iter() {
+        let next = pc + if insn.rvc { 2 } else { 4 };
+        match insn.i_type {
+            InsnType::InsnJal => {
+                succ.push(pc.wrapping_add(insn.imm as u64));
+                if insn.rd != 0 {
+                    succ.push(next);
+                }
+            }
+            InsnType::InsnJalr if insn.rd != 0 => succ.push(next),
+            InsnType::InsnEcall => succ.push(pc + 4),
+            typ if insn_is_branch(typ) => succ.push(pc.wrapping_add(insn.imm as u64)),
+            _ => {}
+        }
+    }
+    succ.extend(block.next);
+    return succ;
+}
+
+pub fn translate_recover(image: &Image) -> BTreeMap<u64, AotBlock> {
+    let mut blocks = BTreeMap::new();
+    let mut work = VecDeque::from([image.entry]);
+    while let Some(pc) = work.pop_front() {
+        if blocks.contains_key(&pc) || image_fetch(image, pc).is_none() {
+            continue;
+        }
+        let block = translate_block(image, pc);
+        work.extend(block_successors(&block));
+        blocks.insert(pc, block);
+    }
+    return blocks;
+}
+
+fn x(reg: i8) -> String {
+    return format!("state.gp_regs[{}]", reg);
+}
+
+fn emit_insn(out: &mut String, pc: u64, insn: &Insn) {
+    let next = pc + if insn.rvc { 2 } else { 4 };
+    let (rd, rs1, rs2, imm) = (insn.rd, x(insn.rs1), x(insn.rs2), insn.imm as i64);
+    let set = |expr: String| {
+        if rd == 0 {
+            String::new()
+        } else {
+            format!("    {} = {};\n", x(rd), expr)
+        }
+    };
+    // loads and stores record their pc first, for the fault handler, and
+    // apply the misaligned policy
+    let addr = |size: u64, store: bool| {
+        let check = if size == 1 {
+            String::new()
+        } else {
+            format!(
+                " if addr & {:#x} != 0 && mem_misaligned(state, addr, {}, {}) {{ return; }}",
+                size - 1,
+                size,
+                store
+            )
+        };
+        format!(
+            "state.pc = {:#x}; let addr = {}.wrapping_add({:#x}); if mem_bounds(state, addr, {}) {{ return; }}{}",
+            pc, rs1, imm as u64, store, check
+        )
+    };
+    let load = |ty: &str, size: u64| {
+        format!(
+            "{{ {} (unsafe {{ (get_ptr(to_host!(state.host_base, addr)) as *const {}).read_unaligned() }}) as u64 }}",
+            addr(size, false),
+            ty
+        )
+    };
+    // a store over code the cache holds ends the block
+    let store = |ty: &str, size: u64| {
+        format!(
+            "    {{ {} unsafe {{ (get_ptr(to_host!(state.host_base, addr)) as *mut {}).write_unaligned({} as {}) }}; if mem_code(state, addr, {}, {}) {{ return; }} }}\n",
+            addr(size, true),
+            ty,
+            rs2,
+            ty,
+            size,
+            if insn.rvc { 2 } else { 4 }
+        )
+    };
+    let branch = |cond: String| {
+        let target = pc.wrapping_add(imm as u64);
+        format!(
+            "    if {} {{\n        return exit(state, ExitReason::DirectBranch, {:#x}, {:#x});\n    }}\n",
+            cond, target, target
+        )
+    };
+
+    let code = match insn.i_type {
+        InsnType::InsnFence => String::new(),
+        InsnType::InsnLui => set(format!("{:#x}", imm as u64)),
+        InsnType::InsnAuipc => set(format!("{:#x}", pc.wrapping_add(imm as u64))),
+        InsnType::InsnAddi => set(format!("{}.wrapping_add({:#x})", rs1, imm as u64)),
+        InsnType::InsnSlti => set(format!("(({} as i64) < {}) as u64", rs1, imm)),
+        InsnType::InsnXori => set(format!("{} ^ {:#x}", rs1, imm as u64)),
+        InsnType::InsnOri => set(format!("{} | {:#x}", rs1, imm as u64)),
+        InsnType::InsnAndi => set(format!("{} & {:#x}", rs1, imm as u64)),
+        InsnType::InsnSlli => set(format!("{} << {}", rs1, imm & 0x3f)),
+        InsnType::InsnSrli => set(format!("{} >> {}", rs1, imm & 0x3f)),
+        InsnType::InsnAdd => set(format!("{}.wrapping_add({})", rs1, rs2)),
+        InsnType::InsnSub => set(format!("{}.wrapping_sub({})", rs1, rs2)),
+        InsnType::InsnMul => set(format!("{}.wrapping_mul({})", rs1, rs2)),
+        InsnType::InsnXor => set(format!("{} ^ {}", rs1, rs2)),
+        InsnType::InsnOr => set(format!("{} | {}", rs1, rs2)),
+        InsnType::InsnAnd => set(format!("{} & {}", rs1, rs2)),
+        InsnType::InsnSll => set(format!("{} << ({} & 0x3f)", rs1, rs2)),
+        InsnType::InsnSrl => set(format!("{} >> ({} & 0x3f)", rs1, rs2)),
+        InsnType::InsnSra => set(format!("(({} as i64) >> ({} & 0x3f)) as u64", rs1, rs2)),
+        InsnType::InsnSraw => set(format!("(({} as i32) >> ({} & 0x1f)) as u64", rs1, rs2)),
+        InsnType::InsnSlt => set(format!("(({} as i64) < ({} as i64)) as u64", rs1, rs2)),
+        InsnType::InsnSltu => set(format!("({} < {}) as u64", rs1, rs2)),
+        InsnType::InsnLb => set(load("i8", 1)),
+        InsnType::InsnLh => set(load("i16", 2)),
+        InsnType::InsnLw => set(load("i32", 4)),
+        InsnType::InsnLd => set(load("i64", 8)),
+        InsnType::InsnLbu => set(load("u8", 1)),
+        InsnType::InsnLhu => set(load("u16", 2)),
+        InsnType::InsnLwu => set(load("u32", 4)),
+        InsnType::InsnSb => store("u8", 1),
+        InsnType::InsnSh => store("u16", 2),
+        InsnType::InsnSw => store("u32", 4),
+        InsnType::InsnSd => store("u64", 8),
+        InsnType::InsnBeq => branch(format!("{} == {}", rs1, rs2)),
+        InsnType::InsnBne => branch(format!("{} != {}", rs1, rs2)),
+        InsnType::InsnBltu => branch(format!("{} < {}", rs1, rs2)),
+        InsnType::InsnBgeu => branch(format!("{} >= {}", rs1, rs2)),
+        InsnType::InsnJal => {
+            let target = pc.wrapping_add(imm as u64);
+            format!(
+                "{}    return exit(state, ExitReason::IndirectBranch, {:#x}, {:#x});\n",
+                set(format!("{:#x}", next)),
+                target,
+                target
+            )
+        }
+        InsnType::InsnJalr => {
+            format!(
+                "    let target = {}.wrapping_add({:#x}) & !1;\n{}    return exit(state, ExitReason::IndirectBranch, {:#x}, target);\n",
+                rs1,
+                imm as u64,
+                set(format!("{:#x}", next)),
+                pc
+            )
+        }
+        InsnType::InsnEcall => format!(
+            "    return exit(state, ExitReason::Ecall, {:#x}, {:#x});\n",
+            pc,
+            pc + 4
+        ),
+        _ => format!(
+            "    if fallback(state, {:#x}, Insn {{ rd: {}, rs1: {}, rs2: {}, rs3: {}, imm: {}, csr: {}, i_type: InsnType::{:?}, rvc: {}, cont: {} }}) {{\n        return;\n    }}\n",
+            pc, insn.rd, insn.rs1, insn.rs2, insn.rs3, insn.imm, insn.csr, insn.i_type, insn.rvc, insn.cont
+        ),
+    };
+
+    writeln!(out, "    // {:x}: {:?}", pc, insn.i_type).unwrap();
+    out.push_str(&code);
+}
+
+const PRELUDE: &str = "use crate::{
+    interp::{mem_bounds, mem_code, mem_misaligned, FUNCS},
+    rvemu::{get_ptr, ExitReason, Insn, InsnType, State},
+    to_host,
+};
+
+#[inline(always)]
+fn exit(state: &mut State, reason: ExitReason, pc: u64, reenter_pc: u64) {
+    state.pc = pc;
+    state.reenter_pc = reenter_pc;
+    state.exit_reason = reason;
+}
+
+#[inline(always)]
+fn fallback(state: &mut State, pc: u64, mut insn: Insn) -> bool {
+    state.pc = pc;
+    FUNCS[insn.i_type as usize](state, &mut insn);
+    state.gp_regs[0] = 0;
+    return state.exit_reason != ExitReason::None;
+}
+";
+
+pub fn translate_emit(image: &Image, hash: u64, blocks: &BTreeMap<u64, AotBlock>) -> String {
+    let mut out = String::new();
+    writeln!(out, "// generated by rvemu-rs translate, do not edit").unwrap();
+    writeln!(out, "{}", PRELUDE).unwrap();
+    writeln!(out, "pub const AOT_HASH: u64 = {:#x};", hash).unwrap();
+    writeln!(out, "pub const AOT_ENTRY: u64 = {:#x};\n", image.entry).unwrap();
+
+    for (pc, block) in blocks.iter() {
+        writeln!(out, "fn block_{:x}(state: &mut State) {{", pc).unwrap();
+        for (pc, _, insn) in block.insns.iter() {
+            emit_insn(&mut out, *pc, insn);
+        }
+        if let Some(next) = block.next {
+            writeln!(
+                out,
+                "    exit(state, ExitReason::DirectBranch, {:#x}, {:#x});",
+                next, next
+            )
+            .unwrap();
+        }
+        writeln!(out, "}}\n").unwrap();
+    }
+
+    writeln!(out, "pub fn aot_exec(state: &mut State) -> bool {{").unwrap();
+    writeln!(out, "    match state.pc {{").unwrap();
+    for pc in blocks.keys() {
+        writeln!(out, "        {:#x} => block_{:x}(state),", pc, pc).unwrap();
+    }
+    writeln!(out, "        _ => return false,").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "    return true;").unwrap();
+    writeln!(out, "}}").unwrap();
+    return out;
+}
+
+pub fn translate(prog: &str, output: &str) -> Result<usize, String> {
+    let buf = fs::read(prog).map_err(|e| format!("{}: {}", prog, e))?;
+    let image = image_load(&buf)?;
+    let blocks = translate_recover(&image);
+    let source = translate_emit(&image, fnv1a(&buf), &blocks);
+    fs::write(output, source).map_err(|e| format!("{}: {}", output, e))?;
+    return Ok(blocks.len());
+}
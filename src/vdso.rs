@@ -0,0 +1,242 @@
+use std::{mem::size_of, slice};
+
+use crate::{
+    elfdef::{
+        elf_hash, Dyn, Ehdr, Phdr, Sym, Verdaux, Verdef, DT_HASH, DT_NULL, DT_SONAME, DT_STRSZ,
+        DT_STRTAB, DT_SYMENT, DT_SYMTAB, DT_VERDEF, DT_VERDEFNUM, DT_VERSYM,
+        EF_RISCV_FLOAT_ABI_DOUBLE, EF_RISCV_RVC, ELFCLASS64, ELFDATA2LSB, ELFMAG, EM_RISCV, ET_DYN,
+        EV_CURRENT, PF_R, PF_X, PROT_EXEC, PROT_READ, PROT_WRITE, PT_DYNAMIC, PT_LOAD, STB_GLOBAL,
+        STT_FUNC, VER_FLG_BASE,
+    },
+    mmu::mmu_copy_in,
+    round_up,
+    rvemu::Mmu,
+    sys_call::{
+        SYS_CLOCK_GETRES, SYS_CLOCK_GETTIME, SYS_GETCPU, SYS_GETTIMEOFDAY, SYS_RISCV_FLUSH_ICACHE,
+        SYS_RT_SIGRETURN,
+    },
+    vma::{vma_map, vma_protect, LINUX_MAP_ANONYMOUS, LINUX_MAP_PRIVATE},
+};
+
+const VDSO_SONAME: &str = "linux-vdso.so.1";
+const VDSO_VERSION: &str = "LINUX_4.15";
+
+// the code comes right after the program headers, so the sigreturn
+// trampoline, the first function, is at a fixed offset
+const VDSO_TEXT: u64 = (size_of::<Ehdr>() + 2 * size_of::<Phdr>()) as u64;
+
+// what the riscv linux vdso exports, each one a plain syscall into the
+// emulator. sigreturn does not return.
+const VDSO_FUNCS: [(&str, usize, bool); 6] = [
+    ("__vdso_rt_sigreturn", SYS_RT_SIGRETURN, false),
+    ("__vdso_gettimeofday", SYS_GETTIMEOFDAY, true),
+    ("__vdso_clock_gettime", SYS_CLOCK_GETTIME, true),
+    ("__vdso_clock_getres", SYS_CLOCK_GETRES, true),
+    ("__vdso_getcpu", SYS_GETCPU, true),
+    ("__vdso_flush_icache", SYS_RISCV_FLUSH_ICACHE, true),
+];
+
+fn vdso_push<T: Copy>(buf: &mut Vec<u8>, val: T) {
+    let bytes = unsafe { slice::from_raw_parts(&val as *const T as *const u8, size_of::<T>()) };
+    buf.extend_from_slice(bytes);
+}
+
+fn vdso_align(buf: &mut Vec<u8>, align: usize) {
+    buf.resize(round_up!(buf.len(), align) as usize, 0);
+}
+
+// li a7, nr; ecall; ret
+fn vdso_code(nr: usize, ret: bool) -> Vec<u32> {
+    let mut code = vec![((nr as u32) << 20) | (17 << 7) | 0x13, 0x00000073];
+    if ret {
+        code.push(0x00008067);
+    }
+    return code;
+}
+
+// a minimal shared object: one PT_LOAD holding the code, a sysv hash
+// table, the dynamic symbols with their LINUX_4.15 version and the
+// dynamic section glibc and musl look them up through. there are no
+// section headers, st_shndx only has to be defined.
+pub fn vdso_image() -> Vec<u8> {
+    let mut buf = vec![0u8; VDSO_TEXT as usize];
+
+    let mut values = Vec::new();
+    for (_, nr, ret) in VDSO_FUNCS {
+        vdso_align(&mut buf, 16);
+        values.push((buf.len() as u64, vdso_code(nr, ret).len() as u64 * 4));
+        for insn in vdso_code(nr, ret) {
+            vdso_push(&mut buf, insn);
+        }
+    }
+
+    let mut strtab = vec![0u8];
+    let mut add_str = |s: &str| {
+        let off = strtab.len() as u32;
+        strtab.extend_from_slice(s.as_bytes());
+        strtab.push(0);
+        return off;
+    };
+    let soname = add_str(VDSO_SONAME);
+    let version = add_str(VDSO_VERSION);
+    let names: Vec<u32> = VDSO_FUNCS
+        .iter()
+        .map(|(name, _, _)| add_str(name))
+        .collect();
+    let nsyms = VDSO_FUNCS.len() as u32 + 1;
+
+    // one bucket chaining through every symbol, any name hashes to it
+    vdso_align(&mut buf, 8);
+    let hash = buf.len() as u64;
+    for word in [1, nsyms, nsyms - 1] {
+        vdso_push(&mut buf, word);
+    }
+    for i in 0..nsyms {
+        vdso_push(&mut buf, i.saturating_sub(1));
+    }
+
+    vdso_align(&mut buf, 8);
+    let symtab = buf.len() as u64;
+    vdso_push(
+        &mut buf,
+        Sym {
+            st_name: 0,
+            st_info: 0,
+            st_other: 0,
+            st_shndx: 0,
+            st_value: 0,
+            st_size: 0,
+        },
+    );
+    for (name, (value, size)) in names.iter().zip(values.iter()) {
+        let sym = Sym {
+            st_name: *name,
+            st_info: (STB_GLOBAL << 4) | STT_FUNC,
+            st_other: 0,
+            st_shndx: 1,
+            st_value: *value,
+            st_size: *size,
+        };
+        vdso_push(&mut buf, sym);
+    }
+
+    // version 1 is the object itself, 2 the one every symbol has
+    let versym = buf.len() as u64;
+    vdso_push(&mut buf, 0u16);
+    for _ in 1..nsyms {
+        vdso_push(&mut buf, 2u16);
+    }
+    vdso_align(&mut buf, 4);
+    let verdef = buf.len() as u64;
+    let entry = (size_of::<Verdef>() + size_of::<Verdaux>()) as u32;
+    for (ndx, name, flags) in [(1, soname, VER_FLG_BASE), (2, version, 0)] {
+        let text = if ndx == 1 { VDSO_SONAME } else { VDSO_VERSION };
+        let def = Verdef {
+            vd_version: 1,
+            vd_flags: flags,
+            vd_ndx: ndx,
+            vd_cnt: 1,
+            vd_hash: elf_hash(text),
+            vd_aux: size_of::<Verdef>() as u32,
+            vd_next: if ndx == 1 { entry } else { 0 },
+        };
+        vdso_push(&mut buf, def);
+        vdso_push(
+            &mut buf,
+            Verdaux {
+                vda_name: name,
+                vda_next: 0,
+            },
+        );
+    }
+
+    let strtab_off = buf.len() as u64;
+    buf.extend_from_slice(&strtab);
+
+    vdso_align(&mut buf, 8);
+    let dynamic = buf.len() as u64;
+    let dyns = [
+        (DT_HASH, hash),
+        (DT_STRTAB, strtab_off),
+        (DT_SYMTAB, symtab),
+        (DT_STRSZ, strtab.len() as u64),
+        (DT_SYMENT, size_of::<Sym>() as u64),
+        (DT_SONAME, soname as u64),
+        (DT_VERSYM, versym),
+        (DT_VERDEF, verdef),
+        (DT_VERDEFNUM, 2),
+        (DT_NULL, 0),
+    ];
+    for (d_tag, d_val) in dyns {
+        vdso_push(&mut buf, Dyn { d_tag, d_val });
+    }
+    let len = buf.len() as u64;
+
+    let mut e_ident = [0u8; 16];
+    e_ident[..4].copy_from_slice(ELFMAG);
+    e_ident[4] = ELFCLASS64;
+    e_ident[5] = ELFDATA2LSB;
+    e_ident[6] = EV_CURRENT;
+    let ehdr = Ehdr {
+        e_ident,
+        e_type: ET_DYN,
+        e_machine: EM_RISCV,
+        e_version: EV_CURRENT as u32,
+        e_entry: 0,
+        e_phoff: size_of::<Ehdr>() as u64,
+        e_shoff: 0,
+        e_flags: EF_RISCV_RVC | EF_RISCV_FLOAT_ABI_DOUBLE,
+        e_ehsiz: size_of::<Ehdr>() as u16,
+        e_phentsize: size_of::<Phdr>() as u16,
+        e_phnum: 2,
+        e_shentsize: 0,
+        e_shnum: 0,
+        e_shstrndx: 0,
+    };
+    let load = Phdr {
+        p_type: PT_LOAD,
+        p_flags: (PF_R | PF_X) as u32,
+        p_offset: 0,
+        p_vaddr: 0,
+        p_paddr: 0,
+        p_filesz: len,
+        p_memsz: len,
+        p_align: page_size::get() as u64,
+    };
+    let dynamic = Phdr {
+        p_type: PT_DYNAMIC,
+        p_flags: PF_R as u32,
+        p_offset: dynamic,
+        p_vaddr: dynamic,
+        p_paddr: dynamic,
+        p_filesz: len - dynamic,
+        p_memsz: len - dynamic,
+        p_align: 8,
+    };
+    let mut head = Vec::new();
+    vdso_push(&mut head, ehdr);
+    vdso_push(&mut head, load);
+    vdso_push(&mut head, dynamic);
+    buf[..head.len()].copy_from_slice(&head);
+    return buf;
+}
+
+// map the vdso where mmap would put it, for AT_SYSINFO_EHDR
+pub fn vdso_map(mmu: &mut Mmu) -> Result<(), String> {
+    let image = vdso_image();
+    let len = image.len() as u64;
+    let flags = LINUX_MAP_PRIVATE | LINUX_MAP_ANONYMOUS;
+    let addr = vma_map(mmu, 0, len, PROT_READ | PROT_WRITE, flags, -1, 0)
+        .map_err(|_| "no room for the vdso".to_string())?;
+    mmu_copy_in(mmu, addr, &image).unwrap();
+    vma_protect(mmu, addr, len, PROT_READ | PROT_EXEC).unwrap();
+    mmu.vmas.get_mut(&addr).unwrap().name = "[vdso]".to_string();
+    mmu.vdso = addr;
+    return Ok(());
+}
+
+// where signal delivery points ra, so the handler returns into
+// rt_sigreturn
+pub fn vdso_sigreturn(mmu: &Mmu) -> u64 {
+    return mmu.vdso + VDSO_TEXT;
+}
@@ -0,0 +1,321 @@
+use std::{env, fs, io, path::PathBuf};
+
+use libc::{EACCES, ELOOP, ENAMETOOLONG, ENOENT, ENOTDIR, EROFS};
+
+// a host directory, or file, seen by the guest at guest
+pub struct Mount {
+    pub guest: String,
+    pub host: PathBuf,
+    pub read_only: bool,
+}
+
+// the guest's view of the filesystem, when it should not see all of the
+// host's. every path the guest passes in is resolved here one component
+// at a time, so neither ".." nor a symlink can take it outside what is
+// mounted. the guest is single threaded, it cannot change a path between
+// the check and the use.
+pub struct Vfs {
+    // a path goes through the mount with the longest guest path it is under
+    pub mounts: Vec<Mount>,
+    // guest paths the guest cannot touch, or anything under them
+    pub deny: Vec<String>,
+    // the guest's working directory, a guest path
+    pub cwd: String,
+}
+
+// what a guest path comes to
+pub struct Resolved {
+    pub guest: String,
+    pub host: PathBuf,
+    pub read_only: bool,
+}
+
+// as on linux
+const MAX_SYMLINKS: usize = 40;
+const PATH_MAX: usize = 4096;
+
+impl Vfs {
+    pub fn new() -> Vfs {
+        let cwd = env::current_dir().map_or("/".to_string(), |cwd| cwd.to_string_lossy().into());
+        return Vfs {
+            mounts: Vec::new(),
+            deny: Vec::new(),
+            cwd,
+        };
+    }
+}
+
+impl Default for Vfs {
+    fn default() -> Vfs {
+        return Vfs::new();
+    }
+}
+
+// with nothing mounted or denied guest paths are host paths
+pub fn vfs_enabled(vfs: &Vfs) -> bool {
+    return !vfs.mounts.is_empty() || !vfs.deny.is_empty();
+}
+
+// a guest path from the command line, absolute with no ".", ".." or
+// empty components
+fn vfs_normalize(path: &str) -> Result<String, String> {
+    if !path.starts_with('/') {
+        return Err(format!("not an absolute path: {}", path));
+    }
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    return Ok(format!("/{}", parts.join("/")));
+}
+
+// --root, --bind and --ro-bind: host shows up at guest. once something is
+// mounted at "/" the guest starts there.
+pub fn vfs_mount(vfs: &mut Vfs, host: &str, guest: &str, read_only: bool) -> Result<(), String> {
+    let guest = vfs_normalize(guest)?;
+    let host = fs::canonicalize(host).map_err(|e| format!("{}: {}", host, e))?;
+    if guest == "/" {
+        vfs.cwd = guest.clone();
+    }
+    vfs.mounts.retain(|mount| mount.guest != guest);
+    vfs.mounts.push(Mount {
+        guest,
+        host,
+        read_only,
+    });
+    return Ok(());
+}
+
+// --deny
+pub fn vfs_deny(vfs: &mut Vfs, guest: &str) -> Result<(), String> {
+    vfs.deny.push(vfs_normalize(guest)?);
+    return Ok(());
+}
+
+// whether guest is prefix or below it
+fn vfs_under(guest: &str, prefix: &str) -> bool {
+    if prefix == "/" || guest == prefix {
+        return true;
+    }
+    return guest.starts_with(prefix) && guest.as_bytes().get(prefix.len()) == Some(&b'/');
+}
+
+// the host path of a guest path and whether it is read-only. with no
+// mounts that is the path itself, with some a path under none of them is
+// not there.
+fn vfs_host(vfs: &Vfs, guest: &str) -> Option<(PathBuf, bool)> {
+    if vfs.mounts.is_empty() {
+        return Some((PathBuf::from(guest), false));
+    }
+    let mount = vfs
+        .mounts
+        .iter()
+        .filter(|mount| vfs_under(guest, &mount.guest))
+        .max_by_key(|mount| mount.guest.len())?;
+    let rest = guest[mount.guest.len()..].trim_start_matches('/');
+    let host = match rest {
+        "" => mount.host.clone(),
+        _ => mount.host.join(rest),
+    };
+    return Some((host, mount.read_only));
+}
+
+fn vfs_denied(vfs: &Vfs, guest: &str) -> bool {
+    return vfs.deny.iter().any(|deny| vfs_under(guest, deny));
+}
+
+fn vfs_errno(e: io::Error) -> i32 {
+    return e.raw_os_error().unwrap_or(ENOENT);
+}
+
+// path as the kernel would resolve it chrooted: relative to base, ".."
+// stopping at "/", and a symlink read and walked in the guest's view, from
+// "/" when it is absolute. follow is whether a symlink as the last
+// component is followed. the last component need not exist, it may be
+// what the call creates.
+pub fn vfs_resolve(vfs: &Vfs, base: &str, path: &str, follow: bool) -> Result<Resolved, i32> {
+    if path.is_empty() {
+        return Err(ENOENT);
+    }
+    if path.len() >= PATH_MAX {
+        return Err(ENAMETOOLONG);
+    }
+    let split = |path: &str| -> Vec<String> {
+        return path
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .rev()
+            .map(String::from)
+            .collect();
+    };
+    let mut parts: Vec<String> = match path.starts_with('/') {
+        true => Vec::new(),
+        false => split(base).into_iter().rev().collect(),
+    };
+    // what is left to walk, the next component last
+    let mut todo = split(path);
+    // "dir/" names the directory a symlink points to
+    let follow = follow || path.ends_with('/');
+    let mut links = 0;
+    while let Some(part) = todo.pop() {
+        match part.as_str() {
+            "." => continue,
+            ".." => {
+                parts.pop();
+                continue;
+            }
+            _ => parts.push(part),
+        }
+        let guest = format!("/{}", parts.join("/"));
+        if vfs_denied(vfs, &guest) {
+            return Err(EACCES);
+        }
+        let (host, _) = vfs_host(vfs, &guest).ok_or(ENOENT)?;
+        let last = todo.is_empty();
+        let meta = match fs::symlink_metadata(&host) {
+            Ok(meta) => meta,
+            Err(e) if last && e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(vfs_errno(e)),
+        };
+        if meta.file_type().is_symlink() && (follow || !last) {
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(ELOOP);
+            }
+            let target = fs::read_link(&host).map_err(vfs_errno)?;
+            let target = target.to_string_lossy();
+            parts.pop();
+            if target.starts_with('/') {
+                parts.clear();
+            }
+            todo.extend(split(&target));
+        } else if !last && !meta.is_dir() {
+            return Err(ENOTDIR);
+        }
+    }
+    let guest = format!("/{}", parts.join("/"));
+    // ".." can come back up to a denied directory
+    if vfs_denied(vfs, &guest) {
+        return Err(EACCES);
+    }
+    let (host, read_only) = vfs_host(vfs, &guest).ok_or(ENOENT)?;
+    return Ok(Resolved {
+        guest,
+        host,
+        read_only,
+    });
+}
+
+// for the calls that change what a path names
+pub fn vfs_writable(resolved: &Resolved) -> Result<(), i32> {
+    if resolved.read_only {
+        return Err(EROFS);
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    // root/ with etc/passwd, a bind mounted data/, and links trying to get
+    // at secret next to root
+    fn vfs_tree(name: &str) -> (PathBuf, Vfs) {
+        let dir = env::temp_dir().join(format!("rvemu-vfs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root/etc")).unwrap();
+        fs::create_dir_all(dir.join("root/mnt")).unwrap();
+        fs::create_dir_all(dir.join("data")).unwrap();
+        fs::write(dir.join("root/etc/passwd"), "root").unwrap();
+        fs::write(dir.join("secret"), "secret").unwrap();
+        fs::write(dir.join("data/file"), "data").unwrap();
+        symlink("../../secret", dir.join("root/etc/up")).unwrap();
+        symlink("/etc/passwd", dir.join("root/abs")).unwrap();
+        symlink(dir.join("secret"), dir.join("root/host")).unwrap();
+        symlink("loop", dir.join("root/loop")).unwrap();
+        symlink("/mnt/file", dir.join("root/etc/data")).unwrap();
+
+        let mut vfs = Vfs::new();
+        let root = dir.join("root");
+        vfs_mount(&mut vfs, root.to_str().unwrap(), "/", false).unwrap();
+        let data = dir.join("data");
+        vfs_mount(&mut vfs, data.to_str().unwrap(), "/mnt", true).unwrap();
+        return (fs::canonicalize(dir).unwrap(), vfs);
+    }
+
+    fn guest(vfs: &Vfs, path: &str) -> Result<String, i32> {
+        return vfs_resolve(vfs, "/etc", path, true).map(|resolved| resolved.guest);
+    }
+
+    #[test]
+    fn vfs_dotdot() {
+        let (dir, vfs) = vfs_tree("dotdot");
+        assert_eq!(guest(&vfs, "../../../etc/passwd"), Ok("/etc/passwd".into()));
+        assert_eq!(guest(&vfs, "/../.."), Ok("/".into()));
+        assert_eq!(guest(&vfs, "passwd/.."), Err(ENOTDIR));
+        let resolved = vfs_resolve(&vfs, "/", "/..", true).unwrap();
+        assert_eq!(resolved.host, dir.join("root"));
+    }
+
+    #[test]
+    fn vfs_symlinks() {
+        let (dir, vfs) = vfs_tree("symlinks");
+        // the ".." in a relative link stops at the guest's "/"
+        assert_eq!(guest(&vfs, "up"), Ok("/secret".into()));
+        assert!(!vfs_resolve(&vfs, "/", "/etc/up", true)
+            .unwrap()
+            .host
+            .exists());
+        // absolute links are guest paths, whatever the host has there
+        assert_eq!(guest(&vfs, "/abs"), Ok("/etc/passwd".into()));
+        // so a link to the host's copy leads nowhere
+        assert_eq!(guest(&vfs, "/host"), Err(ENOENT));
+        assert_eq!(guest(&vfs, "/loop"), Err(ELOOP));
+        // not followed at the end unless asked
+        let resolved = vfs_resolve(&vfs, "/", "/abs", false).unwrap();
+        assert_eq!(resolved.host, dir.join("root/abs"));
+        assert_eq!(guest(&vfs, "/abs/"), Ok("/etc/passwd".into()));
+    }
+
+    #[test]
+    fn vfs_mounts() {
+        let (dir, mut vfs) = vfs_tree("mounts");
+        let resolved = vfs_resolve(&vfs, "/", "/etc/data", true).unwrap();
+        assert_eq!(resolved.host, dir.join("data/file"));
+        assert_eq!(vfs_writable(&resolved), Err(EROFS));
+        let resolved = vfs_resolve(&vfs, "/", "/etc/new", true).unwrap();
+        assert_eq!(resolved.host, dir.join("root/etc/new"));
+        assert_eq!(vfs_writable(&resolved), Ok(()));
+        assert_eq!(guest(&vfs, "/etc/missing/file"), Err(ENOENT));
+
+        vfs_deny(&mut vfs, "/etc/passwd").unwrap();
+        vfs_deny(&mut vfs, "/mnt").unwrap();
+        assert_eq!(guest(&vfs, "passwd"), Err(EACCES));
+        assert_eq!(guest(&vfs, "/abs"), Err(EACCES));
+        assert_eq!(guest(&vfs, "data"), Err(EACCES));
+        assert_eq!(guest(&vfs, "/mnt/../etc"), Err(EACCES));
+        assert_eq!(guest(&vfs, "/etc/passwdx"), Ok("/etc/passwdx".into()));
+    }
+
+    #[test]
+    fn vfs_unmounted() {
+        let mut vfs = Vfs::new();
+        assert!(!vfs_enabled(&vfs));
+        vfs_deny(&mut vfs, "/proc").unwrap();
+        assert!(vfs_enabled(&vfs));
+        let resolved = vfs_resolve(&vfs, "/", "/etc/../tmp", true).unwrap();
+        assert_eq!(resolved.host, PathBuf::from("/tmp"));
+        assert_eq!(guest(&vfs, "/proc/self"), Err(EACCES));
+        let mut vfs = Vfs::new();
+        vfs_mount(&mut vfs, "/tmp", "/tmp", false).unwrap();
+        assert_eq!(guest(&vfs, "/etc/passwd"), Err(ENOENT));
+    }
+}
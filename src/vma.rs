@@ -0,0 +1,740 @@
+use std::{fmt::Write, os::raw::c_void};
+
+use libc::{
+    mmap, mprotect, mremap, munmap, EEXIST, EFAULT, EINVAL, ENOMEM, MAP_ANONYMOUS, MAP_FAILED,
+    MAP_FIXED, MAP_NORESERVE, MAP_PRIVATE, MAP_SHARED, MREMAP_FIXED, MREMAP_MAYMOVE,
+};
+
+use crate::{
+    elfdef::{PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE},
+    max, min,
+    mmu::GUEST_SPACE,
+    round_down, round_up,
+    rvemu::{get_ptr, Mmu},
+    to_guest, to_host,
+};
+
+// mmap flags as the guest passes them, the host ones may differ
+pub const LINUX_MAP_SHARED: i32 = 0x01;
+pub const LINUX_MAP_PRIVATE: i32 = 0x02;
+pub const LINUX_MAP_FIXED: i32 = 0x10;
+pub const LINUX_MAP_ANONYMOUS: i32 = 0x20;
+pub const LINUX_MAP_NORESERVE: i32 = 0x4000;
+pub const LINUX_MAP_FIXED_NOREPLACE: i32 = 0x100000;
+
+pub const LINUX_MREMAP_MAYMOVE: i32 = 0x1;
+pub const LINUX_MREMAP_FIXED: i32 = 0x2;
+
+// linux defaults for RLIMIT_STACK and stack_guard_gap
+pub const STACK_LIMIT: u64 = 8 << 20;
+pub const STACK_GAP: u64 = 256 << 12;
+
+// mmap_base leaves at least this much room for the stack and at most this
+// share of the address space, like MIN_GAP and MAX_GAP in linux
+const MMAP_MIN_GAP: u64 = 128 << 20;
+const MMAP_MAX_GAP: u64 = GUEST_SPACE / 6 * 5;
+
+// how far ASLR moves the stack, mmap_base and brk, the riscv64 defaults
+const STACK_RND: u64 = 1 << 30;
+const MMAP_RND: u64 = 1 << 30;
+const BRK_RND: u64 = 1 << 30;
+
+// a guest mapping, page aligned. the pages mmu_alloc maps for the heap
+// are [heap] mappings, the break itself is the bump pointer in Mmu.
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub prot: i32,
+    pub shared: bool,
+    pub anonymous: bool,
+    // file offset of start
+    pub offset: u64,
+    pub name: String,
+}
+
+fn host_prot(prot: i32) -> i32 {
+    // the interpreter and jit read guest code, so exec needs host read
+    let r = if prot & (PROT_READ | PROT_EXEC) != 0 {
+        PROT_READ
+    } else {
+        0
+    };
+    let w = if prot & PROT_WRITE != 0 {
+        PROT_WRITE
+    } else {
+        0
+    };
+    return r | w;
+}
+
+fn errno() -> i32 {
+    return std::io::Error::last_os_error().raw_os_error().unwrap();
+}
+
+// guest end of the heap
+fn heap_end(mmu: &Mmu) -> u64 {
+    return to_guest!(mmu.host_base, mmu.host_alloc);
+}
+
+pub fn vma_find(mmu: &Mmu, addr: u64) -> Option<&Vma> {
+    return mmu
+        .vmas
+        .range(..=addr)
+        .next_back()
+        .map(|(_, vma)| vma)
+        .filter(|vma| addr < vma.end);
+}
+
+// end and protection of the mapped range holding addr
+pub fn vma_range(mmu: &Mmu, addr: u64) -> Option<(u64, i32)> {
+    return vma_find(mmu, addr).map(|vma| (vma.end, vma.prot));
+}
+
+// end of the executable memory holding pc, across adjacent mappings
+pub fn vma_exec_end(mmu: &Mmu, pc: u64) -> Option<u64> {
+    let mut end = match vma_range(mmu, pc) {
+        Some((end, prot)) if prot & PROT_EXEC != 0 => end,
+        _ => return None,
+    };
+    while let Some((next, prot)) = vma_range(mmu, end) {
+        if prot & PROT_EXEC == 0 {
+            break;
+        }
+        end = next;
+    }
+    return Some(end);
+}
+
+// some executable mapping overlaps [start, end)
+pub fn vma_has_exec(mmu: &Mmu, start: u64, end: u64) -> bool {
+    let first = vma_find(mmu, start).map_or(start, |vma| vma.start);
+    return mmu
+        .vmas
+        .range(first..end)
+        .any(|(_, vma)| vma.prot & PROT_EXEC != 0);
+}
+
+// some writable mapping overlaps [start, end)
+pub fn vma_has_write(mmu: &Mmu, start: u64, end: u64) -> bool {
+    let first = vma_find(mmu, start).map_or(start, |vma| vma.start);
+    return mmu
+        .vmas
+        .range(first..end)
+        .any(|(_, vma)| vma.prot & PROT_WRITE != 0);
+}
+
+// lowest mapping above the heap, where brk has to stop. the stack keeps
+// its guard gap. one of the heap's own mappings that mremap grew over the
+// end of the heap counts too.
+pub fn vma_heap_limit(mmu: &Mmu) -> u64 {
+    let end = heap_end(mmu);
+    let first = vma_find(mmu, end).map_or(end, |vma| vma.start);
+    return match mmu.vmas.range(first..).next() {
+        Some((_, vma)) if vma.name == "[stack]" => vma.start.saturating_sub(mmu.stack_gap),
+        Some((_, vma)) => vma.start,
+        None => GUEST_SPACE,
+    };
+}
+
+pub fn vma_insert(mmu: &mut Mmu, vma: Vma) {
+    mmu.vmas.insert(vma.start, vma);
+}
+
+// brk mapped [start, end) past the end of the heap. the pages join the
+// [heap] mapping below them unless mprotect changed it.
+pub fn vma_heap_grow(mmu: &mut Mmu, start: u64, end: u64) {
+    let mut heap = Vma {
+        start,
+        end,
+        prot: PROT_READ | PROT_WRITE,
+        shared: false,
+        anonymous: true,
+        offset: 0,
+        name: "[heap]".to_string(),
+    };
+    if let Some(prev) = start.checked_sub(1).and_then(|last| vma_find(mmu, last)) {
+        if prev.name == heap.name && prev.prot == heap.prot && prev.end == start {
+            heap.start = prev.start;
+        }
+    }
+    // taking the start of prev replaces it
+    vma_insert(mmu, heap);
+}
+
+// brk gave [start, end) back, whatever is left of the heap there goes
+pub fn vma_heap_shrink(mmu: &mut Mmu, start: u64, end: u64) {
+    vma_carve(mmu, start, end);
+}
+
+// take [start, end) out of the mappings, splitting the ones that straddle
+// its edges, and return the pieces that were inside
+pub fn vma_carve(mmu: &mut Mmu, start: u64, end: u64) -> Vec<Vma> {
+    let first = match mmu.vmas.range(..start).next_back() {
+        Some((_, vma)) if vma.end > start => vma.start,
+        _ => start,
+    };
+    let keys: Vec<u64> = mmu.vmas.range(first..end).map(|(&k, _)| k).collect();
+
+    let mut inside = Vec::new();
+    for key in keys {
+        let vma = mmu.vmas.remove(&key).unwrap();
+        if vma.start < start {
+            let mut head = vma.clone();
+            head.end = start;
+            vma_insert(mmu, head);
+        }
+        if vma.end > end {
+            let mut tail = vma.clone();
+            tail.offset += end - vma.start;
+            tail.start = end;
+            vma_insert(mmu, tail);
+        }
+        let mut mid = vma.clone();
+        mid.start = max!(vma.start, start);
+        mid.end = min!(vma.end, end);
+        mid.offset += mid.start - vma.start;
+        inside.push(mid);
+    }
+    return inside;
+}
+
+// nothing is mapped in [start, end) and the heap is not in the way
+fn vma_is_free(mmu: &Mmu, start: u64, end: u64) -> bool {
+    if start >= end || end > GUEST_SPACE {
+        return false;
+    }
+    if start < heap_end(mmu) && end > mmu.base {
+        return false;
+    }
+    let before = mmu.vmas.range(..end).next_back();
+    return before.is_none_or(|(_, vma)| vma.end <= start);
+}
+
+// highest gap of len bytes between the heap and top
+fn vma_place_below(mmu: &Mmu, top: u64, len: u64) -> Option<u64> {
+    let floor = round_up!(heap_end(mmu), page_size::get());
+    let mut top = top;
+    for (_, vma) in mmu.vmas.range(floor..top).rev() {
+        if top - vma.end >= len {
+            return Some(top - len);
+        }
+        top = vma.start;
+    }
+    if top >= floor + len {
+        return Some(top - len);
+    }
+    return None;
+}
+
+// top down from mmap_base, then anywhere above the heap like the linux
+// bottom-up fallback
+pub fn vma_place(mmu: &Mmu, len: u64) -> Option<u64> {
+    return vma_place_below(mmu, mmu.mmap_base, len)
+        .or_else(|| vma_place_below(mmu, GUEST_SPACE, len));
+}
+
+// splitmix64, so a seed gives the same layout on every run
+fn vma_random(mmu: &mut Mmu, range: u64) -> u64 {
+    let seed = match mmu.aslr.as_mut() {
+        Some(seed) => seed,
+        None => return 0,
+    };
+    *seed = seed.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *seed;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    return round_down!(z % range, page_size::get() as u64);
+}
+
+// where an ET_DYN program goes, linux ELF_ET_DYN_BASE: two thirds up, out
+// of the way of anything mapped at a fixed low address
+pub fn vma_elf_base(mmu: &mut Mmu) -> u64 {
+    let pz = page_size::get() as u64;
+    return round_down!(GUEST_SPACE / 3 * 2, pz) + vma_random(mmu, MMAP_RND);
+}
+
+// lay out the address space once the program is loaded, the way linux
+// does for a 64-bit process: the stack at the top, mmap_base below it
+// with room for RLIMIT_STACK and the guard gap, and brk right after the
+// highest segment
+pub fn vma_layout(mmu: &mut Mmu) -> Result<(), String> {
+    let pz = page_size::get() as u64;
+    let limit = round_up!(mmu.stack_limit, pz);
+    let gap = round_up!(mmu.stack_gap, pz);
+    let stack_rnd = if mmu.aslr.is_some() { STACK_RND } else { 0 };
+    if limit == 0 || limit + gap + MMAP_MIN_GAP > GUEST_SPACE / 2 {
+        return Err(format!("stack size {:#x} does not fit", mmu.stack_limit));
+    }
+
+    mmu.stack_top = GUEST_SPACE - vma_random(mmu, STACK_RND);
+    let stack = mmu.stack_top - limit;
+    // without PT_GNU_STACK the stack is not executable, as on riscv linux
+    let stack_prot = if mmu.exec_stack {
+        PROT_READ | PROT_WRITE | PROT_EXEC
+    } else {
+        PROT_READ | PROT_WRITE
+    };
+    if !vma_is_free(mmu, stack, mmu.stack_top) {
+        return Err(format!("no room for the stack at {:#x}", stack));
+    }
+    vma_map(
+        mmu,
+        stack,
+        limit,
+        stack_prot,
+        LINUX_MAP_PRIVATE | LINUX_MAP_ANONYMOUS | LINUX_MAP_FIXED_NOREPLACE,
+        -1,
+        0,
+    )
+    .map_err(|errno| format!("cannot map the stack: errno {}", errno))?;
+    mmu.vmas.get_mut(&stack).unwrap().name = "[stack]".to_string();
+
+    let room = (limit + gap + stack_rnd).clamp(MMAP_MIN_GAP, MMAP_MAX_GAP);
+    mmu.mmap_base = GUEST_SPACE - room - vma_random(mmu, MMAP_RND);
+
+    // nothing is on the heap yet, so brk can still move
+    assert!(mmu.alloc == mmu.base && heap_end(mmu) == round_up!(mmu.base, pz));
+    let brk = round_up!(mmu.base, pz) + vma_random(mmu, BRK_RND);
+    if brk >= mmu.mmap_base {
+        return Err(format!("program ends above mmap base {:#x}", mmu.mmap_base));
+    }
+    mmu.base = brk;
+    mmu.alloc = brk;
+    mmu.host_alloc = to_host!(mmu.host_base, brk);
+    return Ok(());
+}
+
+// give [start, end) back to the reservation
+fn vma_release(mmu: &Mmu, start: u64, end: u64) {
+    let ret = unsafe {
+        mmap(
+            get_ptr(to_host!(mmu.host_base, start)) as *mut c_void,
+            (end - start) as usize,
+            PROT_NONE,
+            MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED | MAP_NORESERVE,
+            -1,
+            0,
+        )
+    };
+    assert!(ret != MAP_FAILED);
+}
+
+pub fn vma_map(
+    mmu: &mut Mmu,
+    addr: u64,
+    len: u64,
+    prot: i32,
+    flags: i32,
+    fd: i32,
+    offset: u64,
+) -> Result<u64, i32> {
+    let pz = page_size::get() as u64;
+    let shared = match flags & (LINUX_MAP_SHARED | LINUX_MAP_PRIVATE) {
+        LINUX_MAP_SHARED => true,
+        LINUX_MAP_PRIVATE => false,
+        _ => return Err(EINVAL),
+    };
+    let anonymous = flags & LINUX_MAP_ANONYMOUS != 0;
+    if len == 0 || len > GUEST_SPACE || !offset.is_multiple_of(pz) {
+        return Err(EINVAL);
+    }
+    let len = round_up!(len, pz);
+
+    let fixed = flags & (LINUX_MAP_FIXED | LINUX_MAP_FIXED_NOREPLACE) != 0;
+    let start = if fixed {
+        if !addr.is_multiple_of(pz) {
+            return Err(EINVAL);
+        }
+        let end = addr.checked_add(len).ok_or(ENOMEM)?;
+        if end > GUEST_SPACE || (addr < heap_end(mmu) && end > mmu.base) {
+            // mapping over the heap would pull it out from under brk
+            return Err(ENOMEM);
+        }
+        if !vma_is_free(mmu, addr, end) {
+            if flags & LINUX_MAP_FIXED_NOREPLACE != 0 {
+                return Err(EEXIST);
+            }
+            vma_unmap(mmu, addr, len)?;
+        }
+        addr
+    } else if addr.is_multiple_of(pz)
+        && addr != 0
+        && vma_is_free(mmu, addr, addr.saturating_add(len))
+    {
+        // the hint is free, take it
+        addr
+    } else {
+        vma_place(mmu, len).ok_or(ENOMEM)?
+    };
+
+    let mut host_flags = MAP_FIXED | if shared { MAP_SHARED } else { MAP_PRIVATE };
+    if anonymous {
+        host_flags |= MAP_ANONYMOUS;
+    }
+    if flags & LINUX_MAP_NORESERVE != 0 {
+        host_flags |= MAP_NORESERVE;
+    }
+    let ret = unsafe {
+        mmap(
+            get_ptr(to_host!(mmu.host_base, start)) as *mut c_void,
+            len as usize,
+            host_prot(prot),
+            host_flags,
+            if anonymous { -1 } else { fd },
+            if anonymous { 0 } else { offset as i64 },
+        )
+    };
+    if ret == MAP_FAILED {
+        let err = errno();
+        vma_release(mmu, start, start + len);
+        return Err(err);
+    }
+
+    vma_insert(
+        mmu,
+        Vma {
+            start,
+            end: start + len,
+            prot,
+            shared,
+            anonymous,
+            offset: if anonymous { 0 } else { offset },
+            name: String::new(),
+        },
+    );
+    return Ok(start);
+}
+
+pub fn vma_unmap(mmu: &mut Mmu, addr: u64, len: u64) -> Result<(), i32> {
+    let pz = page_size::get() as u64;
+    if !addr.is_multiple_of(pz) || len == 0 {
+        return Err(EINVAL);
+    }
+    let end = addr.checked_add(round_up!(len, pz)).ok_or(EINVAL)?;
+    for vma in vma_carve(mmu, addr, end) {
+        vma_release(mmu, vma.start, vma.end);
+    }
+    return Ok(());
+}
+
+pub fn vma_protect(mmu: &mut Mmu, addr: u64, len: u64, prot: i32) -> Result<(), i32> {
+    let pz = page_size::get() as u64;
+    if !addr.is_multiple_of(pz) || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(EINVAL);
+    }
+    let end = addr.checked_add(round_up!(len, pz)).ok_or(ENOMEM)?;
+
+    // every page has to be mapped, like on linux
+    let mut at = addr;
+    while at < end {
+        at = match vma_find(mmu, at) {
+            Some(vma) => vma.end,
+            None => return Err(ENOMEM),
+        };
+    }
+
+    let ret = unsafe {
+        mprotect(
+            get_ptr(to_host!(mmu.host_base, addr)) as *mut c_void,
+            (end - addr) as usize,
+            host_prot(prot),
+        )
+    };
+    if ret != 0 {
+        return Err(errno());
+    }
+    for mut vma in vma_carve(mmu, addr, end) {
+        vma.prot = prot;
+        vma_insert(mmu, vma);
+    }
+    return Ok(());
+}
+
+// move the pages of [src, src + old_len) to dst and grow or shrink them to
+// new_len, whatever was at dst is gone
+fn vma_move(mmu: &mut Mmu, vma: Vma, dst: u64, new_len: u64) -> Result<u64, i32> {
+    let old_len = vma.end - vma.start;
+    let ret = unsafe {
+        mremap(
+            get_ptr(to_host!(mmu.host_base, vma.start)) as *mut c_void,
+            old_len as usize,
+            new_len as usize,
+            MREMAP_MAYMOVE | MREMAP_FIXED,
+            get_ptr(to_host!(mmu.host_base, dst)) as *mut c_void,
+        )
+    };
+    if ret == MAP_FAILED {
+        let err = errno();
+        vma_insert(mmu, vma);
+        return Err(err);
+    }
+    // the host left a hole where the pages were
+    vma_release(mmu, vma.start, vma.end);
+    vma_insert(
+        mmu,
+        Vma {
+            start: dst,
+            end: dst + new_len,
+            ..vma
+        },
+    );
+    return Ok(dst);
+}
+
+pub fn vma_remap(
+    mmu: &mut Mmu,
+    addr: u64,
+    old_len: u64,
+    new_len: u64,
+    flags: i32,
+    new_addr: u64,
+) -> Result<u64, i32> {
+    let pz = page_size::get() as u64;
+    let may_move = flags & LINUX_MREMAP_MAYMOVE != 0;
+    let fixed = flags & LINUX_MREMAP_FIXED != 0;
+    if !addr.is_multiple_of(pz)
+        || new_len == 0
+        || flags & !(LINUX_MREMAP_MAYMOVE | LINUX_MREMAP_FIXED) != 0
+        || (fixed && !may_move)
+    {
+        return Err(EINVAL);
+    }
+    let old_len = round_up!(old_len, pz);
+    let new_len = round_up!(new_len, pz);
+    if new_len > GUEST_SPACE {
+        return Err(ENOMEM);
+    }
+
+    // the old range has to sit in one mapping
+    let old_end = addr.checked_add(old_len).ok_or(EFAULT)?;
+    match vma_find(mmu, addr) {
+        Some(vma) if old_end <= vma.end => {}
+        _ => return Err(EFAULT),
+    }
+
+    if fixed {
+        let new_end = new_addr.checked_add(new_len).ok_or(EINVAL)?;
+        if !new_addr.is_multiple_of(pz) || (new_addr < old_end && new_end > addr) {
+            return Err(EINVAL);
+        }
+        if new_end > GUEST_SPACE || (new_addr < heap_end(mmu) && new_end > mmu.base) {
+            return Err(ENOMEM);
+        }
+        vma_unmap(mmu, new_addr, new_len)?;
+        let vma = vma_carve(mmu, addr, old_end).pop().unwrap();
+        return vma_move(mmu, vma, new_addr, new_len);
+    }
+
+    if new_len <= old_len {
+        if new_len < old_len {
+            vma_unmap(mmu, addr + new_len, old_len - new_len)?;
+        }
+        return Ok(addr);
+    }
+
+    // grow in place: drop the reservation behind the mapping so the host can
+    // extend it, the backing and sharing stay what they were
+    let grow_end = addr + new_len;
+    if vma_is_free(mmu, old_end, grow_end) {
+        let host_end = get_ptr(to_host!(mmu.host_base, old_end)) as *mut c_void;
+        unsafe { munmap(host_end, (grow_end - old_end) as usize) };
+        let ret = unsafe {
+            mremap(
+                get_ptr(to_host!(mmu.host_base, addr)) as *mut c_void,
+                old_len as usize,
+                new_len as usize,
+                0,
+            )
+        };
+        if ret != MAP_FAILED {
+            let mut vma = vma_carve(mmu, addr, old_end).pop().unwrap();
+            vma.end = grow_end;
+            vma_insert(mmu, vma);
+            return Ok(addr);
+        }
+        vma_release(mmu, old_end, grow_end);
+    }
+
+    if !may_move {
+        return Err(ENOMEM);
+    }
+    let dst = vma_place(mmu, new_len).ok_or(ENOMEM)?;
+    let vma = vma_carve(mmu, addr, old_end).pop().unwrap();
+    return vma_move(mmu, vma, dst, new_len);
+}
+
+// the mappings in the format of /proc/self/maps
+pub fn vma_dump(mmu: &Mmu) -> String {
+    let mut out = String::new();
+    for vma in mmu.vmas.values() {
+        let perms = format!(
+            "{}{}{}{}",
+            if vma.prot & PROT_READ != 0 { 'r' } else { '-' },
+            if vma.prot & PROT_WRITE != 0 { 'w' } else { '-' },
+            if vma.prot & PROT_EXEC != 0 { 'x' } else { '-' },
+            if vma.shared { 's' } else { 'p' },
+        );
+        let line = format!(
+            "{:08x}-{:08x} {} {:08x} 00:00 0",
+            vma.start,
+            vma.end,
+            perms,
+            if vma.anonymous { 0 } else { vma.offset }
+        );
+        writeln!(out, "{}", maps_line(line, &vma.name)).unwrap();
+    }
+    return out;
+}
+
+fn maps_line(line: String, name: &str) -> String {
+    if name.is_empty() {
+        return line;
+    }
+    return format!("{:<72} {}", line, name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::{mmu_alloc, mmu_read, mmu_write};
+
+    const RW: i32 = PROT_READ | PROT_WRITE;
+    const BASE: u64 = 0x100000;
+
+    fn map(mmu: &mut Mmu, addr: u64, pages: u64) {
+        let pz = page_size::get() as u64;
+        let flags = LINUX_MAP_PRIVATE | LINUX_MAP_ANONYMOUS | LINUX_MAP_FIXED;
+        assert_eq!(vma_map(mmu, addr, pages * pz, RW, flags, -1, 0), Ok(addr));
+    }
+
+    fn vmas(mmu: &Mmu) -> Vec<(u64, u64, i32)> {
+        return mmu
+            .vmas
+            .values()
+            .map(|vma| (vma.start, vma.end, vma.prot))
+            .collect();
+    }
+
+    #[test]
+    fn vma_unmap_splits() {
+        let pz = page_size::get() as u64;
+        let mut mmu = Mmu::new();
+        map(&mut mmu, BASE, 4);
+        mmu_write(&mut mmu, BASE + 3 * pz, 0x1234u32).unwrap();
+
+        assert_eq!(vma_unmap(&mut mmu, BASE + pz, pz), Ok(()));
+        assert_eq!(
+            vmas(&mmu),
+            [(BASE, BASE + pz, RW), (BASE + 2 * pz, BASE + 4 * pz, RW)]
+        );
+        assert!(vma_find(&mmu, BASE + pz).is_none());
+        assert!(mmu_read::<u8>(&mmu, BASE + pz).is_err());
+        assert_eq!(mmu_read::<u32>(&mmu, BASE + 3 * pz), Ok(0x1234));
+        assert_eq!(vma_unmap(&mut mmu, BASE + 1, pz), Err(EINVAL));
+    }
+
+    #[test]
+    fn vma_protect_splits() {
+        let pz = page_size::get() as u64;
+        let mut mmu = Mmu::new();
+        map(&mut mmu, BASE, 4);
+
+        assert_eq!(vma_protect(&mut mmu, BASE + pz, 2 * pz, PROT_READ), Ok(()));
+        assert_eq!(
+            vmas(&mmu),
+            [
+                (BASE, BASE + pz, RW),
+                (BASE + pz, BASE + 3 * pz, PROT_READ),
+                (BASE + 3 * pz, BASE + 4 * pz, RW),
+            ]
+        );
+        assert!(mmu_write(&mut mmu, BASE + pz, 1u8).is_err());
+        assert!(mmu_write(&mut mmu, BASE + 3 * pz, 1u8).is_ok());
+        assert!(!vma_has_write(&mmu, BASE + pz, BASE + 3 * pz));
+
+        // every page has to be mapped, nothing changes when one is not
+        vma_unmap(&mut mmu, BASE + 3 * pz, pz).unwrap();
+        assert_eq!(vma_protect(&mut mmu, BASE, 4 * pz, RW), Err(ENOMEM));
+        assert_eq!(vma_find(&mmu, BASE + pz).unwrap().prot, PROT_READ);
+    }
+
+    #[test]
+    fn vma_remap_in_place() {
+        let pz = page_size::get() as u64;
+        let mut mmu = Mmu::new();
+        map(&mut mmu, BASE, 2);
+        mmu_write(&mut mmu, BASE + pz, 0x5678u32).unwrap();
+
+        assert_eq!(vma_remap(&mut mmu, BASE, 2 * pz, 4 * pz, 0, 0), Ok(BASE));
+        assert_eq!(vmas(&mmu), [(BASE, BASE + 4 * pz, RW)]);
+        assert_eq!(mmu_read::<u32>(&mmu, BASE + pz), Ok(0x5678));
+        mmu_write(&mut mmu, BASE + 3 * pz, 1u8).unwrap();
+
+        assert_eq!(vma_remap(&mut mmu, BASE, 4 * pz, pz, 0, 0), Ok(BASE));
+        assert_eq!(vmas(&mmu), [(BASE, BASE + pz, RW)]);
+        assert!(mmu_read::<u8>(&mmu, BASE + pz).is_err());
+    }
+
+    #[test]
+    fn vma_remap_moves() {
+        let pz = page_size::get() as u64;
+        let mut mmu = Mmu::new();
+        map(&mut mmu, BASE, 1);
+        map(&mut mmu, BASE + pz, 1);
+        mmu_write(&mut mmu, BASE, 0x9abcu32).unwrap();
+
+        // the old range has to be in one mapping
+        assert_eq!(vma_remap(&mut mmu, BASE, 2 * pz, 2 * pz, 0, 0), Err(EFAULT));
+        // blocked by the next mapping and not allowed to move
+        assert_eq!(vma_remap(&mut mmu, BASE, pz, 2 * pz, 0, 0), Err(ENOMEM));
+
+        let moved = vma_remap(&mut mmu, BASE, pz, 2 * pz, LINUX_MREMAP_MAYMOVE, 0).unwrap();
+        assert_ne!(moved, BASE);
+        assert!(vma_find(&mmu, BASE).is_none());
+        assert_eq!(vma_find(&mmu, moved).unwrap().end, moved + 2 * pz);
+        assert_eq!(mmu_read::<u32>(&mmu, moved), Ok(0x9abc));
+
+        let flags = LINUX_MREMAP_MAYMOVE | LINUX_MREMAP_FIXED;
+        assert_eq!(
+            vma_remap(&mut mmu, moved, 2 * pz, pz, flags, BASE),
+            Ok(BASE)
+        );
+        assert!(vma_find(&mmu, moved).is_none());
+        assert_eq!(mmu_read::<u32>(&mmu, BASE), Ok(0x9abc));
+        // the destination may not overlap the source
+        assert_eq!(
+            vma_remap(&mut mmu, BASE, pz, 2 * pz, flags, BASE - pz),
+            Err(EINVAL)
+        );
+    }
+
+    #[test]
+    fn vma_protect_heap() {
+        let pz = page_size::get() as u64;
+        let mut mmu = Mmu::new();
+        mmu.base = BASE;
+        mmu.alloc = BASE;
+        mmu.host_alloc = to_host!(mmu.host_base, BASE);
+        assert_eq!(mmu_alloc(&mut mmu, 2 * pz as i64), Ok(BASE));
+        assert_eq!(vmas(&mmu), [(BASE, BASE + 2 * pz, RW)]);
+
+        // a brk page can be mprotected like any other
+        assert_eq!(vma_protect(&mut mmu, BASE, pz, PROT_READ), Ok(()));
+        assert!(mmu_write(&mut mmu, BASE, 1u8).is_err());
+        assert_eq!(mmu_write(&mut mmu, BASE + pz, 1u8), Ok(()));
+
+        // more brk joins the writable part, less takes pages back
+        assert_eq!(mmu_alloc(&mut mmu, pz as i64), Ok(BASE + 2 * pz));
+        assert_eq!(
+            vmas(&mmu),
+            [(BASE, BASE + pz, PROT_READ), (BASE + pz, BASE + 3 * pz, RW)]
+        );
+        assert_eq!(vma_unmap(&mut mmu, BASE + 2 * pz, pz), Ok(()));
+        assert!(mmu_read::<u8>(&mmu, BASE + 2 * pz).is_err());
+        assert_eq!(mmu_alloc(&mut mmu, -2 * pz as i64), Ok(BASE + 3 * pz));
+        assert_eq!(vmas(&mmu), [(BASE, BASE + pz, PROT_READ)]);
+        assert!(vma_dump(&mmu).ends_with("[heap]\n"));
+    }
+}
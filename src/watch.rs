@@ -0,0 +1,243 @@
+use std::fmt;
+
+use crate::rvemu::{get_ptr, ExitReason, State};
+
+// like hardware debug registers there is a fixed number of them, so they
+// fit in State where the insn handlers can see them
+pub const MAX_WATCHPOINTS: usize = 16;
+
+pub const WATCH_READ: u8 = 0x1;
+pub const WATCH_WRITE: u8 = 0x2;
+pub const WATCH_EXEC: u8 = 0x4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Watchpoint {
+    pub start: u64,
+    pub end: u64,
+    pub access: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchAccess {
+    Read,
+    Write,
+    Exec,
+}
+
+impl fmt::Display for WatchAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            WatchAccess::Read => "read",
+            WatchAccess::Write => "write",
+            WatchAccess::Exec => "exec",
+        };
+        return write!(f, "{}", name);
+    }
+}
+
+// what ExitReason::Watchpoint stopped on. for a read old and new are the
+// value read, for exec they are the insn
+#[derive(Debug, Clone, Copy)]
+pub struct WatchHit {
+    pub index: usize,
+    pub pc: u64,
+    pub access: WatchAccess,
+    pub addr: u64,
+    pub size: u64,
+    pub old: u64,
+    pub new: u64,
+}
+
+pub fn watch_add(state: &mut State, start: u64, len: u64, access: u8) -> Result<usize, String> {
+    if len == 0 || access == 0 || access & !(WATCH_READ | WATCH_WRITE | WATCH_EXEC) != 0 {
+        return Err(format!("bad watchpoint at {:#x}", start));
+    }
+    let end = start
+        .checked_add(len)
+        .ok_or(format!("bad watchpoint at {:#x}", start))?;
+    let index = state
+        .watchpoints
+        .iter()
+        .position(|w| w.is_none())
+        .ok_or(format!("more than {} watchpoints", MAX_WATCHPOINTS))?;
+    state.watchpoints[index] = Some(Watchpoint { start, end, access });
+    state.watching = true;
+    return Ok(index);
+}
+
+pub fn watch_remove(state: &mut State, index: usize) {
+    state.watchpoints[index] = None;
+    state.watching = state.watchpoints.iter().any(|w| w.is_some());
+}
+
+// parse ADDR[:LEN[:rwx]], a write watchpoint on one byte by default
+pub fn watch_parse(spec: &str) -> Result<(u64, u64, u8), String> {
+    let bad = || format!("bad watchpoint: {}", spec);
+    let num = |s: &str| match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).map_err(|_| bad()),
+        None => s.parse::<u64>().map_err(|_| bad()),
+    };
+
+    let mut parts = spec.split(':');
+    let addr = num(parts.next().unwrap())?;
+    let len = parts.next().map_or(Ok(1), num)?;
+    let mut access = 0;
+    for c in parts.next().unwrap_or("w").chars() {
+        access |= match c {
+            'r' => WATCH_READ,
+            'w' => WATCH_WRITE,
+            'x' => WATCH_EXEC,
+            _ => return Err(bad()),
+        };
+    }
+    if parts.next().is_some() {
+        return Err(bad());
+    }
+    return Ok((addr, len, access));
+}
+
+fn watch_find(state: &State, addr: u64, size: u64, access: u8) -> Option<usize> {
+    return state.watchpoints.iter().position(|w| match w {
+        Some(w) => w.access & access != 0 && addr < w.end && addr.wrapping_add(size) > w.start,
+        None => false,
+    });
+}
+
+fn watch_mask(value: u64, size: u64) -> u64 {
+    if size >= 8 {
+        return value;
+    }
+    return value & ((1u64 << (size * 8)) - 1);
+}
+
+// stop after the insn at state.pc, len bytes long
+fn watch_stop(state: &mut State, hit: WatchHit, len: u64) {
+    state.watch_hit = Some(hit);
+    state.reenter_pc = state.pc + len;
+    state.exit_reason = ExitReason::Watchpoint;
+}
+
+// the size bytes at addr, before a store overwrites them
+pub fn watch_peek(state: &State, addr: u64, size: u64) -> u64 {
+    let mut buf = [0u8; 8];
+    let ptr = get_ptr(state.host_base.wrapping_add(addr));
+    unsafe { ptr.copy_to_nonoverlapping(buf.as_mut_ptr(), size as usize) };
+    return u64::from_le_bytes(buf);
+}
+
+// after a load: true if it hit a read watchpoint and the block has to end
+pub fn watch_load(state: &mut State, addr: u64, size: u64, value: u64, len: u64) -> bool {
+    let index = match watch_find(state, addr, size, WATCH_READ) {
+        Some(index) => index,
+        None => return false,
+    };
+    let value = watch_mask(value, size);
+    let hit = WatchHit {
+        index,
+        pc: state.pc,
+        access: WatchAccess::Read,
+        addr,
+        size,
+        old: value,
+        new: value,
+    };
+    watch_stop(state, hit, len);
+    return true;
+}
+
+// after a store: true if it hit a write watchpoint and the block has to end
+pub fn watch_store(state: &mut State, addr: u64, size: u64, old: u64, new: u64, len: u64) -> bool {
+    let index = match watch_find(state, addr, size, WATCH_WRITE) {
+        Some(index) => index,
+        None => return false,
+    };
+    let hit = WatchHit {
+        index,
+        pc: state.pc,
+        access: WatchAccess::Write,
+        addr,
+        size,
+        old: watch_mask(old, size),
+        new: watch_mask(new, size),
+    };
+    watch_stop(state, hit, len);
+    return true;
+}
+
+// before the insn at pc runs: true if it hit an exec watchpoint. the insn
+// then runs first thing when the guest is resumed, without stopping again.
+pub fn watch_exec(state: &mut State, pc: u64, data: u32, len: u64) -> bool {
+    if state.watch_resume == Some(pc) {
+        state.watch_resume = None;
+        return false;
+    }
+    let index = match watch_find(state, pc, len, WATCH_EXEC) {
+        Some(index) => index,
+        None => return false,
+    };
+    let data = watch_mask(data as u64, len);
+    state.watch_hit = Some(WatchHit {
+        index,
+        pc,
+        access: WatchAccess::Exec,
+        addr: pc,
+        size: len,
+        old: data,
+        new: data,
+    });
+    state.watch_resume = Some(pc);
+    state.reenter_pc = pc;
+    state.exit_reason = ExitReason::Watchpoint;
+    return true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watch_parse_specs() {
+        assert_eq!(watch_parse("0x1000"), Ok((0x1000, 1, WATCH_WRITE)));
+        assert_eq!(watch_parse("4096:8"), Ok((0x1000, 8, WATCH_WRITE)));
+        assert_eq!(watch_parse("0x1000:0x10:r"), Ok((0x1000, 0x10, WATCH_READ)));
+        assert_eq!(
+            watch_parse("0x1000:4:xrw"),
+            Ok((0x1000, 4, WATCH_READ | WATCH_WRITE | WATCH_EXEC))
+        );
+        for spec in [
+            "",
+            "0x",
+            "0xg",
+            "-1",
+            "0x1000:",
+            "0x1000:4:a",
+            "0x1000:4:r:1",
+        ] {
+            assert_eq!(watch_parse(spec), Err(format!("bad watchpoint: {}", spec)));
+        }
+        // no access at all is left to watch_add
+        assert_eq!(watch_parse("0x1000:4:"), Ok((0x1000, 4, 0)));
+    }
+
+    #[test]
+    fn watch_add_remove() {
+        let mut state = State::new();
+        assert!(watch_add(&mut state, 0x1000, 0, WATCH_READ).is_err());
+        assert!(watch_add(&mut state, 0x1000, 4, 0).is_err());
+        assert!(watch_add(&mut state, 0x1000, 4, 0x8).is_err());
+        assert!(watch_add(&mut state, u64::MAX, 2, WATCH_READ).is_err());
+        assert!(!state.watching);
+
+        for i in 0..MAX_WATCHPOINTS {
+            assert_eq!(watch_add(&mut state, 0x1000, 4, WATCH_WRITE), Ok(i));
+        }
+        assert!(watch_add(&mut state, 0x1000, 4, WATCH_WRITE).is_err());
+        // the lowest free slot is reused
+        watch_remove(&mut state, 3);
+        assert_eq!(watch_add(&mut state, 0x2000, 4, WATCH_READ), Ok(3));
+        for i in 0..MAX_WATCHPOINTS {
+            watch_remove(&mut state, i);
+        }
+        assert!(!state.watching);
+    }
+}